@@ -113,6 +113,80 @@ fn benchmark_cross_spread_matching() {
     );
 }
 
+fn benchmark_cancel_heavy(n: usize) {
+    let mut book = OrderBook::new();
+
+    // Queue many orders per level so cancels land mid-queue
+    for i in 0..n {
+        let _ = book.place_order(
+            Side::Buy,
+            Decimal::from(900 + (i % 10)),
+            Decimal::from(100),
+            i as u64,
+        );
+    }
+
+    let start = Instant::now();
+
+    // Cancel every other order so most cancels unlink from the middle of a queue
+    for i in (0..n).rev().step_by(2) {
+        let _ = book.cancel_order(i as u64);
+    }
+
+    let elapsed = start.elapsed();
+    println!(
+        "Cancelled {} resting orders in {:.2}ms ({:.0} cancels/sec)",
+        n / 2,
+        elapsed.as_secs_f64() * 1000.0,
+        (n / 2) as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn benchmark_quote_refresh(rounds: usize, levels: usize) {
+    let mut book = OrderBook::new();
+    let mut next_id = 0u64;
+    let mut resting = Vec::with_capacity(levels * 2);
+
+    let start = Instant::now();
+
+    // Market maker cancels and re-quotes its whole ladder every round
+    for round in 0..rounds {
+        for id in resting.drain(..) {
+            let _ = book.cancel_order(id);
+        }
+
+        let shift = (round % 5) as u64;
+        for level in 0..levels as u64 {
+            let _ = book.place_order(
+                Side::Buy,
+                Decimal::from(990 - level + shift),
+                Decimal::from(10),
+                next_id,
+            );
+            resting.push(next_id);
+            next_id += 1;
+
+            let _ = book.place_order(
+                Side::Sell,
+                Decimal::from(1010 + level + shift),
+                Decimal::from(10),
+                next_id,
+            );
+            resting.push(next_id);
+            next_id += 1;
+        }
+    }
+
+    let elapsed = start.elapsed();
+    let operations = rounds * levels * 4;
+    println!(
+        "Quote refresh: {} cancel/replace ops in {:.2}ms ({:.0} ops/sec)",
+        operations,
+        elapsed.as_secs_f64() * 1000.0,
+        operations as f64 / elapsed.as_secs_f64()
+    );
+}
+
 fn main() {
     println!("=== OrderBook Performance Benchmarks ===\n");
 
@@ -133,4 +207,9 @@ fn main() {
 
     println!("\nComplex matching:");
     benchmark_cross_spread_matching();
+
+    println!("\nCancel-heavy workload:");
+    benchmark_cancel_heavy(10_000);
+    benchmark_cancel_heavy(100_000);
+    benchmark_quote_refresh(1_000, 20);
 }
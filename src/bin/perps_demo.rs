@@ -1,71 +1,118 @@
-use aptos_matching_engine::funding::FundingRate;
 use aptos_matching_engine::perps::*;
-use aptos_matching_engine::{OrderBook, Side};
+use aptos_matching_engine::Side;
 use rand::Rng;
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use std::{thread, time::Duration};
 
+const MARKET_MAKER_ID: u64 = 0;
+const MARKET_MAKER_LEVERAGE: Decimal = dec!(5);
+const MARKET: MarketId = 1;
+
 fn main() {
     println!("\n╔═══════════════════════════════════════════════════════════╗");
     println!("║     🚀 PERPETUAL FUTURES DEX - ORDER BOOK DEMO 🚀        ║");
     println!("╚═══════════════════════════════════════════════════════════╝\n");
 
-    let mut order_book = OrderBook::new();
-    let mut position_manager = PositionManager::new();
-    let mut funding_rate = FundingRate::new();
-    let mut mark_price = MarkPrice::new();
+    let mut clearing = ClearingHouse::new();
+    clearing.add_market(Market::new(MARKET)).unwrap();
+    // Deeper rebates and cheaper taking as trailing 30-day volume grows
+    let tier = |min_volume, maker_fee, taker_fee| FeeTier {
+        min_volume,
+        rates: FeeRates::new(maker_fee, taker_fee).unwrap(),
+    };
+    clearing.fee_structure = FeeStructure::with_tiers(vec![
+        tier(dec!(1000000), dec!(-0.00015), dec!(0.0004)),
+        tier(dec!(10000000), dec!(-0.0002), dec!(0.00035)),
+    ])
+    .unwrap();
+    let mut mark_price = MarkPrice::with_method(MarkPriceMethod::Median);
     let mut oracle = OraclePrice::new(dec!(1000));
-    let liquidation_engine = LiquidationEngine::new();
-    let fee_structure = FeeStructure::new();
-    let mut insurance_fund = InsuranceFund::new(dec!(1000000));
+    let mut spot = dec!(1000);
+    let mut venues: OracleAggregator<SimulatedOracle> = OracleAggregator::default();
+    for venue in ["venue-a", "venue-b", "venue-c"] {
+        venues
+            .add_source(SimulatedOracle::new(venue, spot), Decimal::ONE)
+            .unwrap();
+    }
 
     let mut rng = rand::thread_rng();
-    let mut order_id = 1u64;
     let mut trader_id = 1u64;
 
     println!("📊 Initial Market Setup");
     println!("═══════════════════════════════════════════════════════");
-    println!("  Max Leverage:        {}x", position_manager.max_leverage);
+    println!(
+        "  Max Leverage:        {}x",
+        clearing.markets[&MARKET].position_manager.max_leverage
+    );
     println!(
         "  Initial Margin:      {}%",
-        (liquidation_engine.initial_margin * dec!(100))
+        (clearing.liquidation_engine.initial_margin * dec!(100))
             .to_f64()
             .unwrap_or(0.0)
     );
     println!(
         "  Maintenance Margin:  {}%",
-        (liquidation_engine.maintenance_margin * dec!(100))
+        (clearing.liquidation_engine.maintenance_margin * dec!(100))
             .to_f64()
             .unwrap_or(0.0)
     );
     println!(
         "  Maker Fee:           {}%",
-        (fee_structure.maker_fee * dec!(100))
+        (clearing.fee_structure.base_rates().maker_fee() * dec!(100))
             .to_f64()
             .unwrap_or(0.0)
     );
     println!(
         "  Taker Fee:           {}%",
-        (fee_structure.taker_fee * dec!(100))
+        (clearing.fee_structure.base_rates().taker_fee() * dec!(100))
             .to_f64()
             .unwrap_or(0.0)
     );
-    println!("  Insurance Fund:      ${}\n", insurance_fund.balance);
+    println!("  Fee Tiers (30d volume):");
+    for tier in clearing.fee_structure.tiers() {
+        println!(
+            "    from ${:<12} maker {}%  taker {}%",
+            tier.min_volume,
+            (tier.rates.maker_fee() * dec!(100)).normalize(),
+            (tier.rates.taker_fee() * dec!(100)).normalize()
+        );
+    }
+    println!("  Risk Brackets:");
+    for bracket in &clearing.liquidation_engine.brackets {
+        let limit = if bracket.max_notional == Decimal::MAX {
+            "and above".to_string()
+        } else {
+            format!("up to ${}", bracket.max_notional)
+        };
+        println!(
+            "    {:<18} {:>4}x  IM {}%  MM {}%",
+            limit,
+            bracket.max_leverage,
+            bracket.initial_margin * dec!(100),
+            bracket.maintenance_margin * dec!(100)
+        );
+    }
+    println!(
+        "  Insurance Fund:      ${}\n",
+        clearing.insurance_fund.balance
+    );
 
     println!("🌊 Seeding Order Book with Initial Liquidity...\n");
+    clearing.deposit(MARKET_MAKER_ID, dec!(100000000)).unwrap();
+    clearing
+        .set_leverage(MARKET_MAKER_ID, MARKET_MAKER_LEVERAGE)
+        .unwrap();
     for i in 0..10 {
         let buy_price = dec!(995) - Decimal::from(i);
         let sell_price = dec!(1005) + Decimal::from(i);
-        order_book
-            .place_order(Side::Buy, buy_price, dec!(1000), order_id)
+        clearing
+            .submit_order(MARKET_MAKER_ID, MARKET, Side::Buy, buy_price, dec!(1000))
             .unwrap();
-        order_id += 1;
-        order_book
-            .place_order(Side::Sell, sell_price, dec!(1000), order_id)
+        clearing
+            .submit_order(MARKET_MAKER_ID, MARKET, Side::Sell, sell_price, dec!(1000))
             .unwrap();
-        order_id += 1;
     }
 
     for round in 1..=15 {
@@ -74,128 +121,274 @@ fn main() {
         println!("╚═══════════════════════════════════════════════════════════╝");
 
         let spot_movement = Decimal::try_from(rng.gen_range(-5.0..5.0)).unwrap_or(Decimal::ZERO);
-        oracle.price += spot_movement;
-        oracle.update(oracle.price).unwrap();
+        spot += spot_movement;
+        // Venues quote around spot; one occasionally prints a bad tick the median drops.
+        // In round 10 every feed freezes to show the safe mode kicking in.
+        let feeds_frozen = round == 10;
+        for (i, offset) in [dec!(0), dec!(0.4), dec!(-0.3)].into_iter().enumerate() {
+            if feeds_frozen {
+                break;
+            }
+            let bad_tick = i == 2 && rng.gen_bool(0.2);
+            let price = if bad_tick {
+                spot * dec!(1.1)
+            } else {
+                spot + offset
+            };
+            venues
+                .source_mut(i)
+                .unwrap()
+                .set_price(price, round as u64 * 3600);
+        }
+        // Frozen feeds age out, so the index goes stale instead of repeating old quotes
+        let aggregated = venues.aggregate(round as u64 * 3600).ok();
+        if let Some(aggregated) = &aggregated {
+            oracle.update(aggregated).unwrap();
+        }
 
-        let (best_bid, best_ask) = match (order_book.best_buy(), order_book.best_sell()) {
+        let (best_bid, best_ask) = match (
+            clearing.markets[&MARKET].order_book().best_buy(),
+            clearing.markets[&MARKET].order_book().best_sell(),
+        ) {
             (Some((bid, _)), Some((ask, _))) => (bid, ask),
             _ => (oracle.price - dec!(1), oracle.price + dec!(1)),
         };
+        let market = &clearing.markets[&MARKET];
         mark_price
-            .calculate(best_bid, best_ask, oracle.price)
+            .calculate(
+                market.order_book(),
+                oracle.price,
+                market.last_trade_price,
+                round as u64 * 3600,
+            )
             .unwrap();
-
-        funding_rate.add_price_sample(mark_price.price, oracle.price, round as u64 * 3600);
-        let current_funding = funding_rate
-            .calculate_funding_rate(round as u64 * 3600)
+        clearing.advance_time(round as u64 * 3600);
+        clearing
+            .update_mark_price(MARKET, mark_price.price)
             .unwrap();
-        funding_rate.update_open_interest(
-            position_manager.total_long_interest,
-            position_manager.total_short_interest,
+        match clearing.check_oracle(MARKET, &oracle).unwrap() {
+            Some(SafeModeEvent {
+                kind: SafeModeEventKind::Entered(status),
+                ..
+            }) => println!("\n🛑 Oracle {status:?}: safe mode on (funding frozen, opens rejected, liquidations paused)"),
+            Some(_) => println!("\n✅ Oracle healthy again: safe mode off"),
+            None => {}
+        }
+
+        let timestamp = round as u64 * 3600;
+        let funding = &mut clearing.market_mut(MARKET).unwrap().funding;
+        funding.add_price_sample(
+            mark_price.price,
+            oracle.price,
+            mark_price.premium,
+            timestamp,
         );
+        let predicted_funding = funding.predicted_rate().unwrap();
+        let funding_due = funding.should_apply_funding(timestamp);
 
         println!("\n📈 Market Prices:");
-        println!("  Oracle/Index Price:  ${:.2}", oracle.price);
+        println!(
+            "  Oracle/Index Price:  ${:.2} (median of {}, confidence {:.0}%)",
+            oracle.price,
+            oracle.source,
+            (oracle.confidence * dec!(100)).to_f64().unwrap_or(0.0)
+        );
+        if let Some(aggregated) = aggregated.filter(|aggregated| !aggregated.dropped.is_empty()) {
+            println!("  Dropped Sources:     {}", aggregated.dropped.join(", "));
+        }
         println!("  Mark Price:          ${:.2}", mark_price.price);
         println!("  Fair Price:          ${:.2}", mark_price.fair_price);
         println!("  Best Bid/Ask:        ${best_bid:.2} / ${best_ask:.2}");
         println!("  Spread:              ${:.2}", best_ask - best_bid);
+        match (mark_price.impact_bid, mark_price.impact_ask) {
+            (Some(bid), Some(ask)) => println!(
+                "  Impact Bid/Ask:      ${bid:.2} / ${ask:.2} (${} notional)",
+                mark_price.impact_notional
+            ),
+            _ => println!("  Impact Bid/Ask:      book too thin for impact notional"),
+        }
 
         println!("\n💰 Funding Rate:");
         println!(
-            "  Current Rate:        {:.4}% per 8h",
-            (current_funding * dec!(100)).to_f64().unwrap_or(0.0)
+            "  Predicted Rate:      {:.4}% per 8h",
+            (predicted_funding * dec!(100)).to_f64().unwrap_or(0.0)
         );
-        println!(
-            "  Premium Index:       {:.4}%",
-            (funding_rate.premium_index * dec!(100))
-                .to_f64()
-                .unwrap_or(0.0)
-        );
-        if current_funding > Decimal::ZERO {
+        if predicted_funding > Decimal::ZERO {
             println!("  Direction:           Longs pay Shorts ↗️");
-        } else if current_funding < Decimal::ZERO {
+        } else if predicted_funding < Decimal::ZERO {
             println!("  Direction:           Shorts pay Longs ↘️");
         } else {
             println!("  Direction:           Neutral ➡️");
         }
+        if funding_due {
+            let index = clearing.apply_funding(MARKET).unwrap();
+            let funding = &clearing.markets[&MARKET].funding;
+            println!(
+                "  Settled Rate:        {:.4}% (premium {:.4}%)",
+                (funding.current_rate * dec!(100)).to_f64().unwrap_or(0.0),
+                (funding.premium_index * dec!(100)).to_f64().unwrap_or(0.0)
+            );
+            println!("  Funding Index:       {index:.4} (settled on next position touch)");
+        }
 
         let action = rng.gen_range(0..100);
 
-        if action < 30 && position_manager.positions.len() < 10 {
+        if action < 30 && clearing.markets[&MARKET].position_manager.positions.len() < 10 {
             let side = if rng.gen_bool(0.5) {
-                PositionSide::Long
+                Side::Buy
             } else {
-                PositionSide::Short
+                Side::Sell
             };
             let size = Decimal::from(rng.gen_range(100..1000));
-            let leverage = Decimal::try_from(rng.gen_range(1.0..50.0)).unwrap_or(dec!(10));
-            let margin = ((mark_price.price * size) / leverage).round_dp(2);
-
-            match position_manager.open_position(
-                trader_id,
-                side,
-                size,
-                mark_price.price,
-                margin,
-                &liquidation_engine,
-            ) {
-                Ok(position) => {
-                    println!("\n🆕 New Position Opened:");
+            let leverage = Decimal::try_from(rng.gen_range(1.0..50.0))
+                .unwrap_or(dec!(10))
+                .round_dp(1);
+            let limit_price = match side {
+                Side::Buy => mark_price.price * dec!(1.02),
+                Side::Sell => mark_price.price * dec!(0.98),
+            }
+            .round_dp(2);
+            let leverage =
+                leverage.min(clearing.liquidation_engine.max_leverage(limit_price * size));
+            let collateral = ((limit_price * size) / leverage * dec!(1.1)).round_dp(2);
+            clearing.deposit(trader_id, collateral).unwrap();
+            clearing.set_leverage(trader_id, leverage).unwrap();
+            // Everyone after the first trader signed up through their referral link
+            if trader_id > 1 {
+                clearing.set_referrer(trader_id, 1).unwrap();
+            }
+
+            match clearing.submit_order(trader_id, MARKET, side, limit_price, size) {
+                Ok(outcome) => {
+                    println!("\n🆕 Order Matched Through CLOB:");
                     println!("  Trader #{trader_id}:");
                     println!("  Side:                {side:?}");
                     println!("  Size:                {size} contracts");
-                    println!("  Leverage:            {leverage:.1}x");
-                    println!("  Entry Price:         ${:.2}", position.entry_price);
-                    println!("  Margin:              ${margin}");
-                    println!("  Liquidation Price:   ${:.2}", position.liquidation_price);
+                    println!("  Fills:               {}", outcome.fills.len());
+                    println!("  Collateral Deposit:  ${collateral}");
+
+                    let fees: Decimal = outcome.fills.iter().map(|f| f.taker_fee()).sum();
+                    println!("  Fee Paid:            ${fees:.2}");
 
-                    let notional = mark_price.price * size;
-                    let fee = fee_structure.calculate_fee(false, notional);
-                    println!("  Fee Paid:            ${:.2}", fee.abs());
+                    if let Some(position) = clearing.markets[&MARKET]
+                        .position_manager
+                        .positions
+                        .get(&trader_id)
+                    {
+                        println!("  Leverage:            {:.1}x", position.leverage);
+                        println!("  Entry Price:         ${:.2}", position.entry_price);
+                        println!("  Liquidation Price:   ${:.2}", position.liquidation_price);
+
+                        // Every trader brackets the position with a 1% stop and a 1.5% target
+                        let (stop, target) = match position.side {
+                            PositionSide::Long => (dec!(0.99), dec!(1.015)),
+                            PositionSide::Short => (dec!(1.01), dec!(0.985)),
+                        };
+                        let stop_price = (position.entry_price * stop).round_dp(2);
+                        let target_price = (position.entry_price * target).round_dp(2);
+                        println!("  Stop Loss:           ${stop_price:.2}");
+                        println!("  Take Profit:         ${target_price:.2}");
+                        for trigger in [
+                            PositionTrigger::new(TriggerKind::StopLoss, stop_price),
+                            PositionTrigger::new(TriggerKind::TakeProfit, target_price),
+                        ] {
+                            clearing.attach_trigger(trader_id, MARKET, trigger).unwrap();
+                        }
+                    }
 
                     trader_id += 1;
                 }
                 Err(e) => {
-                    println!("\n⚠️  Position opening failed: {e}");
+                    println!("\n⚠️  Order rejected [{}]: {e}", e.code());
+                }
+            }
+        }
+
+        match clearing.run_triggers() {
+            Ok(triggered) => {
+                for outcome in triggered {
+                    println!(
+                        "\n🎯 Trader #{} {:?} hit at ${:.2}: closed {} contracts",
+                        outcome.trader_id, outcome.kind, outcome.reference_price, outcome.quantity
+                    );
                 }
             }
+            Err(e) => println!("\n⚠️  Trigger error: {e}"),
         }
 
-        match position_manager.update_positions(mark_price.price, &liquidation_engine) {
-            Ok(liquidated) => {
-                if !liquidated.is_empty() {
+        let alerts_seen = clearing.margin_alerts().len();
+        let liquidations = clearing.run_liquidations();
+        for alert in &clearing.margin_alerts()[alerts_seen..] {
+            println!(
+                "\n{} Trader #{} {:?} → {:?} (margin {:.2}%, liquidates at {:.2}%)",
+                alert_icon(alert.level),
+                alert.trader_id,
+                alert.previous,
+                alert.level,
+                (alert.margin_ratio * dec!(100)).to_f64().unwrap_or(0.0),
+                (alert.liquidation_ratio * dec!(100))
+                    .to_f64()
+                    .unwrap_or(0.0)
+            );
+        }
+        match liquidations {
+            Ok(liquidations) => {
+                if !liquidations.is_empty() {
                     println!("\n⚠️  LIQUIDATIONS:");
-                    for trader in liquidated {
+                    for liquidation in liquidations {
                         println!(
-                            "  🔴 Trader #{} position liquidated at ${:.2}",
-                            trader, mark_price.price
+                            "  🔴 Trader #{} {:?} reduced by {} through the book ({} left, penalty ${:.2})",
+                            liquidation.trader_id,
+                            liquidation.side,
+                            liquidation.quantity,
+                            liquidation.remaining_size,
+                            liquidation.penalty
                         );
-
-                        let liquidation_fee_amount = dec!(1000);
-                        insurance_fund
-                            .add_contribution(liquidation_fee_amount)
-                            .unwrap();
+                        for adl in &liquidation.deleveraged {
+                            println!(
+                                "     ADL: Trader #{} closed {} @ ${:.2}",
+                                adl.trader_id, adl.quantity, adl.price
+                            );
+                        }
+                        if liquidation.insurance_payout > Decimal::ZERO {
+                            println!(
+                                "     Insurance fund covered ${:.2} past bankruptcy",
+                                liquidation.insurance_payout
+                            );
+                        }
                     }
                 }
             }
             Err(e) => {
-                println!("\n⚠️  Error updating positions: {e}");
+                println!("\n⚠️  Error running liquidations: {e}");
             }
         }
 
         println!("\n📊 Open Interest:");
         println!(
             "  Total Long:          {} contracts",
-            position_manager.total_long_interest
+            clearing.markets[&MARKET]
+                .position_manager
+                .total_long_interest
         );
         println!(
             "  Total Short:         {} contracts",
-            position_manager.total_short_interest
+            clearing.markets[&MARKET]
+                .position_manager
+                .total_short_interest
         );
-        let imbalance =
-            position_manager.total_long_interest - position_manager.total_short_interest;
-        let total_oi = position_manager.total_long_interest + position_manager.total_short_interest;
+        let imbalance = clearing.markets[&MARKET]
+            .position_manager
+            .total_long_interest
+            - clearing.markets[&MARKET]
+                .position_manager
+                .total_short_interest;
+        let total_oi = clearing.markets[&MARKET]
+            .position_manager
+            .total_long_interest
+            + clearing.markets[&MARKET]
+                .position_manager
+                .total_short_interest;
         if total_oi > Decimal::ZERO {
             let imbalance_pct = (imbalance / total_oi) * dec!(100);
             println!(
@@ -211,35 +404,54 @@ fn main() {
             );
         }
 
-        if !position_manager.positions.is_empty() {
+        if !clearing.markets[&MARKET]
+            .position_manager
+            .positions
+            .is_empty()
+        {
             println!("\n💼 Active Positions (Top 3):");
-            let mut positions: Vec<_> = position_manager.positions.values().collect();
-            positions.sort_by(|a, b| b.size.cmp(&a.size));
+            let mut positions: Vec<_> = clearing.markets[&MARKET]
+                .position_manager
+                .positions
+                .values()
+                .collect();
+            positions.sort_by_key(|p| std::cmp::Reverse(p.size));
 
             for (i, pos) in positions.iter().take(3).enumerate() {
-                let pnl = LiquidationEngine::calculate_pnl(pos, mark_price.price);
-                let margin_ratio = liquidation_engine
-                    .calculate_margin_ratio(pos, mark_price.price)
-                    .unwrap_or(Decimal::ZERO);
-                let health = if margin_ratio > dec!(0.02) {
-                    "🟢"
-                } else if margin_ratio > dec!(0.01) {
-                    "🟡"
-                } else {
-                    "🔴"
+                let portfolio = clearing.portfolio(pos.trader_id).unwrap();
+                let Some(summary) = portfolio
+                    .positions
+                    .iter()
+                    .find(|summary| summary.market_id == MARKET)
+                else {
+                    continue;
                 };
+                let health = alert_icon(clearing.margin_alert_level(pos.trader_id, Some(MARKET)));
 
                 println!(
                     "  {}. Trader #{}: {:?} {} @ ${:.2} | PnL: ${:.2} | Margin: {:.2}% {}",
                     i + 1,
                     pos.trader_id,
-                    pos.side,
-                    pos.size,
-                    pos.entry_price,
-                    pnl,
-                    (margin_ratio * dec!(100)).to_f64().unwrap_or(0.0),
+                    summary.side,
+                    summary.size,
+                    summary.entry_price,
+                    summary.unrealized_pnl,
+                    (summary
+                        .margin_ratio
+                        .or(portfolio.margin_ratio)
+                        .unwrap_or_default()
+                        * dec!(100))
+                    .to_f64()
+                    .unwrap_or(0.0),
                     health
                 );
+                println!(
+                    "     Equity: ${:.2} | Realized: ${:.2} | Fees: ${:.2} | Funding: ${:.2}",
+                    portfolio.equity,
+                    portfolio.realized_pnl,
+                    portfolio.fees.net(),
+                    portfolio.funding.net()
+                );
             }
         }
 
@@ -255,13 +467,16 @@ fn main() {
                 mark_price.price + Decimal::from(rng.gen_range(1..10))
             };
             let qty = Decimal::from(rng.gen_range(100..1000));
-            order_book.place_order(side, price, qty, order_id).unwrap();
-            order_id += 1;
+            if let Err(e) = clearing.submit_order(MARKET_MAKER_ID, MARKET, side, price, qty) {
+                println!("\n⚠️  Market maker quote rejected [{}]: {e}", e.code());
+            }
         }
 
         println!(
             "\n🛡️  Insurance Fund: ${} (Contributions: ${}, Payouts: ${})",
-            insurance_fund.balance, insurance_fund.contributions, insurance_fund.payouts
+            clearing.insurance_fund.balance,
+            clearing.insurance_fund.contributions,
+            clearing.insurance_fund.payouts
         );
 
         thread::sleep(Duration::from_secs(2));
@@ -275,11 +490,53 @@ fn main() {
     println!("  Total Positions Opened:     {}", trader_id - 1);
     println!(
         "  Active Positions:           {}",
-        position_manager.positions.len()
+        clearing.markets[&MARKET].position_manager.positions.len()
     );
     println!("  Final Mark Price:           ${:.2}", mark_price.price);
     println!("  Final Index Price:          ${:.2}", oracle.price);
-    println!("  Insurance Fund Balance:     ${}", insurance_fund.balance);
+    println!(
+        "  Insurance Fund Balance:     ${}",
+        clearing.insurance_fund.balance
+    );
+    println!("  Treasury:                   ${:.2}", clearing.treasury);
+    println!(
+        "  Net Fees Collected:         ${:.2}",
+        clearing.fees_collected
+    );
+    let referral_earnings: Decimal = clearing
+        .accounts()
+        .entries_for(1)
+        .filter(|entry| entry.kind == LedgerEntryKind::ReferralRebate)
+        .map(|entry| entry.amount)
+        .sum();
+    println!("  Referral Rebates (#1):      ${referral_earnings:.2}");
+    let maker_rates = clearing.fee_rates(MARKET_MAKER_ID);
+    println!(
+        "  Market Maker Fee Rates:     maker {}%  taker {}%",
+        (maker_rates.maker_fee() * dec!(100)).normalize(),
+        (maker_rates.taker_fee() * dec!(100)).normalize()
+    );
+
+    println!("\n🧾 Statement for Trader #1 (CSV):");
+    if let Ok(statement) = clearing.statement(1, 0, clearing.timestamp) {
+        println!(
+            "  Opening ${:.2} → Closing ${:.2}",
+            statement.opening_balance, statement.closing_balance
+        );
+        for line in statement.to_csv().lines() {
+            println!("  {line}");
+        }
+    }
+
+    println!("\n📜 Funding History:");
+    for event in clearing.markets[&MARKET].funding.history() {
+        println!(
+            "  t={:>6}  rate {:>8.4}%  premium {:>8.4}%",
+            event.timestamp,
+            (event.rate * dec!(100)).to_f64().unwrap_or(0.0),
+            (event.premium_index * dec!(100)).to_f64().unwrap_or(0.0)
+        );
+    }
 
     println!("\n✨ Key Perpetual DEX Features Demonstrated:");
     println!("  ✅ Funding Rate Mechanism (longs/shorts pay based on premium)");
@@ -291,3 +548,12 @@ fn main() {
     println!("  ✅ Maker/Taker Fee Structure");
     println!("  ✅ Open Interest Tracking");
 }
+
+fn alert_icon(level: MarginAlertLevel) -> &'static str {
+    match level {
+        MarginAlertLevel::Healthy => "🟢",
+        MarginAlertLevel::Warning => "🟡",
+        MarginAlertLevel::MarginCall => "🟠",
+        MarginAlertLevel::Liquidation => "🔴",
+    }
+}
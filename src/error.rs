@@ -1,3 +1,4 @@
+use rust_decimal::Decimal;
 use thiserror::Error;
 
 #[derive(Error, Debug, Clone, PartialEq)]
@@ -11,14 +12,98 @@ pub enum OrderBookError {
     #[error("Order not found: {id}")]
     OrderNotFound { id: u64 },
 
+    #[error("Trigger not found: {id}")]
+    TriggerNotFound { id: u64 },
+
+    #[error("Duplicate order id: {id}")]
+    DuplicateOrderId { id: u64 },
+
+    #[error("Order pool exhausted: capacity {capacity}")]
+    OrderPoolExhausted { capacity: usize },
+
+    #[error("Price level at {price} links an order that is no longer in the pool")]
+    StaleLevelLink { price: Decimal },
+
+    #[error("Price {price} is not a multiple of tick size {tick_size}")]
+    OffTick { price: Decimal, tick_size: Decimal },
+
+    #[error("Post-only order at {price} would cross the best opposite price {best_price}")]
+    PostOnlyWouldCross { price: Decimal, best_price: Decimal },
+
+    #[error("Order from trader {trader_id} would trade against its own order {order_id}")]
+    SelfTrade { trader_id: u64, order_id: u64 },
+
     #[error("Insufficient margin: required {required}, provided {provided}")]
-    InsufficientMargin { required: u64, provided: u64 },
+    InsufficientMargin {
+        required: Decimal,
+        provided: Decimal,
+    },
+
+    #[error(
+        "Insufficient balance for trader {trader_id}: requested {requested}, available {available}"
+    )]
+    InsufficientBalance {
+        trader_id: u64,
+        requested: Decimal,
+        available: Decimal,
+    },
+
+    #[error("Account not found for trader: {trader_id}")]
+    AccountNotFound { trader_id: u64 },
+
+    #[error("Margin mode locked for trader {trader_id}: close positions and orders first")]
+    MarginModeLocked { trader_id: u64 },
+
+    #[error("Market not found: {market_id}")]
+    MarketNotFound { market_id: u32 },
+
+    #[error("Market {market_id} is halted")]
+    MarketHalted { market_id: u32 },
+
+    #[error("Market {market_id} has expired")]
+    MarketExpired { market_id: u32 },
+
+    #[error("Market {market_id} has not expired")]
+    MarketNotExpired { market_id: u32 },
+
+    #[error("Market already exists: {market_id}")]
+    MarketExists { market_id: u32 },
+
+    #[error("Position for trader {trader_id} in market {market_id} is not liquidatable")]
+    NotLiquidatable { trader_id: u64, market_id: u32 },
+
+    #[error("Reduce-only order for trader {trader_id} in market {market_id} would not reduce a position")]
+    ReduceOnlyRejected { trader_id: u64, market_id: u32 },
 
     #[error("Position not found for trader: {trader_id}")]
     PositionNotFound { trader_id: u64 },
 
+    #[error("{open_orders} open orders reach the limit of {limit}")]
+    TooManyOpenOrders { open_orders: usize, limit: usize },
+
+    #[error("Order notional {notional} exceeds the limit of {limit}")]
+    OrderNotionalExceeded { notional: Decimal, limit: Decimal },
+
+    #[error("Position could reach {size}, above the limit of {limit}")]
+    PositionSizeExceeded { size: Decimal, limit: Decimal },
+
+    #[error("Open interest {open_interest} would exceed cap {cap}")]
+    OpenInterestCapExceeded {
+        open_interest: Decimal,
+        cap: Decimal,
+    },
+
     #[error("Invalid leverage: {0}")]
-    InvalidLeverage(f64),
+    InvalidLeverage(Decimal),
+
+    #[error("Market {market_id} is in oracle safe mode")]
+    OracleSafeMode { market_id: u32 },
+
+    #[error("Oracle for market {market_id} is stale")]
+    StaleOracle { market_id: u32 },
+
+    #[error("Oracle unavailable: {0}")]
+    OracleUnavailable(String),
 
     #[error("Market manipulation detected: {0}")]
     MarketManipulation(String),
@@ -27,4 +112,44 @@ pub enum OrderBookError {
     OverflowError(String),
 }
 
+impl OrderBookError {
+    // Stable identifiers for clients; messages may be reworded, codes never change
+    pub fn code(&self) -> &'static str {
+        match self {
+            OrderBookError::InvalidQuantity(_) => "INVALID_QUANTITY",
+            OrderBookError::InvalidPrice(_) => "INVALID_PRICE",
+            OrderBookError::OrderNotFound { .. } => "ORDER_NOT_FOUND",
+            OrderBookError::TriggerNotFound { .. } => "TRIGGER_NOT_FOUND",
+            OrderBookError::DuplicateOrderId { .. } => "DUPLICATE_ORDER_ID",
+            OrderBookError::OrderPoolExhausted { .. } => "ORDER_POOL_EXHAUSTED",
+            OrderBookError::StaleLevelLink { .. } => "STALE_LEVEL_LINK",
+            OrderBookError::OffTick { .. } => "OFF_TICK",
+            OrderBookError::PostOnlyWouldCross { .. } => "POST_ONLY_WOULD_CROSS",
+            OrderBookError::SelfTrade { .. } => "SELF_TRADE",
+            OrderBookError::InsufficientMargin { .. } => "INSUFFICIENT_MARGIN",
+            OrderBookError::InsufficientBalance { .. } => "INSUFFICIENT_BALANCE",
+            OrderBookError::AccountNotFound { .. } => "ACCOUNT_NOT_FOUND",
+            OrderBookError::MarginModeLocked { .. } => "MARGIN_MODE_LOCKED",
+            OrderBookError::MarketNotFound { .. } => "MARKET_NOT_FOUND",
+            OrderBookError::MarketHalted { .. } => "MARKET_HALTED",
+            OrderBookError::MarketExpired { .. } => "MARKET_EXPIRED",
+            OrderBookError::MarketNotExpired { .. } => "MARKET_NOT_EXPIRED",
+            OrderBookError::MarketExists { .. } => "MARKET_EXISTS",
+            OrderBookError::NotLiquidatable { .. } => "NOT_LIQUIDATABLE",
+            OrderBookError::ReduceOnlyRejected { .. } => "REDUCE_ONLY_REJECTED",
+            OrderBookError::PositionNotFound { .. } => "POSITION_NOT_FOUND",
+            OrderBookError::TooManyOpenOrders { .. } => "TOO_MANY_OPEN_ORDERS",
+            OrderBookError::OrderNotionalExceeded { .. } => "ORDER_NOTIONAL_EXCEEDED",
+            OrderBookError::PositionSizeExceeded { .. } => "POSITION_SIZE_EXCEEDED",
+            OrderBookError::OpenInterestCapExceeded { .. } => "OPEN_INTEREST_CAP_EXCEEDED",
+            OrderBookError::InvalidLeverage(_) => "INVALID_LEVERAGE",
+            OrderBookError::OracleSafeMode { .. } => "ORACLE_SAFE_MODE",
+            OrderBookError::StaleOracle { .. } => "STALE_ORACLE",
+            OrderBookError::OracleUnavailable(_) => "ORACLE_UNAVAILABLE",
+            OrderBookError::MarketManipulation(_) => "MARKET_MANIPULATION",
+            OrderBookError::OverflowError(_) => "OVERFLOW",
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, OrderBookError>;
@@ -1,29 +1,84 @@
-use crate::error::Result;
+use crate::error::{OrderBookError, Result};
 use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
 use std::collections::VecDeque;
 
-const FUNDING_INTERVAL_SECONDS: u64 = 28800;
-const MAX_FUNDING_RATE: Decimal = Decimal::ONE;
-const MIN_FUNDING_RATE: Decimal = Decimal::NEGATIVE_ONE;
-const INTEREST_RATE: Decimal = Decimal::ZERO;
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundingConfig {
+    pub interval_seconds: u64,
+    pub max_rate: Decimal,
+    pub min_rate: Decimal,
+    pub interest_rate: Decimal,
+    pub dampener: Decimal,
+    // Extra rate per unit of get_imbalance_ratio, charged to the heavier side
+    pub imbalance_weight: Decimal,
+}
+
+impl Default for FundingConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FundingConfig {
+    pub fn new() -> Self {
+        Self {
+            interval_seconds: 28800,
+            max_rate: dec!(0.01),
+            min_rate: dec!(-0.01),
+            interest_rate: Decimal::ZERO,
+            dampener: Decimal::ZERO,
+            imbalance_weight: Decimal::ZERO,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.interval_seconds == 0 {
+            return Err(OrderBookError::InvalidQuantity(
+                "Funding interval must be positive".to_string(),
+            ));
+        }
+
+        if self.min_rate > self.max_rate
+            || self.dampener < Decimal::ZERO
+            || self.imbalance_weight < Decimal::ZERO
+        {
+            return Err(OrderBookError::InvalidPrice(format!(
+                "Invalid funding clamp: [{}, {}] with dampener {}",
+                self.min_rate, self.max_rate, self.dampener
+            )));
+        }
+
+        Ok(())
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PriceSample {
     pub mark_price: Decimal,
     pub index_price: Decimal,
+    pub premium: Decimal,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundingEvent {
     pub timestamp: u64,
+    pub rate: Decimal,
+    pub premium_index: Decimal,
 }
 
 #[derive(Debug, Clone)]
 pub struct FundingRate {
+    config: FundingConfig,
     pub current_rate: Decimal,
     pub next_funding_time: u64,
     pub premium_index: Decimal,
-    pub interest_rate: Decimal,
     pub long_open_interest: Decimal,
     pub short_open_interest: Decimal,
     price_samples: VecDeque<PriceSample>,
     max_samples: usize,
+    history: Vec<FundingEvent>,
 }
 
 impl Default for FundingRate {
@@ -34,22 +89,44 @@ impl Default for FundingRate {
 
 impl FundingRate {
     pub fn new() -> Self {
+        Self::build(FundingConfig::new())
+    }
+
+    pub fn with_config(config: FundingConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(Self::build(config))
+    }
+
+    pub fn config(&self) -> &FundingConfig {
+        &self.config
+    }
+
+    fn build(config: FundingConfig) -> Self {
         Self {
             current_rate: Decimal::ZERO,
-            next_funding_time: FUNDING_INTERVAL_SECONDS,
+            next_funding_time: config.interval_seconds,
             premium_index: Decimal::ZERO,
-            interest_rate: INTEREST_RATE,
             long_open_interest: Decimal::ZERO,
             short_open_interest: Decimal::ZERO,
             price_samples: VecDeque::new(),
             max_samples: 480,
+            history: Vec::new(),
+            config,
         }
     }
 
-    pub fn add_price_sample(&mut self, mark_price: Decimal, index_price: Decimal, timestamp: u64) {
+    // `premium` is the impact premium as a fraction of the index (see MarkPrice::premium)
+    pub fn add_price_sample(
+        &mut self,
+        mark_price: Decimal,
+        index_price: Decimal,
+        premium: Decimal,
+        timestamp: u64,
+    ) {
         let sample = PriceSample {
             mark_price,
             index_price,
+            premium,
             timestamp,
         };
 
@@ -83,7 +160,7 @@ impl FundingRate {
 
         for i in 0..relevant_samples.len() {
             let sample = relevant_samples[i];
-            let premium = (sample.mark_price - sample.index_price) / sample.index_price;
+            let premium = sample.premium;
 
             let weight = if i < relevant_samples.len() - 1 {
                 Decimal::from(relevant_samples[i + 1].timestamp - sample.timestamp)
@@ -102,20 +179,49 @@ impl FundingRate {
         }
     }
 
+    // Live estimate of the next settlement from the running premium TWAP
+    pub fn predicted_rate(&self) -> Result<Decimal> {
+        let premium = self.calculate_twap_premium(self.config.interval_seconds)?;
+        Ok(self.rate_from_premium(premium))
+    }
+
     pub fn calculate_funding_rate(&mut self, timestamp: u64) -> Result<Decimal> {
-        let premium_8h = self.calculate_twap_premium(FUNDING_INTERVAL_SECONDS)?;
+        let premium = self.calculate_twap_premium(self.config.interval_seconds)?;
 
-        self.premium_index = premium_8h;
+        self.premium_index = premium;
+        self.current_rate = self.rate_from_premium(premium);
+        self.next_funding_time = timestamp + self.config.interval_seconds;
+        self.history.push(FundingEvent {
+            timestamp,
+            rate: self.current_rate,
+            premium_index: premium,
+        });
+
+        Ok(self.current_rate)
+    }
 
-        let funding_rate = premium_8h + self.interest_rate;
+    pub fn history(&self) -> &[FundingEvent] {
+        &self.history
+    }
 
-        self.current_rate = funding_rate
-            .max(MIN_FUNDING_RATE / Decimal::from(100))
-            .min(MAX_FUNDING_RATE / Decimal::from(100));
+    pub fn history_between(&self, from: u64, to: u64) -> impl Iterator<Item = &FundingEvent> {
+        self.history
+            .iter()
+            .filter(move |event| event.timestamp >= from && event.timestamp <= to)
+    }
 
-        self.next_funding_time = timestamp + FUNDING_INTERVAL_SECONDS;
+    // Premiums within `dampener` of the interest rate settle at the interest rate; with no
+    // dampener the interest rate is added to the premium outright
+    fn rate_from_premium(&self, premium: Decimal) -> Decimal {
+        let config = &self.config;
+        let adjustment = if config.dampener.is_zero() {
+            config.interest_rate
+        } else {
+            (config.interest_rate - premium).clamp(-config.dampener, config.dampener)
+        };
+        let imbalance = config.imbalance_weight * self.get_imbalance_ratio();
 
-        Ok(self.current_rate)
+        (premium + adjustment + imbalance).clamp(config.min_rate, config.max_rate)
     }
 
     pub fn update_open_interest(&mut self, long_oi: Decimal, short_oi: Decimal) {
@@ -136,8 +242,13 @@ impl FundingRate {
         timestamp >= self.next_funding_time
     }
 
-    pub fn calculate_funding_payment(&self, position_size: Decimal, is_long: bool) -> Decimal {
-        let payment = position_size * self.current_rate;
+    pub fn calculate_funding_payment(
+        &self,
+        position_size: Decimal,
+        mark_price: Decimal,
+        is_long: bool,
+    ) -> Decimal {
+        let payment = position_size * mark_price * self.current_rate;
         if is_long {
             -payment
         } else {
@@ -145,3 +256,79 @@ impl FundingRate {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predicted_rate_tracks_premium_and_settles_into_history() {
+        let mut funding = FundingRate::new();
+        funding.add_price_sample(dec!(1002), dec!(1000), dec!(0.002), 0);
+        assert_eq!(funding.predicted_rate().unwrap(), dec!(0.002));
+        assert!(funding.history().is_empty());
+
+        funding.add_price_sample(dec!(1000), dec!(1000), dec!(0), 60);
+        assert_eq!(funding.predicted_rate().unwrap(), dec!(0.001));
+
+        assert_eq!(funding.calculate_funding_rate(28800).unwrap(), dec!(0.001));
+        assert_eq!(funding.next_funding_time, 57600);
+        assert_eq!(
+            funding.history(),
+            &[FundingEvent {
+                timestamp: 28800,
+                rate: dec!(0.001),
+                premium_index: dec!(0.001),
+            }]
+        );
+        assert_eq!(funding.history_between(0, 28799).count(), 0);
+    }
+
+    #[test]
+    fn test_config_clamps_and_dampens() {
+        let config = FundingConfig {
+            interval_seconds: 3600,
+            max_rate: dec!(0.0075),
+            min_rate: dec!(-0.0075),
+            interest_rate: dec!(0.0001),
+            dampener: dec!(0.0005),
+            imbalance_weight: Decimal::ZERO,
+        };
+        let mut funding = FundingRate::with_config(config.clone()).unwrap();
+        assert_eq!(funding.next_funding_time, 3600);
+
+        // Inside the dampener the interest rate wins
+        funding.add_price_sample(dec!(1000.3), dec!(1000), dec!(0.0003), 0);
+        assert_eq!(funding.predicted_rate().unwrap(), dec!(0.0001));
+
+        let mut funding = FundingRate::with_config(config.clone()).unwrap();
+        funding.add_price_sample(dec!(1050), dec!(1000), dec!(0.05), 0);
+        assert_eq!(funding.predicted_rate().unwrap(), dec!(0.0075));
+
+        // Longs outweigh shorts 3:1, so half the weight is added on top
+        let mut funding = FundingRate::with_config(FundingConfig {
+            imbalance_weight: dec!(0.002),
+            ..config.clone()
+        })
+        .unwrap();
+        funding.update_open_interest(dec!(300), dec!(100));
+        funding.add_price_sample(dec!(1002), dec!(1000), dec!(0.002), 0);
+        assert_eq!(funding.predicted_rate().unwrap(), dec!(0.0025));
+
+        // Without a dampener the interest rate still applies on top of the premium
+        let mut funding = FundingRate::with_config(FundingConfig {
+            dampener: Decimal::ZERO,
+            ..config.clone()
+        })
+        .unwrap();
+        funding.add_price_sample(dec!(1000.3), dec!(1000), dec!(0.0003), 0);
+        assert_eq!(funding.predicted_rate().unwrap(), dec!(0.0004));
+        assert_eq!(funding.config().interest_rate, dec!(0.0001));
+
+        let invalid = FundingConfig {
+            min_rate: dec!(0.01),
+            ..config
+        };
+        assert!(FundingRate::with_config(invalid).is_err());
+    }
+}
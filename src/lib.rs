@@ -10,7 +10,7 @@ pub use orderbook::OrderBook;
 pub use types::{Side, Trade};
 
 // Funding exports
-pub use funding::FundingRate;
+pub use funding::{FundingConfig, FundingEvent, FundingRate};
 
 // Perps exports - re-export everything from perps module
 pub use perps::*;
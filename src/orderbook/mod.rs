@@ -1,14 +1,21 @@
 pub mod price;
+mod slab;
 
 use crate::error::{OrderBookError, Result};
 use crate::types::{Order, Side, Trade};
 use price::BuyPrice;
 use rust_decimal::Decimal;
-use std::collections::{BTreeMap, VecDeque};
+use slab::{OrderNode, OrderSlab, PriceLevel};
+use std::collections::{BTreeMap, HashMap};
+
+// Upper bound on resting orders per book; storage starts small and grows toward it
+pub const DEFAULT_ORDER_CAPACITY: usize = 100_000;
 
 pub struct OrderBook {
-    buy_levels: BTreeMap<BuyPrice, VecDeque<Order>>,
-    sell_levels: BTreeMap<Decimal, VecDeque<Order>>,
+    buy_levels: BTreeMap<BuyPrice, PriceLevel>,
+    sell_levels: BTreeMap<Decimal, PriceLevel>,
+    orders: OrderSlab,
+    order_index: HashMap<u64, usize>,
     sequence: u64,
     min_price: Decimal,
     max_price: Decimal,
@@ -18,9 +25,15 @@ pub struct OrderBook {
 impl OrderBook {
     #[inline]
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_ORDER_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
             buy_levels: BTreeMap::new(),
             sell_levels: BTreeMap::new(),
+            orders: OrderSlab::with_capacity(capacity),
+            order_index: HashMap::new(),
             sequence: 0,
             min_price: Decimal::from(1),
             max_price: Decimal::from(1_000_000),
@@ -35,6 +48,54 @@ impl OrderBook {
         quantity: Decimal,
         id: u64,
     ) -> Result<Vec<Trade>> {
+        self.submit(side, price, quantity, id, true, |maker: &Order| {
+            maker.quantity
+        })
+    }
+
+    // Immediate-or-cancel: matches what it can and never rests the remainder
+    pub fn place_ioc_order(
+        &mut self,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+        id: u64,
+    ) -> Result<Vec<Trade>> {
+        self.submit(side, price, quantity, id, false, |maker: &Order| {
+            maker.quantity
+        })
+    }
+
+    // Like place_order/place_ioc_order, but asks `fillable` how much of each resting order may
+    // trade before matching it. Makers capped below their size are trimmed in place, or
+    // cancelled outright when capped at zero.
+    pub fn place_order_with<F>(
+        &mut self,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+        id: u64,
+        rest: bool,
+        fillable: F,
+    ) -> Result<Vec<Trade>>
+    where
+        F: FnMut(&Order) -> Decimal,
+    {
+        self.submit(side, price, quantity, id, rest, fillable)
+    }
+
+    fn submit<F>(
+        &mut self,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+        id: u64,
+        rest: bool,
+        mut fillable: F,
+    ) -> Result<Vec<Trade>>
+    where
+        F: FnMut(&Order) -> Decimal,
+    {
         if quantity <= Decimal::ZERO {
             return Err(OrderBookError::InvalidQuantity(
                 "Quantity must be positive".to_string(),
@@ -55,6 +116,19 @@ impl OrderBook {
             )));
         }
 
+        if self.order_index.contains_key(&id) {
+            return Err(OrderBookError::DuplicateOrderId { id });
+        }
+
+        // A full pool can't rest an order that trades with nothing. One that crosses always
+        // matches first: any remainder means every maker it reached was filled or capped out
+        // of the book, so their slots are free by the time the remainder rests.
+        if rest && self.orders.is_full() && !self.crosses(side, price) {
+            return Err(OrderBookError::OrderPoolExhausted {
+                capacity: self.orders.capacity(),
+            });
+        }
+
         let timestamp = self.sequence;
         self.sequence = self
             .sequence
@@ -62,32 +136,154 @@ impl OrderBook {
             .ok_or_else(|| OrderBookError::OverflowError("Sequence overflow".to_string()))?;
 
         match side {
-            Side::Buy => self.place_buy_order(price, quantity, id, timestamp),
-            Side::Sell => self.place_sell_order(price, quantity, id, timestamp),
+            Side::Buy => self.place_buy_order(price, quantity, id, timestamp, rest, &mut fillable),
+            Side::Sell => {
+                self.place_sell_order(price, quantity, id, timestamp, rest, &mut fillable)
+            }
+        }
+    }
+
+    pub fn cancel_order(&mut self, id: u64) -> Result<Order> {
+        let key = self
+            .order_index
+            .remove(&id)
+            .ok_or(OrderBookError::OrderNotFound { id })?;
+
+        let (side, price) = match self.orders.get(key) {
+            Some(node) => (node.side, node.price),
+            None => return Err(OrderBookError::OrderNotFound { id }),
+        };
+
+        match side {
+            Side::Buy => {
+                if let Some(level) = self.buy_levels.get_mut(&BuyPrice(price)) {
+                    level.unlink(&mut self.orders, key);
+                    if level.is_empty() {
+                        self.buy_levels.remove(&BuyPrice(price));
+                    }
+                }
+            }
+            Side::Sell => {
+                if let Some(level) = self.sell_levels.get_mut(&price) {
+                    level.unlink(&mut self.orders, key);
+                    if level.is_empty() {
+                        self.sell_levels.remove(&price);
+                    }
+                }
+            }
+        }
+
+        self.orders
+            .remove(key)
+            .map(|node| node.order)
+            .ok_or(OrderBookError::OrderNotFound { id })
+    }
+
+    // Shrinks a resting order in place without losing its queue position; zero cancels it
+    pub fn reduce_order(&mut self, id: u64, quantity: Decimal) -> Result<Decimal> {
+        let key = *self
+            .order_index
+            .get(&id)
+            .ok_or(OrderBookError::OrderNotFound { id })?;
+        let node = self
+            .orders
+            .get(key)
+            .ok_or(OrderBookError::OrderNotFound { id })?;
+        if quantity < Decimal::ZERO || quantity > node.order.quantity {
+            return Err(OrderBookError::InvalidQuantity(format!(
+                "Order {id} can only be reduced, from {}",
+                node.order.quantity
+            )));
+        }
+
+        let removed = node.order.quantity - quantity;
+        if quantity.is_zero() {
+            self.cancel_order(id)?;
+            return Ok(removed);
+        }
+
+        let (side, price) = (node.side, node.price);
+        let level = match side {
+            Side::Buy => self.buy_levels.get_mut(&BuyPrice(price)),
+            Side::Sell => self.sell_levels.get_mut(&price),
+        };
+        if let Some(level) = level {
+            level.total_quantity -= removed;
+        }
+        if let Some(node) = self.orders.get_mut(key) {
+            node.order.quantity = quantity;
+        }
+        Ok(removed)
+    }
+
+    #[inline]
+    pub fn order(&self, id: u64) -> Option<&Order> {
+        let key = *self.order_index.get(&id)?;
+        self.orders.get(key).map(|node| &node.order)
+    }
+
+    #[inline]
+    pub fn price_bounds(&self) -> (Decimal, Decimal) {
+        (self.min_price, self.max_price)
+    }
+
+    #[inline]
+    pub fn order_count(&self) -> usize {
+        self.orders.len()
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.orders.capacity()
+    }
+
+    fn crosses(&self, side: Side, price: Decimal) -> bool {
+        match side {
+            Side::Buy => self
+                .sell_levels
+                .first_key_value()
+                .is_some_and(|(&best, _)| best <= price),
+            Side::Sell => self
+                .buy_levels
+                .first_key_value()
+                .is_some_and(|(&BuyPrice(best), _)| best >= price),
         }
     }
 
     #[inline]
-    fn place_buy_order(
+    fn place_buy_order<F>(
         &mut self,
         price: Decimal,
         quantity: Decimal,
         id: u64,
         timestamp: u64,
-    ) -> Result<Vec<Trade>> {
+        rest: bool,
+        fillable: &mut F,
+    ) -> Result<Vec<Trade>>
+    where
+        F: FnMut(&Order) -> Decimal,
+    {
         let mut trades = Vec::new();
         let mut remaining = quantity;
         let mut exhausted_levels = Vec::new();
 
-        for (&level_price, level_orders) in &mut self.sell_levels {
+        for (&level_price, level) in &mut self.sell_levels {
             if level_price > price {
                 break;
             }
 
-            remaining =
-                Self::match_at_level(level_orders, remaining, level_price, id, &mut trades)?;
+            remaining = Self::match_at_level(
+                level,
+                &mut self.orders,
+                &mut self.order_index,
+                remaining,
+                level_price,
+                id,
+                &mut trades,
+                fillable,
+            )?;
 
-            if level_orders.is_empty() {
+            if level.is_empty() {
                 exhausted_levels.push(level_price);
             }
 
@@ -100,41 +296,51 @@ impl OrderBook {
             self.sell_levels.remove(&level);
         }
 
-        if remaining > Decimal::ZERO {
+        if rest && remaining > Decimal::ZERO {
+            let key = self.rest_order(Side::Buy, price, remaining, id, timestamp)?;
             self.buy_levels
                 .entry(BuyPrice(price))
                 .or_default()
-                .push_back(Order {
-                    id,
-                    quantity: remaining,
-                    timestamp,
-                });
+                .push_back(&mut self.orders, key);
         }
 
         Ok(trades)
     }
 
     #[inline]
-    fn place_sell_order(
+    fn place_sell_order<F>(
         &mut self,
         price: Decimal,
         quantity: Decimal,
         id: u64,
         timestamp: u64,
-    ) -> Result<Vec<Trade>> {
+        rest: bool,
+        fillable: &mut F,
+    ) -> Result<Vec<Trade>>
+    where
+        F: FnMut(&Order) -> Decimal,
+    {
         let mut trades = Vec::new();
         let mut remaining = quantity;
         let mut exhausted_levels = Vec::new();
 
-        for (&BuyPrice(level_price), level_orders) in &mut self.buy_levels {
+        for (&BuyPrice(level_price), level) in &mut self.buy_levels {
             if level_price < price {
                 break;
             }
 
-            remaining =
-                Self::match_at_level(level_orders, remaining, level_price, id, &mut trades)?;
+            remaining = Self::match_at_level(
+                level,
+                &mut self.orders,
+                &mut self.order_index,
+                remaining,
+                level_price,
+                id,
+                &mut trades,
+                fillable,
+            )?;
 
-            if level_orders.is_empty() {
+            if level.is_empty() {
                 exhausted_levels.push(BuyPrice(level_price));
             }
 
@@ -147,27 +353,82 @@ impl OrderBook {
             self.buy_levels.remove(&level);
         }
 
-        if remaining > Decimal::ZERO {
-            self.sell_levels.entry(price).or_default().push_back(Order {
-                id,
-                quantity: remaining,
-                timestamp,
-            });
+        if rest && remaining > Decimal::ZERO {
+            let key = self.rest_order(Side::Sell, price, remaining, id, timestamp)?;
+            self.sell_levels
+                .entry(price)
+                .or_default()
+                .push_back(&mut self.orders, key);
         }
 
         Ok(trades)
     }
 
     #[inline]
-    fn match_at_level(
-        level_orders: &mut VecDeque<Order>,
+    fn rest_order(
+        &mut self,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+        id: u64,
+        timestamp: u64,
+    ) -> Result<usize> {
+        let key = self
+            .orders
+            .insert(OrderNode {
+                order: Order {
+                    id,
+                    quantity,
+                    timestamp,
+                },
+                side,
+                price,
+                prev: None,
+                next: None,
+            })
+            .ok_or(OrderBookError::OrderPoolExhausted {
+                capacity: self.orders.capacity(),
+            })?;
+        self.order_index.insert(id, key);
+        Ok(key)
+    }
+
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    fn match_at_level<F>(
+        level: &mut PriceLevel,
+        orders: &mut OrderSlab,
+        order_index: &mut HashMap<u64, usize>,
         mut remaining: Decimal,
         price: Decimal,
         taker_id: u64,
         trades: &mut Vec<Trade>,
-    ) -> Result<Decimal> {
-        while remaining > Decimal::ZERO && !level_orders.is_empty() {
-            let maker_order = level_orders.front_mut().unwrap();
+        fillable: &mut F,
+    ) -> Result<Decimal>
+    where
+        F: FnMut(&Order) -> Decimal,
+    {
+        while remaining > Decimal::ZERO {
+            let Some(key) = level.head else {
+                break;
+            };
+            let Some(maker) = orders.get_mut(key) else {
+                return Err(OrderBookError::StaleLevelLink { price });
+            };
+            let maker_order = &mut maker.order;
+
+            let allowed = fillable(maker_order).clamp(Decimal::ZERO, maker_order.quantity);
+            if allowed < maker_order.quantity {
+                level.total_quantity -= maker_order.quantity - allowed;
+                maker_order.quantity = allowed;
+                if allowed.is_zero() {
+                    let maker_id = maker_order.id;
+                    level.unlink(orders, key);
+                    orders.remove(key);
+                    order_index.remove(&maker_id);
+                    continue;
+                }
+            }
             let fill_quantity = remaining.min(maker_order.quantity);
 
             trades.push(Trade {
@@ -184,9 +445,13 @@ impl OrderBook {
                 .quantity
                 .checked_sub(fill_quantity)
                 .ok_or_else(|| OrderBookError::OverflowError("Quantity underflow".to_string()))?;
+            level.total_quantity -= fill_quantity;
 
             if maker_order.quantity == Decimal::ZERO {
-                level_orders.pop_front();
+                let maker_id = maker_order.id;
+                level.unlink(orders, key);
+                orders.remove(key);
+                order_index.remove(&maker_id);
             }
         }
 
@@ -197,18 +462,14 @@ impl OrderBook {
     pub fn best_buy(&self) -> Option<(Decimal, Decimal)> {
         self.buy_levels
             .first_key_value()
-            .map(|(BuyPrice(price), orders)| {
-                let total_quantity: Decimal = orders.iter().map(|o| o.quantity).sum();
-                (*price, total_quantity)
-            })
+            .map(|(BuyPrice(price), level)| (*price, level.total_quantity))
     }
 
     #[inline]
     pub fn best_sell(&self) -> Option<(Decimal, Decimal)> {
-        self.sell_levels.first_key_value().map(|(price, orders)| {
-            let total_quantity: Decimal = orders.iter().map(|o| o.quantity).sum();
-            (*price, total_quantity)
-        })
+        self.sell_levels
+            .first_key_value()
+            .map(|(price, level)| (*price, level.total_quantity))
     }
 
     #[inline]
@@ -230,6 +491,8 @@ impl OrderBook {
     pub fn clear(&mut self) {
         self.buy_levels.clear();
         self.sell_levels.clear();
+        self.orders.clear();
+        self.order_index.clear();
     }
 
     #[inline]
@@ -237,10 +500,7 @@ impl OrderBook {
         self.buy_levels
             .iter()
             .take(limit)
-            .map(|(BuyPrice(price), orders)| {
-                let total_quantity: Decimal = orders.iter().map(|o| o.quantity).sum();
-                (*price, total_quantity)
-            })
+            .map(|(BuyPrice(price), level)| (*price, level.total_quantity))
             .collect()
     }
 
@@ -249,10 +509,7 @@ impl OrderBook {
         self.sell_levels
             .iter()
             .take(limit)
-            .map(|(price, orders)| {
-                let total_quantity: Decimal = orders.iter().map(|o| o.quantity).sum();
-                (*price, total_quantity)
-            })
+            .map(|(price, level)| (*price, level.total_quantity))
             .collect()
     }
 }
@@ -466,4 +723,207 @@ mod tests {
         assert_eq!(trades[0].quantity, dec!(5.125));
         assert_eq!(book.best_buy(), Some((dec!(100.50), dec!(5.125))));
     }
+
+    #[test]
+    fn test_cancel_order() {
+        let mut book = OrderBook::new();
+        book.place_order(Side::Buy, dec!(100), dec!(10), 1).unwrap();
+        book.place_order(Side::Sell, dec!(101), dec!(10), 2)
+            .unwrap();
+
+        let cancelled = book.cancel_order(1).unwrap();
+        assert_eq!(cancelled.id, 1);
+        assert_eq!(cancelled.quantity, dec!(10));
+        assert_eq!(book.best_buy(), None);
+        assert_eq!(book.buy_depth(), 0);
+        assert_eq!(book.order_count(), 1);
+
+        assert_eq!(
+            book.cancel_order(1),
+            Err(OrderBookError::OrderNotFound { id: 1 })
+        );
+    }
+
+    #[test]
+    fn test_cancel_mid_queue_preserves_priority() {
+        let mut book = OrderBook::new();
+        book.place_order(Side::Buy, dec!(100), dec!(10), 1).unwrap();
+        book.place_order(Side::Buy, dec!(100), dec!(10), 2).unwrap();
+        book.place_order(Side::Buy, dec!(100), dec!(10), 3).unwrap();
+
+        book.cancel_order(2).unwrap();
+        assert_eq!(book.best_buy(), Some((dec!(100), dec!(20))));
+
+        let trades = book
+            .place_order(Side::Sell, dec!(100), dec!(15), 4)
+            .unwrap();
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].maker_id, 1);
+        assert_eq!(trades[1].maker_id, 3);
+        assert_eq!(trades[1].quantity, dec!(5));
+        assert_eq!(book.order(3).map(|o| o.quantity), Some(dec!(5)));
+    }
+
+    #[test]
+    fn test_filled_orders_leave_index() {
+        let mut book = OrderBook::new();
+        book.place_order(Side::Sell, dec!(100), dec!(10), 1)
+            .unwrap();
+        book.place_order(Side::Buy, dec!(100), dec!(10), 2).unwrap();
+
+        assert!(book.order(1).is_none());
+        assert_eq!(book.order_count(), 0);
+        assert_eq!(
+            book.cancel_order(1),
+            Err(OrderBookError::OrderNotFound { id: 1 })
+        );
+    }
+
+    #[test]
+    fn test_duplicate_resting_id_rejected() {
+        let mut book = OrderBook::new();
+        book.place_order(Side::Buy, dec!(100), dec!(10), 1).unwrap();
+
+        assert_eq!(
+            book.place_order(Side::Buy, dec!(99), dec!(10), 1),
+            Err(OrderBookError::DuplicateOrderId { id: 1 })
+        );
+    }
+
+    #[test]
+    fn test_capacity_limit() {
+        let mut book = OrderBook::with_capacity(2);
+        book.place_order(Side::Buy, dec!(100), dec!(10), 1).unwrap();
+        book.place_order(Side::Buy, dec!(99), dec!(10), 2).unwrap();
+
+        assert_eq!(
+            book.place_order(Side::Buy, dec!(98), dec!(10), 3),
+            Err(OrderBookError::OrderPoolExhausted { capacity: 2 })
+        );
+
+        let trades = book.place_order(Side::Sell, dec!(100), dec!(5), 4).unwrap();
+        assert_eq!(trades.len(), 1);
+
+        book.cancel_order(2).unwrap();
+        book.place_order(Side::Buy, dec!(98), dec!(10), 3).unwrap();
+        assert_eq!(book.order_count(), 2);
+        assert_eq!(
+            book.buy_levels(5),
+            vec![(dec!(100), dec!(5)), (dec!(98), dec!(10))]
+        );
+    }
+
+    #[test]
+    fn test_full_pool_rests_remainder_after_capped_makers() {
+        let mut book = OrderBook::with_capacity(2);
+        book.place_order(Side::Sell, dec!(100), dec!(5), 1).unwrap();
+        book.place_order(Side::Sell, dec!(101), dec!(5), 2).unwrap();
+
+        // Enough crosses to pass the pool check, but the caps cut the fills to 3
+        let trades = book
+            .place_order_with(Side::Buy, dec!(101), dec!(8), 3, true, |maker: &Order| {
+                if maker.id == 1 {
+                    dec!(3)
+                } else {
+                    Decimal::ZERO
+                }
+            })
+            .unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, dec!(3));
+        assert_eq!(book.order(3).map(|order| order.quantity), Some(dec!(5)));
+        assert_eq!(book.order_count(), 1);
+
+        // On a full pool the marketable part trades first and the remainder takes the slot
+        // its maker freed
+        book.place_order(Side::Buy, dec!(90), dec!(1), 4).unwrap();
+        let trades = book.place_order(Side::Sell, dec!(100), dec!(7), 5).unwrap();
+        assert_eq!((trades[0].maker_id, trades[0].quantity), (3, dec!(5)));
+        assert_eq!(book.order(5).map(|order| order.quantity), Some(dec!(2)));
+        assert_eq!(book.best_buy(), Some((dec!(90), dec!(1))));
+        assert_eq!(book.best_sell(), Some((dec!(100), dec!(2))));
+
+        // Only an order with nothing to trade against is turned away
+        assert_eq!(
+            book.place_order(Side::Sell, dec!(95), dec!(1), 6),
+            Err(OrderBookError::OrderPoolExhausted { capacity: 2 })
+        );
+        assert_eq!(book.order_count(), 2);
+    }
+
+    #[test]
+    fn test_ioc_order_never_rests() {
+        let mut book = OrderBook::with_capacity(1);
+        book.place_order(Side::Buy, dec!(100), dec!(10), 1).unwrap();
+
+        let trades = book
+            .place_ioc_order(Side::Sell, dec!(99), dec!(15), 2)
+            .unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, dec!(10));
+        assert!(book.is_empty());
+        assert!(book.order(2).is_none());
+
+        let trades = book
+            .place_ioc_order(Side::Buy, dec!(101), dec!(5), 3)
+            .unwrap();
+        assert!(trades.is_empty());
+        assert_eq!(book.order_count(), 0);
+    }
+
+    #[test]
+    fn test_reduce_order_keeps_priority() {
+        let mut book = OrderBook::new();
+        book.place_order(Side::Sell, dec!(100), dec!(10), 1)
+            .unwrap();
+        book.place_order(Side::Sell, dec!(100), dec!(10), 2)
+            .unwrap();
+
+        assert_eq!(book.reduce_order(1, dec!(4)).unwrap(), dec!(6));
+        assert_eq!(book.best_sell(), Some((dec!(100), dec!(14))));
+        assert!(book.reduce_order(1, dec!(5)).is_err());
+
+        let trades = book.place_order(Side::Buy, dec!(100), dec!(6), 3).unwrap();
+        assert_eq!(trades[0].maker_id, 1);
+        assert_eq!(trades[0].quantity, dec!(4));
+        assert_eq!(trades[1].maker_id, 2);
+
+        book.reduce_order(2, dec!(0)).unwrap();
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn test_fillable_caps_trim_makers_at_match_time() {
+        let mut book = OrderBook::new();
+        book.place_order(Side::Sell, dec!(100), dec!(10), 1)
+            .unwrap();
+        book.place_order(Side::Sell, dec!(100), dec!(10), 2)
+            .unwrap();
+        book.place_order(Side::Sell, dec!(101), dec!(10), 3)
+            .unwrap();
+
+        let trades = book
+            .place_order_with(
+                Side::Buy,
+                dec!(101),
+                dec!(12),
+                4,
+                true,
+                |maker| match maker.id {
+                    1 => dec!(0),
+                    2 => dec!(5),
+                    _ => maker.quantity,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!((trades[0].maker_id, trades[0].quantity), (2, dec!(5)));
+        assert_eq!((trades[1].maker_id, trades[1].quantity), (3, dec!(7)));
+        assert!(book.order(1).is_none());
+        assert!(book.order(2).is_none());
+        assert_eq!(book.sell_levels(5), vec![(dec!(101), dec!(3))]);
+        assert_eq!(book.order_count(), 1);
+    }
 }
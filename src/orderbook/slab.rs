@@ -0,0 +1,338 @@
+use crate::types::{Order, Side};
+use rust_decimal::Decimal;
+
+#[derive(Debug, Clone)]
+pub struct OrderNode {
+    pub order: Order,
+    pub side: Side,
+    pub price: Decimal,
+    pub prev: Option<usize>,
+    pub next: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+enum Slot {
+    Occupied(OrderNode),
+    Vacant { next_free: Option<usize> },
+}
+
+// Slots preallocated up front; beyond this the slab grows on demand up to its capacity
+const INITIAL_SLOTS: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct OrderSlab {
+    slots: Vec<Slot>,
+    next_free: Option<usize>,
+    len: usize,
+    capacity: usize,
+}
+
+impl OrderSlab {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slots: Vec::with_capacity(capacity.min(INITIAL_SLOTS)),
+            next_free: None,
+            len: 0,
+            capacity,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    #[inline]
+    pub fn insert(&mut self, node: OrderNode) -> Option<usize> {
+        if self.is_full() {
+            return None;
+        }
+
+        let key = match self.next_free {
+            Some(key) => {
+                if let Slot::Vacant { next_free } = self.slots[key] {
+                    self.next_free = next_free;
+                }
+                self.slots[key] = Slot::Occupied(node);
+                key
+            }
+            None => {
+                self.slots.push(Slot::Occupied(node));
+                self.slots.len() - 1
+            }
+        };
+
+        self.len += 1;
+        Some(key)
+    }
+
+    #[inline]
+    pub fn remove(&mut self, key: usize) -> Option<OrderNode> {
+        let slot = self.slots.get_mut(key)?;
+        if matches!(slot, Slot::Vacant { .. }) {
+            return None;
+        }
+
+        let vacant = Slot::Vacant {
+            next_free: self.next_free,
+        };
+        match std::mem::replace(slot, vacant) {
+            Slot::Occupied(node) => {
+                self.next_free = Some(key);
+                self.len -= 1;
+                Some(node)
+            }
+            Slot::Vacant { .. } => None,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, key: usize) -> Option<&OrderNode> {
+        match self.slots.get(key)? {
+            Slot::Occupied(node) => Some(node),
+            Slot::Vacant { .. } => None,
+        }
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut OrderNode> {
+        match self.slots.get_mut(key)? {
+            Slot::Occupied(node) => Some(node),
+            Slot::Vacant { .. } => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.next_free = None;
+        self.len = 0;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PriceLevel {
+    pub head: Option<usize>,
+    pub tail: Option<usize>,
+    pub total_quantity: Decimal,
+    pub order_count: usize,
+}
+
+impl PriceLevel {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    #[inline]
+    pub fn push_back(&mut self, slab: &mut OrderSlab, key: usize) {
+        let Some(node) = slab.get_mut(key) else {
+            return;
+        };
+        node.prev = self.tail;
+        node.next = None;
+        let quantity = node.order.quantity;
+
+        match self.tail {
+            Some(tail) => {
+                if let Some(tail_node) = slab.get_mut(tail) {
+                    tail_node.next = Some(key);
+                }
+            }
+            None => self.head = Some(key),
+        }
+
+        self.tail = Some(key);
+        self.total_quantity += quantity;
+        self.order_count += 1;
+    }
+
+    #[inline]
+    pub fn unlink(&mut self, slab: &mut OrderSlab, key: usize) {
+        let Some(node) = slab.get(key) else {
+            return;
+        };
+        let (prev, next, quantity) = (node.prev, node.next, node.order.quantity);
+
+        match prev {
+            Some(prev) => {
+                if let Some(prev_node) = slab.get_mut(prev) {
+                    prev_node.next = next;
+                }
+            }
+            None => self.head = next,
+        }
+
+        match next {
+            Some(next) => {
+                if let Some(next_node) = slab.get_mut(next) {
+                    next_node.prev = prev;
+                }
+            }
+            None => self.tail = prev,
+        }
+
+        self.total_quantity -= quantity;
+        self.order_count -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn node(id: u64, quantity: Decimal) -> OrderNode {
+        OrderNode {
+            order: Order {
+                id,
+                quantity,
+                timestamp: id,
+            },
+            side: Side::Buy,
+            price: dec!(100),
+            prev: None,
+            next: None,
+        }
+    }
+
+    // Three orders queued at one level, returned with their keys in time priority
+    fn queued_level() -> (OrderSlab, PriceLevel, [usize; 3]) {
+        let mut slab = OrderSlab::with_capacity(8);
+        let mut level = PriceLevel::default();
+        let keys = [1, 2, 3].map(|id| slab.insert(node(id, Decimal::from(id))).unwrap());
+        for key in keys {
+            level.push_back(&mut slab, key);
+        }
+        (slab, level, keys)
+    }
+
+    fn queue_ids(slab: &OrderSlab, level: &PriceLevel) -> Vec<u64> {
+        let mut ids = Vec::new();
+        let mut cursor = level.head;
+        while let Some(key) = cursor {
+            let node = slab.get(key).unwrap();
+            ids.push(node.order.id);
+            cursor = node.next;
+        }
+        ids
+    }
+
+    #[test]
+    fn test_removed_slots_are_reused_most_recent_first() {
+        let mut slab = OrderSlab::with_capacity(8);
+        let keys: Vec<usize> = (1..=3)
+            .map(|id| slab.insert(node(id, dec!(1))).unwrap())
+            .collect();
+        assert_eq!(keys, vec![0, 1, 2]);
+
+        assert_eq!(slab.remove(1).unwrap().order.id, 2);
+        assert!(slab.get(1).is_none());
+        assert!(slab.remove(1).is_none());
+        assert_eq!(slab.len(), 2);
+        assert_eq!(slab.insert(node(4, dec!(1))), Some(1));
+
+        slab.remove(0);
+        slab.remove(2);
+        assert_eq!(slab.insert(node(5, dec!(1))), Some(2));
+        assert_eq!(slab.insert(node(6, dec!(1))), Some(0));
+        // Only once the free list is empty does the slab take a fresh slot
+        assert_eq!(slab.insert(node(7, dec!(1))), Some(3));
+        assert_eq!(slab.len(), 4);
+        assert_eq!(slab.get(0).unwrap().order.id, 6);
+    }
+
+    #[test]
+    fn test_slab_grows_past_initial_slots_up_to_capacity() {
+        let capacity = INITIAL_SLOTS + 16;
+        let mut slab = OrderSlab::with_capacity(capacity);
+        assert!(slab.slots.capacity() >= INITIAL_SLOTS);
+
+        for id in 0..capacity as u64 {
+            assert_eq!(slab.insert(node(id, dec!(1))), Some(id as usize));
+        }
+        assert!(slab.is_full());
+        assert_eq!(slab.len(), capacity);
+        assert!(slab.insert(node(u64::MAX, dec!(1))).is_none());
+        assert_eq!(
+            slab.get(capacity - 1).unwrap().order.id,
+            capacity as u64 - 1
+        );
+
+        // A freed slot makes room again without growing past capacity
+        slab.remove(INITIAL_SLOTS);
+        assert_eq!(slab.insert(node(u64::MAX, dec!(1))), Some(INITIAL_SLOTS));
+        assert_eq!(slab.slots.len(), capacity);
+        assert!(slab.insert(node(u64::MAX, dec!(1))).is_none());
+    }
+
+    #[test]
+    fn test_small_slab_does_not_preallocate_past_capacity() {
+        let mut slab = OrderSlab::with_capacity(2);
+        assert!(slab.slots.capacity() < INITIAL_SLOTS);
+        assert_eq!(slab.insert(node(1, dec!(1))), Some(0));
+        assert_eq!(slab.insert(node(2, dec!(1))), Some(1));
+        assert!(slab.insert(node(3, dec!(1))).is_none());
+
+        slab.clear();
+        assert_eq!(slab.len(), 0);
+        assert_eq!(slab.insert(node(4, dec!(1))), Some(0));
+    }
+
+    #[test]
+    fn test_unlink_head_of_level() {
+        let (mut slab, mut level, [head, middle, tail]) = queued_level();
+        level.unlink(&mut slab, head);
+
+        assert_eq!(level.head, Some(middle));
+        assert_eq!(level.tail, Some(tail));
+        assert_eq!(slab.get(middle).unwrap().prev, None);
+        assert_eq!(queue_ids(&slab, &level), vec![2, 3]);
+        assert_eq!(level.total_quantity, dec!(5));
+        assert_eq!(level.order_count, 2);
+    }
+
+    #[test]
+    fn test_unlink_middle_of_level() {
+        let (mut slab, mut level, [head, middle, tail]) = queued_level();
+        level.unlink(&mut slab, middle);
+
+        assert_eq!(level.head, Some(head));
+        assert_eq!(level.tail, Some(tail));
+        assert_eq!(slab.get(head).unwrap().next, Some(tail));
+        assert_eq!(slab.get(tail).unwrap().prev, Some(head));
+        assert_eq!(queue_ids(&slab, &level), vec![1, 3]);
+        assert_eq!(level.total_quantity, dec!(4));
+        assert_eq!(level.order_count, 2);
+    }
+
+    #[test]
+    fn test_unlink_tail_of_level() {
+        let (mut slab, mut level, [head, middle, tail]) = queued_level();
+        level.unlink(&mut slab, tail);
+
+        assert_eq!(level.head, Some(head));
+        assert_eq!(level.tail, Some(middle));
+        assert_eq!(slab.get(middle).unwrap().next, None);
+        assert_eq!(queue_ids(&slab, &level), vec![1, 2]);
+        assert_eq!(level.total_quantity, dec!(3));
+        assert_eq!(level.order_count, 2);
+
+        // Emptying the level clears both ends
+        level.unlink(&mut slab, middle);
+        level.unlink(&mut slab, head);
+        assert!(level.is_empty());
+        assert_eq!(level.tail, None);
+        assert_eq!(level.total_quantity, dec!(0));
+        assert_eq!(level.order_count, 0);
+    }
+}
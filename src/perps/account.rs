@@ -0,0 +1,263 @@
+use super::risk::RiskLimits;
+use crate::error::{OrderBookError, Result};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerEntryKind {
+    Deposit,
+    Withdrawal,
+    TradingFee,
+    Funding,
+    RealizedPnl,
+    LiquidationPenalty,
+    InsurancePayout,
+    SocializedLoss,
+    ReferralRebate,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LedgerEntry {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub trader_id: u64,
+    pub kind: LedgerEntryKind,
+    pub amount: Decimal,
+    pub balance_after: Decimal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarginMode {
+    Isolated,
+    Cross,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub trader_id: u64,
+    pub balance: Decimal,
+    pub order_margin: Decimal,
+    pub leverage: Decimal,
+    pub margin_mode: MarginMode,
+    pub risk_limits: RiskLimits,
+    pub referrer: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct AccountManager {
+    // Balances only change through `post`, so every change is on the ledger
+    accounts: HashMap<u64, Account>,
+    pub default_leverage: Decimal,
+    pub default_risk_limits: RiskLimits,
+    ledger: Vec<LedgerEntry>,
+    // Positions of each trader's entries in the ledger
+    entries_by_trader: HashMap<u64, Vec<usize>>,
+    timestamp: u64,
+    volumes: HashMap<u64, VecDeque<(u64, Decimal)>>,
+}
+
+impl Default for AccountManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccountManager {
+    pub fn new() -> Self {
+        Self {
+            accounts: HashMap::new(),
+            default_leverage: dec!(10),
+            default_risk_limits: RiskLimits::new(),
+            ledger: Vec::new(),
+            entries_by_trader: HashMap::new(),
+            timestamp: 0,
+            volumes: HashMap::new(),
+        }
+    }
+
+    // Stamps subsequent ledger entries
+    pub fn set_time(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    pub fn account(&self, trader_id: u64) -> Result<&Account> {
+        self.accounts
+            .get(&trader_id)
+            .ok_or(OrderBookError::AccountNotFound { trader_id })
+    }
+
+    pub(crate) fn account_mut(&mut self, trader_id: u64) -> Result<&mut Account> {
+        self.accounts
+            .get_mut(&trader_id)
+            .ok_or(OrderBookError::AccountNotFound { trader_id })
+    }
+
+    pub fn deposit(&mut self, trader_id: u64, amount: Decimal) -> Result<LedgerEntry> {
+        if amount <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidQuantity(
+                "Deposit amount must be positive".to_string(),
+            ));
+        }
+
+        let (default_leverage, default_risk_limits) =
+            (self.default_leverage, self.default_risk_limits);
+        self.accounts.entry(trader_id).or_insert_with(|| Account {
+            trader_id,
+            balance: Decimal::ZERO,
+            order_margin: Decimal::ZERO,
+            leverage: default_leverage,
+            margin_mode: MarginMode::Isolated,
+            risk_limits: default_risk_limits,
+            referrer: None,
+        });
+
+        self.post(trader_id, LedgerEntryKind::Deposit, amount)
+    }
+
+    pub(crate) fn post(
+        &mut self,
+        trader_id: u64,
+        kind: LedgerEntryKind,
+        amount: Decimal,
+    ) -> Result<LedgerEntry> {
+        let account = self
+            .accounts
+            .get_mut(&trader_id)
+            .ok_or(OrderBookError::AccountNotFound { trader_id })?;
+
+        account.balance = account
+            .balance
+            .checked_add(amount)
+            .ok_or_else(|| OrderBookError::OverflowError("Account balance overflow".to_string()))?;
+
+        let entry = LedgerEntry {
+            sequence: self.ledger.len() as u64,
+            timestamp: self.timestamp,
+            trader_id,
+            kind,
+            amount,
+            balance_after: account.balance,
+        };
+        self.entries_by_trader
+            .entry(trader_id)
+            .or_default()
+            .push(self.ledger.len());
+        self.ledger.push(entry.clone());
+        Ok(entry)
+    }
+
+    // Traded notional, kept for `window` seconds behind the latest fill
+    pub fn record_volume(
+        &mut self,
+        trader_id: u64,
+        timestamp: u64,
+        notional: Decimal,
+        window: u64,
+    ) {
+        let fills = self.volumes.entry(trader_id).or_default();
+        fills.push_back((timestamp, notional));
+        let cutoff = timestamp.saturating_sub(window);
+        while fills
+            .front()
+            .is_some_and(|&(filled_at, _)| filled_at < cutoff)
+        {
+            fills.pop_front();
+        }
+    }
+
+    pub fn trailing_volume(&self, trader_id: u64, now: u64, window: u64) -> Decimal {
+        let cutoff = now.saturating_sub(window);
+        self.volumes.get(&trader_id).map_or(Decimal::ZERO, |fills| {
+            fills
+                .iter()
+                .filter(|&&(filled_at, _)| filled_at >= cutoff)
+                .map(|&(_, notional)| notional)
+                .sum()
+        })
+    }
+
+    pub fn ledger(&self) -> &[LedgerEntry] {
+        &self.ledger
+    }
+
+    pub fn entries_for(&self, trader_id: u64) -> impl Iterator<Item = &LedgerEntry> {
+        self.entries_by_trader
+            .get(&trader_id)
+            .into_iter()
+            .flatten()
+            .map(|&index| &self.ledger[index])
+    }
+
+    pub fn ledger_balance(&self, trader_id: u64) -> Decimal {
+        self.entries_for(trader_id).map(|entry| entry.amount).sum()
+    }
+
+    pub fn reconcile(&self, trader_id: u64) -> bool {
+        self.accounts
+            .get(&trader_id)
+            .is_some_and(|account| account.balance == self.ledger_balance(trader_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deposit_opens_account_and_records_entry() {
+        let mut accounts = AccountManager::new();
+        let entry = accounts.deposit(7, dec!(1000)).unwrap();
+
+        assert_eq!(entry.kind, LedgerEntryKind::Deposit);
+        assert_eq!(entry.balance_after, dec!(1000));
+        assert_eq!(accounts.account(7).unwrap().leverage, dec!(10));
+        assert!(accounts.deposit(7, dec!(0)).is_err());
+    }
+
+    #[test]
+    fn test_ledger_reconciles_with_balance() {
+        let mut accounts = AccountManager::new();
+        accounts.deposit(1, dec!(1000)).unwrap();
+        accounts.deposit(2, dec!(50)).unwrap();
+        accounts
+            .post(1, LedgerEntryKind::TradingFee, dec!(-2.5))
+            .unwrap();
+        accounts
+            .post(1, LedgerEntryKind::RealizedPnl, dec!(120.25))
+            .unwrap();
+
+        let sequences: Vec<u64> = accounts.ledger().iter().map(|e| e.sequence).collect();
+        assert_eq!(sequences, vec![0, 1, 2, 3]);
+        let own: Vec<u64> = accounts.entries_for(1).map(|e| e.sequence).collect();
+        assert_eq!(own, vec![0, 2, 3]);
+        assert_eq!(accounts.entries_for(3).count(), 0);
+        assert_eq!(accounts.account(1).unwrap().balance, dec!(1117.75));
+        assert_eq!(accounts.ledger_balance(1), dec!(1117.75));
+        assert!(accounts.reconcile(1));
+        assert!(accounts.reconcile(2));
+        assert!(!accounts.reconcile(3));
+    }
+
+    #[test]
+    fn test_trailing_volume_drops_old_fills() {
+        let mut accounts = AccountManager::new();
+        accounts.record_volume(1, 0, dec!(100), 60);
+        accounts.record_volume(1, 30, dec!(50), 60);
+        assert_eq!(accounts.trailing_volume(1, 30, 60), dec!(150));
+        assert_eq!(accounts.trailing_volume(1, 61, 60), dec!(50));
+
+        accounts.record_volume(1, 100, dec!(10), 60);
+        assert_eq!(accounts.trailing_volume(1, 100, 60), dec!(10));
+        assert_eq!(accounts.trailing_volume(2, 100, 60), dec!(0));
+    }
+
+    #[test]
+    fn test_post_requires_account() {
+        let mut accounts = AccountManager::new();
+        assert_eq!(
+            accounts.post(9, LedgerEntryKind::Funding, dec!(1)),
+            Err(OrderBookError::AccountNotFound { trader_id: 9 })
+        );
+    }
+}
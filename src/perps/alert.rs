@@ -0,0 +1,365 @@
+use super::account::MarginMode;
+use super::clearing::{ClearingHouse, MarketId};
+use crate::error::{OrderBookError, Result};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MarginAlertLevel {
+    Healthy,
+    Warning,
+    MarginCall,
+    Liquidation,
+}
+
+// Thresholds are multiples of the margin ratio the engine liquidates at: 1.5 warns at 150% of it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarginAlertConfig {
+    pub warning: Decimal,
+    pub margin_call: Decimal,
+    pub liquidation: Decimal,
+    pub hysteresis: Decimal,
+}
+
+impl Default for MarginAlertConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MarginAlertConfig {
+    pub fn new() -> Self {
+        Self {
+            warning: dec!(1.5),
+            margin_call: dec!(1.1),
+            liquidation: Decimal::ONE,
+            hysteresis: dec!(0.05),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.liquidation <= Decimal::ZERO
+            || self.margin_call <= self.liquidation
+            || self.warning <= self.margin_call
+        {
+            return Err(OrderBookError::InvalidPrice(
+                "Alert thresholds must satisfy warning > margin call > liquidation > 0".to_string(),
+            ));
+        }
+
+        if self.hysteresis < Decimal::ZERO {
+            return Err(OrderBookError::InvalidPrice(
+                "Alert hysteresis cannot be negative".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn threshold_level(&self, health: Decimal) -> MarginAlertLevel {
+        if health < self.liquidation {
+            MarginAlertLevel::Liquidation
+        } else if health < self.margin_call {
+            MarginAlertLevel::MarginCall
+        } else if health < self.warning {
+            MarginAlertLevel::Warning
+        } else {
+            MarginAlertLevel::Healthy
+        }
+    }
+
+    // Escalates as soon as a threshold is crossed, but only steps back down once health clears
+    // the threshold by `hysteresis`, so a ratio hovering at a boundary doesn't flap
+    pub fn level(&self, health: Decimal, current: MarginAlertLevel) -> MarginAlertLevel {
+        let level = self.threshold_level(health);
+        if level >= current {
+            return level;
+        }
+        self.threshold_level(health - self.hysteresis).min(current)
+    }
+}
+
+// Isolated positions are tracked per market; a cross account is tracked as a whole with no market
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarginAlert {
+    pub trader_id: u64,
+    pub market_id: Option<MarketId>,
+    pub timestamp: u64,
+    pub previous: MarginAlertLevel,
+    pub level: MarginAlertLevel,
+    pub margin_ratio: Decimal,
+    pub liquidation_ratio: Decimal,
+}
+
+impl MarginAlert {
+    pub fn is_escalation(&self) -> bool {
+        self.level > self.previous
+    }
+}
+
+impl ClearingHouse {
+    pub fn margin_alert_config(&self) -> MarginAlertConfig {
+        self.margin_alert_config
+    }
+
+    pub fn set_margin_alert_config(&mut self, config: MarginAlertConfig) -> Result<()> {
+        config.validate()?;
+        self.margin_alert_config = config;
+        Ok(())
+    }
+
+    // Re-grades every isolated position and cross account against the ratio it would be liquidated
+    // at and reports the ones whose level changed; closed positions drop their state silently
+    pub fn check_margin_alerts(&mut self) -> Vec<MarginAlert> {
+        let mut health = Vec::new();
+        let mut traders: Vec<u64> = self
+            .markets
+            .values()
+            .flat_map(|market| market.position_manager.positions.keys().copied())
+            .collect();
+        traders.sort_unstable();
+        traders.dedup();
+
+        for trader_id in traders {
+            match self.margin_mode(trader_id) {
+                MarginMode::Isolated => {
+                    for market in self.markets.values() {
+                        let Some(position) = market.position(trader_id) else {
+                            continue;
+                        };
+                        let mark_price = market.valuation_price(position);
+                        let Ok(ratio) = self
+                            .liquidation_engine
+                            .calculate_margin_ratio(position, mark_price)
+                        else {
+                            continue;
+                        };
+                        let liquidation_ratio =
+                            self.liquidation_engine.liquidation_margin_ratio(position);
+                        health.push(((trader_id, Some(market.id)), ratio, liquidation_ratio));
+                    }
+                }
+                MarginMode::Cross => {
+                    let Ok(ratio) = self.account_margin_ratio(trader_id) else {
+                        continue;
+                    };
+                    let liquidation_ratio = self
+                        .liquidation_engine
+                        .account_maintenance_rate(&self.positions_for(trader_id));
+                    health.push(((trader_id, None), ratio, liquidation_ratio));
+                }
+            }
+        }
+
+        let tracked: HashSet<(u64, Option<MarketId>)> =
+            health.iter().map(|&(key, _, _)| key).collect();
+        self.margin_alert_levels
+            .retain(|key, _| tracked.contains(key));
+
+        let mut alerts = Vec::new();
+        for ((trader_id, market_id), margin_ratio, liquidation_ratio) in health {
+            let previous = self
+                .margin_alert_levels
+                .get(&(trader_id, market_id))
+                .copied()
+                .unwrap_or(MarginAlertLevel::Healthy);
+            let level = self
+                .margin_alert_config
+                .level(margin_ratio / liquidation_ratio, previous);
+            if level == previous {
+                continue;
+            }
+
+            self.margin_alert_levels
+                .insert((trader_id, market_id), level);
+            alerts.push(MarginAlert {
+                trader_id,
+                market_id,
+                timestamp: self.timestamp,
+                previous,
+                level,
+                margin_ratio,
+                liquidation_ratio,
+            });
+        }
+
+        self.margin_alerts.extend_from_slice(&alerts);
+        alerts
+    }
+
+    pub fn margin_alerts(&self) -> &[MarginAlert] {
+        &self.margin_alerts
+    }
+
+    // Cross accounts are graded as a whole, so their level is keyed without a market
+    pub fn margin_alert_level(
+        &self,
+        trader_id: u64,
+        market_id: Option<MarketId>,
+    ) -> MarginAlertLevel {
+        self.margin_alert_levels
+            .get(&(trader_id, market_id))
+            .copied()
+            .unwrap_or(MarginAlertLevel::Healthy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perps::clearing::fixtures::{funded, MARKET};
+    use crate::types::Side;
+
+    #[test]
+    fn test_levels_escalate_immediately_and_recover_with_hysteresis() {
+        let config = MarginAlertConfig::new();
+        assert!(config.validate().is_ok());
+
+        let mut level = MarginAlertLevel::Healthy;
+        let mut transitions = Vec::new();
+        for health in [
+            dec!(1.6),
+            dec!(1.49),
+            dec!(1.51),
+            dec!(1.09),
+            dec!(1.12),
+            dec!(0.99),
+        ] {
+            let next = config.level(health, level);
+            if next != level {
+                transitions.push(next);
+                level = next;
+            }
+        }
+        assert_eq!(
+            transitions,
+            vec![
+                MarginAlertLevel::Warning,
+                MarginAlertLevel::MarginCall,
+                MarginAlertLevel::Liquidation
+            ]
+        );
+
+        // Recovery past several thresholds at once still needs the margin on the last one
+        assert_eq!(
+            config.level(dec!(1.52), MarginAlertLevel::Liquidation),
+            MarginAlertLevel::Warning
+        );
+        assert_eq!(
+            config.level(dec!(1.55), MarginAlertLevel::Liquidation),
+            MarginAlertLevel::Healthy
+        );
+
+        assert!(MarginAlertConfig {
+            margin_call: dec!(1.6),
+            ..config
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_margin_alerts_grade_positions_with_hysteresis() {
+        let mut clearing = funded(&[(1, dec!(10000)), (2, dec!(10000)), (3, dec!(100000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+
+        // Margin 1006 on a 10 @ 1000 long liquidates at a 0.8% ratio, around 906.65
+        let mut levels = Vec::new();
+        for mark_price in [
+            dec!(950),
+            dec!(910),
+            dec!(910.5),
+            dec!(911),
+            dec!(907),
+            dec!(906),
+        ] {
+            clearing.update_mark_price(MARKET, mark_price).unwrap();
+            for alert in clearing.check_margin_alerts() {
+                assert_eq!(alert.market_id, Some(MARKET));
+                levels.push((alert.trader_id, alert.level));
+            }
+        }
+        assert_eq!(
+            levels,
+            vec![
+                (1, MarginAlertLevel::Warning),
+                (1, MarginAlertLevel::Healthy),
+                (1, MarginAlertLevel::MarginCall),
+                (1, MarginAlertLevel::Liquidation),
+            ]
+        );
+        assert!(clearing.margin_alerts()[2].is_escalation());
+        assert!(clearing.is_liquidatable(1, MARKET));
+
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(905), dec!(10))
+            .unwrap();
+        // Partial liquidation restores the rest of the position, which steps back down
+        clearing.run_liquidations().unwrap();
+        let alerts = clearing.check_margin_alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].previous, MarginAlertLevel::Liquidation);
+        assert!(!alerts[0].is_escalation());
+        assert!(clearing
+            .set_margin_alert_config(MarginAlertConfig {
+                hysteresis: dec!(-0.1),
+                ..MarginAlertConfig::new()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_cross_account_alerts_once_and_closed_positions_drop_state() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(2000))]);
+        clearing.set_margin_mode(2, MarginMode::Cross).unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(2000), dec!(4))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(2000), dec!(4))
+            .unwrap();
+
+        let mut mark_price = dec!(2000);
+        let alerts = loop {
+            mark_price -= dec!(1);
+            clearing.update_mark_price(MARKET, mark_price).unwrap();
+            let alerts = clearing.check_margin_alerts();
+            if !alerts.is_empty() {
+                break alerts;
+            }
+        };
+        // Only the losing cross account is graded, as a whole rather than per market
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].trader_id, alerts[0].market_id), (2, None));
+        assert_eq!(alerts[0].level, MarginAlertLevel::Warning);
+        assert_eq!(
+            clearing.margin_alert_level(2, None),
+            MarginAlertLevel::Warning
+        );
+        assert_eq!(
+            clearing.margin_alert_level(2, Some(MARKET)),
+            MarginAlertLevel::Healthy
+        );
+
+        // Closing the position forgets the level without reporting a recovery
+        clearing.deposit(2, dec!(2000)).unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Buy, mark_price, dec!(4))
+            .unwrap();
+        clearing
+            .submit_reduce_only_order(2, MARKET, Side::Sell, mark_price, dec!(4))
+            .unwrap();
+        assert!(clearing.check_margin_alerts().is_empty());
+        assert_eq!(
+            clearing.margin_alert_level(2, None),
+            MarginAlertLevel::Healthy
+        );
+        assert_eq!(clearing.margin_alerts().len(), 1);
+    }
+}
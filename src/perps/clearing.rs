@@ -0,0 +1,3012 @@
+use super::account::{AccountManager, LedgerEntry, LedgerEntryKind, MarginMode};
+use super::alert::{MarginAlert, MarginAlertConfig, MarginAlertLevel};
+use super::expiry::ContractKind;
+use super::oracle::OracleStatus;
+use super::risk::{OrderExposure, RiskLimits};
+use super::trigger::ArmedTrigger;
+use super::{
+    FeeRates, FeeStructure, InsuranceFund, LiquidationEngine, OraclePrice, Position,
+    PositionManager, PositionSide, SafeModeLiquidations, SocializedLoss,
+};
+use crate::error::{OrderBookError, Result};
+use crate::funding::FundingRate;
+use crate::orderbook::OrderBook;
+use crate::types::{Side, Trade};
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub type MarketId = u32;
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct RestingOrder {
+    pub(super) trader_id: u64,
+    pub(super) side: Side,
+    pub(super) price: Decimal,
+    margin_per_unit: Decimal,
+    reduce_only: bool,
+}
+
+// Resting orders cut back by reduce-only checks, with the quantity taken off each
+type TrimmedOrders = Vec<(RestingOrder, Decimal)>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    pub trade: Trade,
+    pub maker_trader_id: u64,
+    pub taker_trader_id: u64,
+    maker_fee: Decimal,
+    taker_fee: Decimal,
+}
+
+impl Fill {
+    pub fn maker_fee(&self) -> Decimal {
+        self.maker_fee
+    }
+
+    pub fn taker_fee(&self) -> Decimal {
+        self.taker_fee
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderOutcome {
+    pub order_id: u64,
+    pub fills: Vec<Fill>,
+    pub resting_quantity: Decimal,
+    // Book orders with no owner on record, taken off the book instead of filled
+    pub untracked_makers: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdlFill {
+    pub trader_id: u64,
+    pub quantity: Decimal,
+    pub price: Decimal,
+    pub realized_pnl: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdlRank {
+    pub trader_id: u64,
+    pub side: PositionSide,
+    pub score: Decimal,
+    pub quantile: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafeModeEventKind {
+    Entered(OracleStatus),
+    Exited,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SafeModeEvent {
+    pub market_id: MarketId,
+    pub timestamp: u64,
+    pub kind: SafeModeEventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiquidationOutcome {
+    pub trader_id: u64,
+    pub market_id: MarketId,
+    pub side: PositionSide,
+    pub quantity: Decimal,
+    pub remaining_size: Decimal,
+    pub fills: Vec<Fill>,
+    pub deleveraged: Vec<AdlFill>,
+    pub penalty: Decimal,
+    pub insurance_payout: Decimal,
+    pub socialized_loss: Decimal,
+    pub uncovered_loss: Decimal,
+}
+
+pub struct Market {
+    pub id: MarketId,
+    pub contract: ContractKind,
+    pub(crate) order_book: OrderBook,
+    pub position_manager: PositionManager,
+    pub funding: FundingRate,
+    pub mark_price: Option<Decimal>,
+    pub last_trade_price: Option<Decimal>,
+    pub safe_mode: Option<OracleStatus>,
+    pub settlement_price: Option<Decimal>,
+    // None accepts any price
+    tick_size: Option<Decimal>,
+    // Halted markets reject new orders and hold their triggers until trading resumes
+    pub halted: bool,
+    pub(super) resting_orders: HashMap<u64, RestingOrder>,
+    // Each trader's resting order ids, oldest first, so pre-trade checks only visit their own
+    orders_by_trader: HashMap<u64, BTreeSet<u64>>,
+    pub(super) triggers: BTreeMap<u64, ArmedTrigger>,
+}
+
+impl Market {
+    pub fn new(id: MarketId) -> Self {
+        Self::with_components(id, OrderBook::new(), PositionManager::new())
+    }
+
+    pub fn dated(id: MarketId, expiry: u64, settlement_periods: usize) -> Self {
+        Self {
+            contract: ContractKind::Dated {
+                expiry,
+                settlement_periods,
+            },
+            ..Self::new(id)
+        }
+    }
+
+    pub fn with_components(
+        id: MarketId,
+        order_book: OrderBook,
+        position_manager: PositionManager,
+    ) -> Self {
+        Self {
+            id,
+            contract: ContractKind::Perpetual,
+            order_book,
+            position_manager,
+            funding: FundingRate::new(),
+            mark_price: None,
+            last_trade_price: None,
+            safe_mode: None,
+            settlement_price: None,
+            tick_size: None,
+            halted: false,
+            resting_orders: HashMap::new(),
+            orders_by_trader: HashMap::new(),
+            triggers: BTreeMap::new(),
+        }
+    }
+
+    // Keeps the funding imbalance on the same open interest the positions hold, so the
+    // predicted rate moves with every fill rather than only at settlement
+    fn sync_open_interest(&mut self) {
+        let manager = &self.position_manager;
+        self.funding
+            .update_open_interest(manager.total_long_interest, manager.total_short_interest);
+    }
+
+    pub fn order_book(&self) -> &OrderBook {
+        &self.order_book
+    }
+
+    pub fn position(&self, trader_id: u64) -> Option<&Position> {
+        self.position_manager.positions.get(&trader_id)
+    }
+
+    pub fn valuation_price(&self, position: &Position) -> Decimal {
+        self.mark_price.unwrap_or(position.entry_price)
+    }
+
+    pub fn tick_size(&self) -> Option<Decimal> {
+        self.tick_size
+    }
+
+    pub fn set_tick_size(&mut self, tick_size: Option<Decimal>) -> Result<()> {
+        if tick_size.is_some_and(|tick_size| tick_size <= Decimal::ZERO) {
+            return Err(OrderBookError::InvalidPrice(
+                "Tick size must be positive".to_string(),
+            ));
+        }
+
+        self.tick_size = tick_size;
+        Ok(())
+    }
+
+    pub fn in_safe_mode(&self) -> bool {
+        self.safe_mode.is_some()
+    }
+
+    fn safe_mode_error(&self) -> OrderBookError {
+        match self.safe_mode {
+            Some(OracleStatus::Stale) => OrderBookError::StaleOracle { market_id: self.id },
+            _ => OrderBookError::OracleSafeMode { market_id: self.id },
+        }
+    }
+
+    fn check_tick(&self, price: Decimal) -> Result<()> {
+        match self.tick_size {
+            Some(tick_size) if !(price % tick_size).is_zero() => {
+                Err(OrderBookError::OffTick { price, tick_size })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn track_order(&mut self, order_id: u64, resting: RestingOrder) {
+        self.resting_orders.insert(order_id, resting);
+        self.orders_by_trader
+            .entry(resting.trader_id)
+            .or_default()
+            .insert(order_id);
+    }
+
+    pub(super) fn untrack_order(&mut self, order_id: u64) -> Option<RestingOrder> {
+        let resting = self.resting_orders.remove(&order_id)?;
+        if let Some(order_ids) = self.orders_by_trader.get_mut(&resting.trader_id) {
+            order_ids.remove(&order_id);
+            if order_ids.is_empty() {
+                self.orders_by_trader.remove(&resting.trader_id);
+            }
+        }
+        Some(resting)
+    }
+
+    // The trader's resting orders, oldest first
+    pub(super) fn orders_for(&self, trader_id: u64) -> impl Iterator<Item = (u64, &RestingOrder)> {
+        self.orders_by_trader
+            .get(&trader_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|order_id| Some((*order_id, self.resting_orders.get(order_id)?)))
+    }
+
+    // The trader's own opposite orders that an incoming order at `price` reaches, oldest first
+    pub(super) fn crossing_orders(&self, trader_id: u64, side: Side, price: Decimal) -> Vec<u64> {
+        self.orders_for(trader_id)
+            .filter(|(_, resting)| resting.side != side)
+            .filter(|(_, resting)| match side {
+                Side::Buy => resting.price <= price,
+                Side::Sell => resting.price >= price,
+            })
+            .map(|(order_id, _)| order_id)
+            .collect()
+    }
+
+    fn self_trade(&self, trader_id: u64, side: Side, price: Decimal) -> Option<u64> {
+        self.crossing_orders(trader_id, side, price)
+            .first()
+            .copied()
+    }
+
+    // Quantity, notional and reserved margin of the trader's resting orders that add to `side`
+    fn resting_exposure(&self, trader_id: u64, side: Side) -> (Decimal, Decimal, Decimal) {
+        self.orders_for(trader_id)
+            .filter(|(_, resting)| resting.side == side && !resting.reduce_only)
+            .filter_map(|(order_id, resting)| {
+                let quantity = self.order_book.order(order_id)?.quantity;
+                Some((quantity, resting))
+            })
+            .fold(
+                (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO),
+                |(quantity, notional, margin), (resting_quantity, resting)| {
+                    (
+                        quantity + resting_quantity,
+                        notional + resting.price * resting_quantity,
+                        margin + resting.margin_per_unit * resting_quantity,
+                    )
+                },
+            )
+    }
+
+    // Matches against the book, capping each reduce-only maker at whatever still reduces its
+    // owner's position once the fills ahead of it in this match are counted. Returns the trades,
+    // the makers that were trimmed along the way and the untracked makers that were cancelled.
+    fn match_order(
+        &mut self,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+        order_id: u64,
+        rest: bool,
+    ) -> Result<(Vec<Trade>, TrimmedOrders, Vec<u64>)> {
+        let Market {
+            order_book,
+            position_manager,
+            resting_orders,
+            ..
+        } = self;
+
+        let mut exposure: HashMap<u64, Decimal> = HashMap::new();
+        let mut trimmed = Vec::new();
+        let mut untracked = Vec::new();
+        let trades =
+            order_book.place_order_with(side, price, quantity, order_id, rest, |maker| {
+                // An untracked maker has nothing to settle against; it is cancelled rather than
+                // filled so every trade that leaves the book can be settled
+                let Some(resting) = resting_orders.get(&maker.id) else {
+                    untracked.push(maker.id);
+                    return Decimal::ZERO;
+                };
+                let net = exposure.entry(resting.trader_id).or_insert_with(|| {
+                    position_manager
+                        .positions
+                        .get(&resting.trader_id)
+                        .map_or(Decimal::ZERO, |position| {
+                            signed_quantity(position.side, position.size)
+                        })
+                });
+
+                let direction = signed_quantity(resting.side.into(), Decimal::ONE);
+                let allowed = if resting.reduce_only {
+                    maker.quantity.min((-*net * direction).max(Decimal::ZERO))
+                } else {
+                    maker.quantity
+                };
+                *net += direction * allowed;
+                if allowed < maker.quantity {
+                    trimmed.push((
+                        maker.id,
+                        *resting,
+                        maker.quantity - allowed,
+                        allowed.is_zero(),
+                    ));
+                }
+                allowed
+            })?;
+
+        let mut released = Vec::with_capacity(trimmed.len());
+        for (maker_id, resting, quantity, cancelled) in trimmed {
+            // Partly trimmed makers may still fill below and are cleaned up at settlement
+            if cancelled {
+                self.untrack_order(maker_id);
+            }
+            released.push((resting, quantity));
+        }
+        Ok((trades, released, untracked))
+    }
+
+    // Shrinks the trader's resting reduce-only orders so together they never exceed the
+    // position, oldest first; orders that would now add to or flip it are cancelled
+    fn resize_reduce_only(&mut self, trader_id: u64) -> Result<TrimmedOrders> {
+        let position = self
+            .position(trader_id)
+            .map(|position| (position.side, position.size));
+        let order_ids: Vec<u64> = self
+            .orders_for(trader_id)
+            .filter(|(_, resting)| resting.reduce_only)
+            .map(|(order_id, _)| order_id)
+            .collect();
+
+        let mut reducible = position.map_or(Decimal::ZERO, |(_, size)| size);
+        let mut trimmed = Vec::new();
+        for order_id in order_ids {
+            let resting = self.resting_orders[&order_id];
+            let Some(quantity) = self.order_book.order(order_id).map(|order| order.quantity) else {
+                continue;
+            };
+
+            let reduces = position
+                .is_some_and(|(side, _)| side == PositionSide::from(resting.side).opposite());
+            let allowed = if reduces {
+                quantity.min(reducible)
+            } else {
+                Decimal::ZERO
+            };
+            reducible -= allowed;
+            if allowed == quantity {
+                continue;
+            }
+
+            let removed = self.order_book.reduce_order(order_id, allowed)?;
+            if allowed.is_zero() {
+                self.untrack_order(order_id);
+            }
+            trimmed.push((resting, removed));
+        }
+        Ok(trimmed)
+    }
+}
+
+fn signed_quantity(side: PositionSide, quantity: Decimal) -> Decimal {
+    match side {
+        PositionSide::Long => quantity,
+        PositionSide::Short => -quantity,
+    }
+}
+
+pub struct ClearingHouse {
+    pub markets: BTreeMap<MarketId, Market>,
+    pub liquidation_engine: LiquidationEngine,
+    pub fee_structure: FeeStructure,
+    pub(crate) accounts: AccountManager,
+    pub insurance_fund: InsuranceFund,
+    pub fees_collected: Decimal,
+    pub treasury: Decimal,
+    pub timestamp: u64,
+    safe_mode_events: Vec<SafeModeEvent>,
+    pub(super) margin_alert_config: MarginAlertConfig,
+    pub(super) margin_alert_levels: HashMap<(u64, Option<MarketId>), MarginAlertLevel>,
+    pub(super) margin_alerts: Vec<MarginAlert>,
+    next_order_id: u64,
+    pub(super) next_trigger_id: u64,
+}
+
+impl Default for ClearingHouse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClearingHouse {
+    pub fn new() -> Self {
+        Self::with_components(LiquidationEngine::new(), FeeStructure::new())
+    }
+
+    pub fn with_components(
+        liquidation_engine: LiquidationEngine,
+        fee_structure: FeeStructure,
+    ) -> Self {
+        Self {
+            markets: BTreeMap::new(),
+            insurance_fund: InsuranceFund::new(liquidation_engine.insurance_fund),
+            liquidation_engine,
+            fee_structure,
+            accounts: AccountManager::new(),
+            fees_collected: Decimal::ZERO,
+            treasury: Decimal::ZERO,
+            timestamp: 0,
+            safe_mode_events: Vec::new(),
+            margin_alert_config: MarginAlertConfig::new(),
+            margin_alert_levels: HashMap::new(),
+            margin_alerts: Vec::new(),
+            next_order_id: 1,
+            next_trigger_id: 1,
+        }
+    }
+
+    pub fn add_market(&mut self, market: Market) -> Result<()> {
+        if self.markets.contains_key(&market.id) {
+            return Err(OrderBookError::MarketExists {
+                market_id: market.id,
+            });
+        }
+
+        self.markets.insert(market.id, market);
+        Ok(())
+    }
+
+    pub fn market(&self, market_id: MarketId) -> Result<&Market> {
+        self.markets
+            .get(&market_id)
+            .ok_or(OrderBookError::MarketNotFound { market_id })
+    }
+
+    pub fn market_mut(&mut self, market_id: MarketId) -> Result<&mut Market> {
+        self.markets
+            .get_mut(&market_id)
+            .ok_or(OrderBookError::MarketNotFound { market_id })
+    }
+
+    pub fn advance_time(&mut self, timestamp: u64) {
+        self.timestamp = self.timestamp.max(timestamp);
+        self.accounts.set_time(self.timestamp);
+    }
+
+    // Enters safe mode while the index is stale or low-confidence: funding stops accruing,
+    // liquidations pause or fall back to the book mid, and only reducing orders are accepted
+    pub fn check_oracle(
+        &mut self,
+        market_id: MarketId,
+        oracle: &OraclePrice,
+    ) -> Result<Option<SafeModeEvent>> {
+        let status = oracle.status(self.timestamp);
+        let market = self.market_mut(market_id)?;
+        let kind = match (market.safe_mode, status) {
+            (None, OracleStatus::Healthy) => return Ok(None),
+            (Some(_), OracleStatus::Healthy) => SafeModeEventKind::Exited,
+            (Some(current), status) if current == status => return Ok(None),
+            (_, status) => SafeModeEventKind::Entered(status),
+        };
+        market.safe_mode = match kind {
+            SafeModeEventKind::Entered(status) => Some(status),
+            SafeModeEventKind::Exited => None,
+        };
+
+        let event = SafeModeEvent {
+            market_id,
+            timestamp: self.timestamp,
+            kind,
+        };
+        self.safe_mode_events.push(event);
+        Ok(Some(event))
+    }
+
+    pub fn accounts(&self) -> &AccountManager {
+        &self.accounts
+    }
+
+    pub fn safe_mode_events(&self) -> &[SafeModeEvent] {
+        &self.safe_mode_events
+    }
+
+    pub fn update_mark_price(&mut self, market_id: MarketId, mark_price: Decimal) -> Result<()> {
+        if mark_price <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidPrice(
+                "Mark price must be positive".to_string(),
+            ));
+        }
+
+        self.market_mut(market_id)?.mark_price = Some(mark_price);
+        Ok(())
+    }
+
+    pub fn deposit(&mut self, trader_id: u64, amount: Decimal) -> Result<LedgerEntry> {
+        self.accounts.deposit(trader_id, amount)
+    }
+
+    pub fn withdraw(&mut self, trader_id: u64, amount: Decimal) -> Result<LedgerEntry> {
+        if amount <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidQuantity(
+                "Withdrawal amount must be positive".to_string(),
+            ));
+        }
+
+        self.settle_account_funding(trader_id)?;
+        let available = self.max_withdrawable(trader_id)?;
+        if amount > available {
+            return Err(OrderBookError::InsufficientBalance {
+                trader_id,
+                requested: amount,
+                available,
+            });
+        }
+
+        self.accounts
+            .post(trader_id, LedgerEntryKind::Withdrawal, -amount)
+    }
+
+    pub fn set_leverage(&mut self, trader_id: u64, leverage: Decimal) -> Result<()> {
+        let max_leverage = self
+            .markets
+            .values()
+            .map(|market| market.position_manager.max_leverage)
+            .fold(
+                Decimal::ONE / self.liquidation_engine.initial_margin,
+                Decimal::min,
+            );
+        if leverage <= Decimal::ZERO || leverage > max_leverage {
+            return Err(OrderBookError::InvalidLeverage(leverage));
+        }
+
+        self.accounts.account_mut(trader_id)?.leverage = leverage;
+        Ok(())
+    }
+
+    pub fn set_risk_limits(&mut self, trader_id: u64, risk_limits: RiskLimits) -> Result<()> {
+        risk_limits.validate()?;
+        self.accounts.account_mut(trader_id)?.risk_limits = risk_limits;
+        Ok(())
+    }
+
+    pub fn open_order_count(&self, trader_id: u64) -> usize {
+        self.markets
+            .values()
+            .map(|market| market.orders_for(trader_id).count())
+            .sum()
+    }
+
+    pub fn set_referrer(&mut self, trader_id: u64, referrer_id: u64) -> Result<()> {
+        self.accounts.account(referrer_id)?;
+        if referrer_id == trader_id {
+            return Err(OrderBookError::InvalidQuantity(
+                "An account cannot refer itself".to_string(),
+            ));
+        }
+
+        self.accounts.account_mut(trader_id)?.referrer = Some(referrer_id);
+        Ok(())
+    }
+
+    pub fn fee_rates(&self, trader_id: u64) -> FeeRates {
+        let volume = self.accounts.trailing_volume(
+            trader_id,
+            self.timestamp,
+            self.fee_structure.volume_window,
+        );
+        self.fee_structure.rates_for(trader_id, volume)
+    }
+
+    pub fn set_margin_mode(&mut self, trader_id: u64, margin_mode: MarginMode) -> Result<()> {
+        let has_positions = !self.positions_for(trader_id).is_empty();
+        let account = self.accounts.account_mut(trader_id)?;
+        if account.margin_mode == margin_mode {
+            return Ok(());
+        }
+
+        if has_positions || account.order_margin > Decimal::ZERO {
+            return Err(OrderBookError::MarginModeLocked { trader_id });
+        }
+
+        account.margin_mode = margin_mode;
+        Ok(())
+    }
+
+    pub fn positions_for(&self, trader_id: u64) -> Vec<(&Position, Decimal)> {
+        self.markets
+            .values()
+            .filter_map(|market| {
+                market
+                    .position(trader_id)
+                    .map(|position| (position, market.valuation_price(position)))
+            })
+            .collect()
+    }
+
+    pub fn available_balance(&self, trader_id: u64) -> Result<Decimal> {
+        let account = self.accounts.account(trader_id)?;
+        let positions = self.positions_for(trader_id);
+        let position_margin: Decimal = positions.iter().map(|(p, _)| p.margin).sum();
+
+        // Cross accounts can put unrealized profit to work; isolated ones cannot
+        let unrealized_pnl = match account.margin_mode {
+            MarginMode::Isolated => Decimal::ZERO,
+            MarginMode::Cross => positions
+                .iter()
+                .map(|&(position, mark)| LiquidationEngine::calculate_pnl(position, mark))
+                .sum(),
+        };
+
+        Ok(account.balance + unrealized_pnl - account.order_margin - position_margin)
+    }
+
+    pub fn max_withdrawable(&self, trader_id: u64) -> Result<Decimal> {
+        let account = self.accounts.account(trader_id)?;
+        let free = self.available_balance(trader_id)?;
+
+        let mut unrealized_pnl = Decimal::ZERO;
+        let mut initial_margin = Decimal::ZERO;
+        for (position, mark) in self.positions_for(trader_id) {
+            unrealized_pnl += LiquidationEngine::calculate_pnl(position, mark);
+            let notional = mark * position.size;
+            initial_margin += notional * self.liquidation_engine.initial_margin_rate(notional);
+        }
+        let excess_equity = account.balance + self.pending_funding(trader_id) + unrealized_pnl
+            - initial_margin
+            - account.order_margin;
+
+        Ok(free.min(excess_equity).max(Decimal::ZERO))
+    }
+
+    pub fn account_margin_ratio(&self, trader_id: u64) -> Result<Decimal> {
+        let account = self.accounts.account(trader_id)?;
+        let positions = self.positions_for(trader_id);
+        if positions.is_empty() {
+            return Err(OrderBookError::PositionNotFound { trader_id });
+        }
+
+        self.liquidation_engine.calculate_account_margin_ratio(
+            account.balance + self.pending_funding(trader_id),
+            &positions,
+        )
+    }
+
+    pub fn liquidation_candidates(&self) -> Vec<(u64, MarketId)> {
+        let mut candidates: Vec<(u64, MarketId)> = self
+            .markets
+            .values()
+            .flat_map(|market| {
+                market
+                    .position_manager
+                    .positions
+                    .keys()
+                    .map(move |&trader_id| (trader_id, market.id))
+            })
+            .filter(|&(trader_id, market_id)| self.is_liquidatable(trader_id, market_id))
+            .collect();
+
+        candidates.sort_unstable();
+        candidates
+    }
+
+    pub fn is_liquidatable(&self, trader_id: u64, market_id: MarketId) -> bool {
+        let Some(market) = self.markets.get(&market_id) else {
+            return false;
+        };
+        let Some(position) = market.position(trader_id) else {
+            return false;
+        };
+
+        match self.margin_mode(trader_id) {
+            MarginMode::Isolated => self
+                .liquidation_engine
+                .should_liquidate(position, market.valuation_price(position)),
+            MarginMode::Cross => self
+                .account_margin_ratio(trader_id)
+                .map(|ratio| {
+                    self.liquidation_engine
+                        .should_liquidate_account(ratio, &self.positions_for(trader_id))
+                })
+                .unwrap_or(false),
+        }
+    }
+
+    pub fn run_liquidations(&mut self) -> Result<Vec<LiquidationOutcome>> {
+        let market_ids: Vec<MarketId> = self.markets.keys().copied().collect();
+        for market_id in market_ids {
+            self.settle_market_funding(market_id)?;
+            self.apply_fallback_mark(market_id)?;
+        }
+        self.check_margin_alerts();
+
+        let mut outcomes = Vec::new();
+        for (trader_id, market_id) in self.liquidation_candidates() {
+            // Expired markets close out at settlement instead of through the book
+            if self.liquidations_paused(market_id) || self.expired(market_id) {
+                continue;
+            }
+            // An earlier partial liquidation may already have restored a cross account
+            if self.is_liquidatable(trader_id, market_id) {
+                outcomes.push(self.liquidate(trader_id, market_id)?);
+            }
+        }
+        Ok(outcomes)
+    }
+
+    pub fn liquidate(&mut self, trader_id: u64, market_id: MarketId) -> Result<LiquidationOutcome> {
+        if self.liquidations_paused(market_id) {
+            return Err(self.market(market_id)?.safe_mode_error());
+        }
+        if self.expired(market_id) {
+            return Err(OrderBookError::MarketExpired { market_id });
+        }
+        self.apply_fallback_mark(market_id)?;
+
+        // Counterparties may be deleveraged or socialized, so the whole market settles first
+        self.settle_market_funding(market_id)?;
+        self.settle_account_funding(trader_id)?;
+        if !self.is_liquidatable(trader_id, market_id) {
+            return Err(OrderBookError::NotLiquidatable {
+                trader_id,
+                market_id,
+            });
+        }
+
+        let open_orders: Vec<u64> = self
+            .market(market_id)?
+            .orders_for(trader_id)
+            .map(|(order_id, _)| order_id)
+            .collect();
+        for order_id in open_orders {
+            self.cancel_order(trader_id, market_id, order_id)?;
+        }
+        self.market_mut(market_id)?.cancel_triggers(trader_id);
+
+        let market = self.market(market_id)?;
+        let position = market
+            .position(trader_id)
+            .cloned()
+            .ok_or(OrderBookError::PositionNotFound { trader_id })?;
+        let (fills, deleveraged) = if self.requires_adl(trader_id, &position, market)? {
+            (Vec::new(), self.auto_deleverage(market_id, &position)?)
+        } else {
+            (
+                self.place_liquidation_order(market_id, &position)?,
+                Vec::new(),
+            )
+        };
+
+        let executions = fills
+            .iter()
+            .map(|fill| (fill.trade.price, fill.trade.quantity))
+            .chain(deleveraged.iter().map(|adl| (adl.price, adl.quantity)));
+        let mut realized_pnl = Decimal::ZERO;
+        let mut filled = Decimal::ZERO;
+        let mut notional = Decimal::ZERO;
+        for (price, quantity) in executions {
+            realized_pnl += match position.side {
+                PositionSide::Long => (price - position.entry_price) * quantity,
+                PositionSide::Short => (position.entry_price - price) * quantity,
+            };
+            filled += quantity;
+            notional += price * quantity;
+        }
+
+        let margin_mode = self.margin_mode(trader_id);
+        // The penalty can only take what the closed margin (or the cross balance) still holds
+        let equity = match margin_mode {
+            MarginMode::Isolated => position.margin + realized_pnl,
+            MarginMode::Cross => self.accounts.account(trader_id)?.balance,
+        };
+        let penalty = (notional * self.fee_structure.liquidation_fee)
+            .min(equity)
+            .max(Decimal::ZERO);
+        if !penalty.is_zero() {
+            self.accounts
+                .post(trader_id, LedgerEntryKind::LiquidationPenalty, -penalty)?;
+            self.insurance_fund
+                .add_contribution(penalty, self.timestamp)?;
+        }
+
+        // Liquidated margin stays with what is left of the position instead of being freed
+        let market = self
+            .markets
+            .get_mut(&market_id)
+            .ok_or(OrderBookError::MarketNotFound { market_id })?;
+        let remaining_size = match market.position_manager.positions.get_mut(&trader_id) {
+            Some(remaining) => {
+                remaining.margin = position.margin + realized_pnl - penalty;
+                PositionManager::refresh_risk(remaining, &self.liquidation_engine)?;
+                remaining.size
+            }
+            None => Decimal::ZERO,
+        };
+
+        let shortfall = match margin_mode {
+            MarginMode::Isolated if remaining_size.is_zero() => -(equity - penalty),
+            MarginMode::Isolated => Decimal::ZERO,
+            MarginMode::Cross => -self.accounts.account(trader_id)?.balance,
+        }
+        .max(Decimal::ZERO);
+
+        let mut insurance_payout = Decimal::ZERO;
+        let mut socialized_loss = Decimal::ZERO;
+        let mut uncovered_loss = Decimal::ZERO;
+        if shortfall > Decimal::ZERO {
+            if self
+                .insurance_fund
+                .process_payout(shortfall, self.timestamp)?
+            {
+                self.accounts
+                    .post(trader_id, LedgerEntryKind::InsurancePayout, shortfall)?;
+                insurance_payout = shortfall;
+            } else {
+                self.insurance_fund
+                    .record_shortfall(shortfall, self.timestamp)?;
+                socialized_loss = self.socialize_loss(trader_id, market_id, shortfall)?;
+                if !socialized_loss.is_zero() {
+                    self.accounts.post(
+                        trader_id,
+                        LedgerEntryKind::SocializedLoss,
+                        socialized_loss,
+                    )?;
+                }
+                uncovered_loss = shortfall - socialized_loss;
+            }
+        }
+
+        Ok(LiquidationOutcome {
+            trader_id,
+            market_id,
+            side: position.side,
+            quantity: filled,
+            remaining_size,
+            fills,
+            deleveraged,
+            penalty,
+            insurance_payout,
+            socialized_loss,
+            uncovered_loss,
+        })
+    }
+
+    pub fn required_margin(
+        &self,
+        trader_id: u64,
+        market_id: MarketId,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<Decimal> {
+        let leverage = self.accounts.account(trader_id)?.leverage;
+        let market = self.market(market_id)?;
+
+        // A sell can fill above its limit against resting bids, so size it at the worst fill
+        let margin_price = match (side, market.order_book.best_buy()) {
+            (Side::Sell, Some((best_bid, _))) => price.max(best_bid),
+            _ => price,
+        };
+        let notional = margin_price * quantity;
+
+        // Size against the bracket the position lands in if this and every other open order on
+        // the same side fills
+        let (position_notional, position_margin) = market
+            .position(trader_id)
+            .filter(|position| position.side == PositionSide::from(side))
+            .map_or((Decimal::ZERO, Decimal::ZERO), |position| {
+                (position.entry_price * position.size, position.margin)
+            });
+        let (_, resting_notional, resting_margin) = market.resting_exposure(trader_id, side);
+        let existing_notional = position_notional + resting_notional;
+        let existing_margin = position_margin + resting_margin;
+        let total_notional = existing_notional + notional;
+        let engine = &self.liquidation_engine;
+        let initial_margin_rate = engine.initial_margin_rate(total_notional);
+        let max_leverage = leverage.min(engine.max_leverage(total_notional));
+
+        let initial_margin = (notional * initial_margin_rate)
+            .round_dp(2)
+            .max(notional / max_leverage);
+        let bracket_requirement = (total_notional * initial_margin_rate)
+            .round_dp(2)
+            .max(total_notional / engine.max_leverage(total_notional));
+        let top_up = (bracket_requirement - existing_margin - initial_margin).max(Decimal::ZERO);
+
+        let taker_fee = self.fee_rates(trader_id).taker_fee();
+        Ok(initial_margin + top_up + (notional * taker_fee).max(Decimal::ZERO))
+    }
+
+    pub fn submit_order(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<OrderOutcome> {
+        self.submit(
+            trader_id, market_id, side, price, quantity, false, false, true,
+        )
+    }
+
+    // Immediate-or-cancel: fills what it can on arrival and never rests the remainder
+    pub fn submit_ioc_order(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<OrderOutcome> {
+        self.submit(
+            trader_id, market_id, side, price, quantity, false, false, false,
+        )
+    }
+
+    // Rejected instead of taking liquidity if it would match on arrival
+    pub fn submit_post_only_order(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<OrderOutcome> {
+        self.submit(
+            trader_id, market_id, side, price, quantity, false, true, true,
+        )
+    }
+
+    // Only ever shrinks the trader's position: sized down to the position at placement, capped
+    // again at match time, and resized or cancelled if the position shrinks while it rests
+    pub fn submit_reduce_only_order(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<OrderOutcome> {
+        self.submit(
+            trader_id, market_id, side, price, quantity, true, false, true,
+        )
+    }
+
+    // Closes against the book immediately without ever resting, e.g. to flatten a position
+    pub fn submit_reduce_only_ioc_order(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<OrderOutcome> {
+        self.submit(
+            trader_id, market_id, side, price, quantity, true, false, false,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn submit(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+        reduce_only: bool,
+        post_only: bool,
+        rest: bool,
+    ) -> Result<OrderOutcome> {
+        if quantity <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidQuantity(
+                "Quantity must be positive".to_string(),
+            ));
+        }
+
+        let market = self.market(market_id)?;
+        if market.halted {
+            return Err(OrderBookError::MarketHalted { market_id });
+        }
+        if market.contract.is_expired(self.timestamp) {
+            return Err(OrderBookError::MarketExpired { market_id });
+        }
+        market.check_tick(price)?;
+
+        let quantity = if reduce_only {
+            let reducible = market
+                .position(trader_id)
+                .filter(|position| position.side == PositionSide::from(side).opposite())
+                .map_or(Decimal::ZERO, |position| position.size);
+            if reducible.is_zero() {
+                return Err(OrderBookError::ReduceOnlyRejected {
+                    trader_id,
+                    market_id,
+                });
+            }
+            quantity.min(reducible)
+        } else {
+            quantity
+        };
+
+        let (resting_quantity, _, _) = market.resting_exposure(trader_id, side);
+        let exposure = OrderExposure {
+            open_orders: self.open_order_count(trader_id),
+            position_size: market
+                .position(trader_id)
+                .filter(|position| position.side == PositionSide::from(side))
+                .map_or(Decimal::ZERO, |position| position.size),
+            resting_quantity,
+            quantity,
+            notional: price * quantity,
+            reduce_only,
+            rests: rest,
+        };
+        self.accounts
+            .account(trader_id)?
+            .risk_limits
+            .check(&exposure, market.position_manager.max_position_size)?;
+        if !reduce_only {
+            // Resting orders are held to the caps as placed; fills settle regardless
+            market.position_manager.check_open_interest(
+                trader_id,
+                side.into(),
+                resting_quantity + quantity,
+            )?;
+        }
+
+        if market.in_safe_mode() {
+            let reducing = market.position(trader_id).is_some_and(|position| {
+                position.side == PositionSide::from(side).opposite() && quantity <= position.size
+            });
+            if !reducing {
+                return Err(market.safe_mode_error());
+            }
+        }
+
+        if post_only {
+            let best_opposite = match side {
+                Side::Buy => market.order_book.best_sell(),
+                Side::Sell => market.order_book.best_buy(),
+            };
+            if let Some((best_price, _)) = best_opposite {
+                let crosses = match side {
+                    Side::Buy => price >= best_price,
+                    Side::Sell => price <= best_price,
+                };
+                if crosses {
+                    return Err(OrderBookError::PostOnlyWouldCross { price, best_price });
+                }
+            }
+        }
+
+        // Self-trade prevention rejects the incoming order and leaves the resting one in place
+        if let Some(order_id) = market.self_trade(trader_id, side, price) {
+            return Err(OrderBookError::SelfTrade {
+                trader_id,
+                order_id,
+            });
+        }
+
+        let required = if reduce_only {
+            // Closing needs no initial margin, only the fee it may pay
+            (price * quantity * self.fee_rates(trader_id).taker_fee()).max(Decimal::ZERO)
+        } else {
+            self.required_margin(trader_id, market_id, side, price, quantity)?
+        };
+        let available = self.available_balance(trader_id)?;
+        if available < required {
+            return Err(OrderBookError::InsufficientMargin {
+                required,
+                provided: available,
+            });
+        }
+
+        let order_id = self.next_order_id;
+        let (trades, trimmed, untracked_makers) = self
+            .market_mut(market_id)?
+            .match_order(side, price, quantity, order_id, rest)?;
+        self.next_order_id += 1;
+        self.release_order_margin(&trimmed)?;
+
+        let taker = RestingOrder {
+            trader_id,
+            side,
+            price,
+            margin_per_unit: required / quantity,
+            reduce_only,
+        };
+
+        let taker_fee_rate = self.fee_rates(trader_id).taker_fee();
+        let mut fills = Vec::with_capacity(trades.len());
+        for trade in trades {
+            fills.push(self.settle_trade(market_id, trade, side, taker, taker_fee_rate)?);
+        }
+
+        let market = self.market_mut(market_id)?;
+        let resting_quantity = market
+            .order_book
+            .order(order_id)
+            .map(|order| order.quantity)
+            .unwrap_or(Decimal::ZERO);
+        if resting_quantity > Decimal::ZERO {
+            market.track_order(order_id, taker);
+            self.accounts.account_mut(trader_id)?.order_margin +=
+                taker.margin_per_unit * resting_quantity;
+        }
+
+        // Older reduce-only orders keep their claim on the position over this one
+        let resting_quantity = if reduce_only && resting_quantity > Decimal::ZERO {
+            self.on_position_change(market_id, trader_id)?;
+            self.market(market_id)?
+                .order_book
+                .order(order_id)
+                .map(|order| order.quantity)
+                .unwrap_or(Decimal::ZERO)
+        } else {
+            resting_quantity
+        };
+
+        Ok(OrderOutcome {
+            order_id,
+            fills,
+            resting_quantity,
+            untracked_makers,
+        })
+    }
+
+    pub fn cancel_order(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        order_id: u64,
+    ) -> Result<Decimal> {
+        let market = self.market_mut(market_id)?;
+        match market.resting_orders.get(&order_id) {
+            Some(resting) if resting.trader_id == trader_id => {}
+            _ => return Err(OrderBookError::OrderNotFound { id: order_id }),
+        }
+
+        let order = market.order_book.cancel_order(order_id)?;
+        let resting = market
+            .untrack_order(order_id)
+            .ok_or(OrderBookError::OrderNotFound { id: order_id })?;
+
+        let released = resting.margin_per_unit * order.quantity;
+        self.accounts.account_mut(trader_id)?.order_margin -= released;
+        Ok(released)
+    }
+
+    pub fn adjust_margin(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        delta: Decimal,
+    ) -> Result<Position> {
+        self.settle_funding(trader_id, market_id)?;
+        if delta > Decimal::ZERO {
+            let available = self.available_balance(trader_id)?;
+            if delta > available {
+                return Err(OrderBookError::InsufficientBalance {
+                    trader_id,
+                    requested: delta,
+                    available,
+                });
+            }
+        }
+
+        let market = self
+            .markets
+            .get_mut(&market_id)
+            .ok_or(OrderBookError::MarketNotFound { market_id })?;
+        let mark_price = market
+            .position(trader_id)
+            .map(|position| market.valuation_price(position))
+            .ok_or(OrderBookError::PositionNotFound { trader_id })?;
+        market.position_manager.adjust_margin(
+            trader_id,
+            delta,
+            mark_price,
+            &self.liquidation_engine,
+        )
+    }
+
+    // Settles the market's funding rate for the interval ending now and accrues it into the
+    // index; returns the new index
+    pub fn apply_funding(&mut self, market_id: MarketId) -> Result<Decimal> {
+        let timestamp = self.timestamp;
+        let market = self.market_mut(market_id)?;
+        if market.in_safe_mode() || !market.contract.has_funding() {
+            return Ok(market.position_manager.cumulative_funding);
+        }
+        let mark_price = market
+            .mark_price
+            .ok_or_else(|| OrderBookError::InvalidPrice("Mark price not set".to_string()))?;
+        market.sync_open_interest();
+        market.funding.calculate_funding_rate(timestamp)?;
+        market
+            .position_manager
+            .apply_funding(&market.funding, mark_price)
+    }
+
+    pub fn settle_funding(&mut self, trader_id: u64, market_id: MarketId) -> Result<Decimal> {
+        let market = self
+            .markets
+            .get_mut(&market_id)
+            .ok_or(OrderBookError::MarketNotFound { market_id })?;
+        let payment = market
+            .position_manager
+            .settle_funding(trader_id, &self.liquidation_engine)?;
+        if !payment.is_zero() {
+            self.accounts
+                .post(trader_id, LedgerEntryKind::Funding, payment)?;
+        }
+        Ok(payment)
+    }
+
+    pub fn pending_funding(&self, trader_id: u64) -> Decimal {
+        self.markets
+            .values()
+            .map(|market| market.position_manager.pending_funding(trader_id))
+            .sum()
+    }
+
+    pub fn reconcile(&self, trader_id: u64) -> bool {
+        self.accounts.reconcile(trader_id)
+    }
+
+    pub fn total_position_value(&self) -> Decimal {
+        self.markets
+            .values()
+            .flat_map(|market| {
+                market
+                    .position_manager
+                    .positions
+                    .values()
+                    .map(move |position| market.valuation_price(position) * position.size)
+            })
+            .sum()
+    }
+
+    pub fn adl_ranking(&self, market_id: MarketId) -> Result<Vec<AdlRank>> {
+        let market = self.market(market_id)?;
+        let mut ranking = Vec::with_capacity(market.position_manager.positions.len());
+
+        for side in [PositionSide::Long, PositionSide::Short] {
+            let mut scored: Vec<(u64, Decimal)> = market
+                .position_manager
+                .positions
+                .values()
+                .filter(|position| position.side == side)
+                .map(|position| {
+                    let score =
+                        LiquidationEngine::adl_score(position, market.valuation_price(position));
+                    (position.trader_id, score)
+                })
+                .collect();
+            scored.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+            // Profitable positions fill quantiles 5 (first in line) down to 1; losers are never hit
+            let profitable = scored
+                .iter()
+                .filter(|(_, score)| *score > Decimal::ZERO)
+                .count();
+            for (rank, (trader_id, score)) in scored.into_iter().enumerate() {
+                let quantile = if score > Decimal::ZERO {
+                    (5 - rank * 5 / profitable) as u8
+                } else {
+                    0
+                };
+                ranking.push(AdlRank {
+                    trader_id,
+                    side,
+                    score,
+                    quantile,
+                });
+            }
+        }
+
+        Ok(ranking)
+    }
+
+    pub fn adl_quantile(&self, trader_id: u64, market_id: MarketId) -> Result<u8> {
+        self.adl_ranking(market_id)?
+            .into_iter()
+            .find(|rank| rank.trader_id == trader_id)
+            .map(|rank| rank.quantile)
+            .ok_or(OrderBookError::PositionNotFound { trader_id })
+    }
+
+    fn requires_adl(&self, trader_id: u64, position: &Position, market: &Market) -> Result<bool> {
+        if !self.liquidation_engine.adl_enabled {
+            return Ok(false);
+        }
+
+        let (equity, _) =
+            self.liquidation_exposure(trader_id, position, market.valuation_price(position))?;
+        if equity > Decimal::ZERO {
+            return Ok(false);
+        }
+
+        // Profitable counterparties are only closed out for a deficit the fund can't pay
+        Ok(-equity > self.insurance_fund.balance)
+    }
+
+    fn place_liquidation_order(
+        &mut self,
+        market_id: MarketId,
+        position: &Position,
+    ) -> Result<Vec<Fill>> {
+        let market = self.market(market_id)?;
+        let quantity = self.liquidation_quantity(position.trader_id, position, market)?;
+        self.place_reduce_only(market_id, position, quantity, Decimal::ZERO)
+    }
+
+    // Side and slippage-bounded limit price of an IOC that closes `position`
+    pub(super) fn closing_order(&self, market: &Market, position: &Position) -> (Side, Decimal) {
+        let mark = market.valuation_price(position);
+        let slippage = self.liquidation_engine.max_liquidation_slippage;
+        let (min_price, max_price) = market.order_book.price_bounds();
+        match position.side {
+            PositionSide::Long => (
+                Side::Sell,
+                (mark * (Decimal::ONE - slippage)).max(min_price),
+            ),
+            PositionSide::Short => (Side::Buy, (mark * (Decimal::ONE + slippage)).min(max_price)),
+        }
+    }
+
+    pub(super) fn place_reduce_only(
+        &mut self,
+        market_id: MarketId,
+        position: &Position,
+        quantity: Decimal,
+        taker_fee_rate: Decimal,
+    ) -> Result<Vec<Fill>> {
+        let (side, limit_price) = self.closing_order(self.market(market_id)?, position);
+        let order_id = self.next_order_id;
+        let (trades, trimmed, _) = self.market_mut(market_id)?.match_order(
+            side,
+            limit_price,
+            quantity,
+            order_id,
+            false,
+        )?;
+        self.next_order_id += 1;
+        self.release_order_margin(&trimmed)?;
+
+        let closer = RestingOrder {
+            trader_id: position.trader_id,
+            side,
+            price: limit_price,
+            margin_per_unit: Decimal::ZERO,
+            reduce_only: true,
+        };
+        let mut fills = Vec::with_capacity(trades.len());
+        for trade in trades {
+            fills.push(self.settle_trade(market_id, trade, side, closer, taker_fee_rate)?);
+        }
+        Ok(fills)
+    }
+
+    // Closes a bankrupt position at its bankruptcy price against the top of the ADL queue
+    fn auto_deleverage(
+        &mut self,
+        market_id: MarketId,
+        position: &Position,
+    ) -> Result<Vec<AdlFill>> {
+        let opposing = position.side.opposite();
+        let counterparties: Vec<u64> = self
+            .adl_ranking(market_id)?
+            .into_iter()
+            .filter(|rank| rank.side == opposing && rank.quantile > 0)
+            .map(|rank| rank.trader_id)
+            .collect();
+
+        let price = position.bankruptcy_price;
+        let market = self
+            .markets
+            .get_mut(&market_id)
+            .ok_or(OrderBookError::MarketNotFound { market_id })?;
+        let mut remaining = position.size;
+        let mut deleveraged = Vec::new();
+
+        for counterparty in counterparties {
+            if remaining.is_zero() {
+                break;
+            }
+            let Some(size) = market.position(counterparty).map(|p| p.size) else {
+                continue;
+            };
+            let quantity = remaining.min(size);
+
+            let legs = [
+                (counterparty, position.side),
+                (position.trader_id, opposing),
+            ];
+            let mut counterparty_pnl = Decimal::ZERO;
+            for (trader_id, side) in legs {
+                let update = market.position_manager.apply_fill(
+                    trader_id,
+                    side,
+                    quantity,
+                    price,
+                    Decimal::ZERO,
+                    &self.liquidation_engine,
+                )?;
+                if !update.funding_payment.is_zero() {
+                    self.accounts.post(
+                        trader_id,
+                        LedgerEntryKind::Funding,
+                        update.funding_payment,
+                    )?;
+                }
+                if !update.realized_pnl.is_zero() {
+                    self.accounts.post(
+                        trader_id,
+                        LedgerEntryKind::RealizedPnl,
+                        update.realized_pnl,
+                    )?;
+                }
+                if trader_id == counterparty {
+                    counterparty_pnl = update.realized_pnl;
+                }
+            }
+
+            deleveraged.push(AdlFill {
+                trader_id: counterparty,
+                quantity,
+                price,
+                realized_pnl: counterparty_pnl,
+            });
+            remaining -= quantity;
+        }
+
+        self.on_position_change(market_id, position.trader_id)?;
+        for adl in &deleveraged {
+            self.on_position_change(market_id, adl.trader_id)?;
+        }
+        Ok(deleveraged)
+    }
+
+    // Charges a shortfall the fund could not cover to the remaining positions in the market.
+    // No position is charged past its margin: a capped share is spread over the others, and
+    // whatever none of them can absorb is left out of the returned total.
+    pub(super) fn socialize_loss(
+        &mut self,
+        bankrupt_trader: u64,
+        market_id: MarketId,
+        amount: Decimal,
+    ) -> Result<Decimal> {
+        let mode = self.liquidation_engine.socialized_loss;
+        let market = self.market(market_id)?;
+        let mut open: Vec<(u64, Decimal, Decimal)> = market
+            .position_manager
+            .positions
+            .values()
+            .filter(|position| position.trader_id != bankrupt_trader)
+            .map(|position| {
+                let mark = market.valuation_price(position);
+                let weight = match mode {
+                    SocializedLoss::Disabled => Decimal::ZERO,
+                    SocializedLoss::ProfitablePositions => {
+                        LiquidationEngine::calculate_pnl(position, mark).max(Decimal::ZERO)
+                    }
+                    SocializedLoss::OpenInterest => mark * position.size,
+                };
+                (position.trader_id, weight, position.margin)
+            })
+            .filter(|&(_, weight, capacity)| weight > Decimal::ZERO && capacity > Decimal::ZERO)
+            .collect();
+        open.sort_unstable_by_key(|&(trader_id, _, _)| trader_id);
+
+        let mut charges = Vec::new();
+        let mut remaining = amount;
+        while !open.is_empty() && remaining > Decimal::ZERO {
+            let total: Decimal = open.iter().map(|&(_, weight, _)| weight).sum();
+            let (capped, uncapped): (Vec<_>, Vec<_>) = open
+                .iter()
+                .partition(|&&(_, weight, capacity)| remaining * weight / total >= capacity);
+            if capped.is_empty() {
+                let mut allocated = Decimal::ZERO;
+                for (index, &(trader_id, weight, _)) in uncapped.iter().enumerate() {
+                    // The last share absorbs rounding so the whole remainder is allocated
+                    let share = if index + 1 == uncapped.len() {
+                        remaining - allocated
+                    } else {
+                        remaining * weight / total
+                    };
+                    allocated += share;
+                    charges.push((trader_id, share));
+                }
+                remaining = Decimal::ZERO;
+                break;
+            }
+
+            for &(trader_id, _, capacity) in &capped {
+                charges.push((trader_id, capacity));
+                remaining -= capacity;
+            }
+            open = uncapped;
+        }
+        charges.sort_unstable_by_key(|&(trader_id, _)| trader_id);
+
+        let market = self
+            .markets
+            .get_mut(&market_id)
+            .ok_or(OrderBookError::MarketNotFound { market_id })?;
+        for (trader_id, share) in charges {
+            if let Some(position) = market.position_manager.positions.get_mut(&trader_id) {
+                position.margin -= share;
+                PositionManager::refresh_risk(position, &self.liquidation_engine)?;
+            }
+            self.accounts
+                .post(trader_id, LedgerEntryKind::SocializedLoss, -share)?;
+        }
+
+        Ok(amount - remaining)
+    }
+
+    fn liquidation_exposure(
+        &self,
+        trader_id: u64,
+        position: &Position,
+        mark: Decimal,
+    ) -> Result<(Decimal, Decimal)> {
+        match self.margin_mode(trader_id) {
+            MarginMode::Isolated => Ok((
+                position.margin + LiquidationEngine::calculate_pnl(position, mark),
+                mark * position.size,
+            )),
+            MarginMode::Cross => {
+                let positions = self.positions_for(trader_id);
+                let unrealized_pnl: Decimal = positions
+                    .iter()
+                    .map(|&(p, price)| LiquidationEngine::calculate_pnl(p, price))
+                    .sum();
+                let notional = positions.iter().map(|&(p, price)| price * p.size).sum();
+                Ok((
+                    self.accounts.account(trader_id)?.balance + unrealized_pnl,
+                    notional,
+                ))
+            }
+        }
+    }
+
+    fn expired(&self, market_id: MarketId) -> bool {
+        self.markets
+            .get(&market_id)
+            .is_some_and(|market| market.contract.is_expired(self.timestamp))
+    }
+
+    fn liquidations_paused(&self, market_id: MarketId) -> bool {
+        self.liquidation_engine.safe_mode_liquidations == SafeModeLiquidations::Pause
+            && self
+                .markets
+                .get(&market_id)
+                .is_some_and(|market| market.in_safe_mode())
+    }
+
+    // In safe mode the index can't be trusted, so FallbackMark values positions at the book mid
+    fn apply_fallback_mark(&mut self, market_id: MarketId) -> Result<()> {
+        if self.liquidation_engine.safe_mode_liquidations != SafeModeLiquidations::FallbackMark {
+            return Ok(());
+        }
+
+        let market = self.market_mut(market_id)?;
+        if !market.in_safe_mode() {
+            return Ok(());
+        }
+        if let (Some((bid, _)), Some((ask, _))) =
+            (market.order_book.best_buy(), market.order_book.best_sell())
+        {
+            market.mark_price = Some((bid + ask) / Decimal::TWO);
+        }
+        Ok(())
+    }
+
+    fn settle_account_funding(&mut self, trader_id: u64) -> Result<()> {
+        let market_ids: Vec<MarketId> = self
+            .markets
+            .values()
+            .filter(|market| market.position(trader_id).is_some())
+            .map(|market| market.id)
+            .collect();
+        for market_id in market_ids {
+            self.settle_funding(trader_id, market_id)?;
+        }
+        Ok(())
+    }
+
+    fn settle_market_funding(&mut self, market_id: MarketId) -> Result<()> {
+        let mut trader_ids: Vec<u64> = self
+            .market(market_id)?
+            .position_manager
+            .positions
+            .keys()
+            .copied()
+            .collect();
+        trader_ids.sort_unstable();
+        for trader_id in trader_ids {
+            self.settle_funding(trader_id, market_id)?;
+        }
+        Ok(())
+    }
+
+    // Keeps orders and triggers hanging off a position in line with it after every change
+    fn on_position_change(&mut self, market_id: MarketId, trader_id: u64) -> Result<()> {
+        let market = self.market_mut(market_id)?;
+        market.sync_open_interest();
+        market.prune_triggers(trader_id);
+        let trimmed = market.resize_reduce_only(trader_id)?;
+        self.release_order_margin(&trimmed)
+    }
+
+    fn release_order_margin(&mut self, trimmed: &[(RestingOrder, Decimal)]) -> Result<()> {
+        for (resting, quantity) in trimmed {
+            self.accounts.account_mut(resting.trader_id)?.order_margin -=
+                resting.margin_per_unit * quantity;
+        }
+        Ok(())
+    }
+
+    // Splits a referred account's fee with its referrer and itself; rebates earn nothing
+    fn pay_referral(&mut self, trader_id: u64, fee: Decimal) -> Result<Decimal> {
+        let Some(referrer) = self.accounts.account(trader_id)?.referrer else {
+            return Ok(Decimal::ZERO);
+        };
+        if fee <= Decimal::ZERO {
+            return Ok(Decimal::ZERO);
+        }
+
+        let splits = [
+            (referrer, fee * self.fee_structure.referrer_share),
+            (trader_id, fee * self.fee_structure.referee_share),
+        ];
+        let mut paid = Decimal::ZERO;
+        for (recipient, amount) in splits {
+            if !amount.is_zero() {
+                self.accounts
+                    .post(recipient, LedgerEntryKind::ReferralRebate, amount)?;
+                paid += amount;
+            }
+        }
+        Ok(paid)
+    }
+
+    fn route_fees(&mut self, fees: Decimal) -> Result<()> {
+        if fees <= Decimal::ZERO {
+            return Ok(());
+        }
+
+        let (to_fund, to_treasury) = self
+            .insurance_fund
+            .fee_split(fees, self.total_position_value());
+        if !to_fund.is_zero() {
+            self.insurance_fund
+                .add_contribution(to_fund, self.timestamp)?;
+        }
+        self.treasury += to_treasury;
+        Ok(())
+    }
+
+    pub(super) fn margin_mode(&self, trader_id: u64) -> MarginMode {
+        self.accounts
+            .account(trader_id)
+            .map(|account| account.margin_mode)
+            .unwrap_or(MarginMode::Isolated)
+    }
+
+    // Smallest reduction that brings the margin ratio back to initial margin after the penalty
+    fn liquidation_quantity(
+        &self,
+        trader_id: u64,
+        position: &Position,
+        market: &Market,
+    ) -> Result<Decimal> {
+        let mark = market.valuation_price(position);
+        let (equity, notional) = self.liquidation_exposure(trader_id, position, mark)?;
+
+        let target = self.liquidation_engine.initial_margin_rate(notional);
+        let penalty = self.fee_structure.liquidation_fee;
+        if equity <= Decimal::ZERO || target <= penalty || mark.is_zero() {
+            return Ok(position.size);
+        }
+
+        let quantity = ((target * notional - equity) / ((target - penalty) * mark))
+            .round_dp_with_strategy(position.size.scale(), RoundingStrategy::AwayFromZero);
+        let min_lot = Decimal::new(1, position.size.scale());
+        Ok(quantity.max(min_lot).min(position.size))
+    }
+
+    fn settle_trade(
+        &mut self,
+        market_id: MarketId,
+        trade: Trade,
+        taker_side: Side,
+        taker: RestingOrder,
+        taker_fee_rate: Decimal,
+    ) -> Result<Fill> {
+        let maker = self
+            .market(market_id)?
+            .resting_orders
+            .get(&trade.maker_id)
+            .copied()
+            .ok_or(OrderBookError::OrderNotFound { id: trade.maker_id })?;
+        let maker_fee_rate = self.fee_rates(maker.trader_id).maker_fee();
+
+        let market = self
+            .markets
+            .get_mut(&market_id)
+            .ok_or(OrderBookError::MarketNotFound { market_id })?;
+        if market.order_book.order(trade.maker_id).is_none() {
+            market.untrack_order(trade.maker_id);
+        }
+
+        market.last_trade_price = Some(trade.price);
+        let notional = trade.price * trade.quantity;
+        let maker_fee = notional * maker_fee_rate;
+        let taker_fee = notional * taker_fee_rate;
+
+        let maker_margin = maker.margin_per_unit * trade.quantity;
+        self.accounts.account_mut(maker.trader_id)?.order_margin -= maker_margin;
+
+        let legs = [
+            (
+                maker.trader_id,
+                taker_side.opposite(),
+                maker_margin,
+                maker_fee,
+            ),
+            (
+                taker.trader_id,
+                taker_side,
+                taker.margin_per_unit * trade.quantity,
+                taker_fee,
+            ),
+        ];
+        for (trader_id, side, margin, fee) in legs {
+            let update = market.position_manager.settle_fill(
+                trader_id,
+                side.into(),
+                trade.quantity,
+                trade.price,
+                margin - fee,
+                &self.liquidation_engine,
+            )?;
+
+            if !update.funding_payment.is_zero() {
+                self.accounts
+                    .post(trader_id, LedgerEntryKind::Funding, update.funding_payment)?;
+            }
+            if !fee.is_zero() {
+                self.accounts
+                    .post(trader_id, LedgerEntryKind::TradingFee, -fee)?;
+            }
+            if !update.realized_pnl.is_zero() {
+                self.accounts
+                    .post(trader_id, LedgerEntryKind::RealizedPnl, update.realized_pnl)?;
+            }
+        }
+
+        self.on_position_change(market_id, maker.trader_id)?;
+        self.on_position_change(market_id, taker.trader_id)?;
+
+        let window = self.fee_structure.volume_window;
+        let mut referral_paid = Decimal::ZERO;
+        for (trader_id, fee) in [(maker.trader_id, maker_fee), (taker.trader_id, taker_fee)] {
+            self.accounts
+                .record_volume(trader_id, self.timestamp, notional, window);
+            referral_paid += self.pay_referral(trader_id, fee)?;
+        }
+
+        let net_fees = maker_fee + taker_fee - referral_paid;
+        self.fees_collected += net_fees;
+        self.route_fees(net_fees)?;
+
+        Ok(Fill {
+            trade,
+            maker_trader_id: maker.trader_id,
+            taker_trader_id: taker.trader_id,
+            maker_fee,
+            taker_fee,
+        })
+    }
+}
+
+// Builders shared by the clearing tests here and in the feature modules
+#[cfg(test)]
+pub(super) mod fixtures {
+    use super::*;
+    use crate::perps::AggregatedPrice;
+
+    pub const MARKET: MarketId = 1;
+
+    pub fn funded(traders: &[(u64, Decimal)]) -> ClearingHouse {
+        let mut clearing = ClearingHouse::new();
+        clearing.add_market(Market::new(MARKET)).unwrap();
+        for &(trader_id, amount) in traders {
+            clearing.deposit(trader_id, amount).unwrap();
+        }
+        clearing
+    }
+
+    pub fn oracle_at(price: Decimal, timestamp: u64, confidence: Decimal) -> OraclePrice {
+        let mut oracle = OraclePrice::new(price);
+        oracle
+            .update(&AggregatedPrice {
+                price,
+                timestamp,
+                confidence,
+                sources: vec!["a".to_string()],
+                dropped: Vec::new(),
+            })
+            .unwrap();
+        oracle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::*;
+    use super::*;
+    use crate::perps::{FeeTier, FundEvent, FundEventKind, PositionSide};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_trade_updates_maker_and_taker_at_fill_price() {
+        let mut clearing = funded(&[(1, dec!(5000)), (2, dec!(5000))]);
+        let resting = clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        assert!(resting.fills.is_empty());
+        assert_eq!(resting.resting_quantity, dec!(10));
+
+        let outcome = clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1010), dec!(4))
+            .unwrap();
+
+        assert_eq!(outcome.fills.len(), 1);
+        let fill = &outcome.fills[0];
+        assert_eq!(fill.trade.price, dec!(1000));
+        assert_eq!(fill.maker_trader_id, 1);
+        assert_eq!(fill.taker_trader_id, 2);
+
+        let maker = &clearing.markets[&MARKET].position_manager.positions[&1];
+        assert_eq!(maker.side, PositionSide::Short);
+        assert_eq!(maker.size, dec!(4));
+        assert_eq!(maker.entry_price, dec!(1000));
+
+        let taker = &clearing.markets[&MARKET].position_manager.positions[&2];
+        assert_eq!(taker.side, PositionSide::Long);
+        assert_eq!(taker.entry_price, dec!(1000));
+        assert_eq!(
+            clearing.markets[&MARKET]
+                .position_manager
+                .total_long_interest,
+            dec!(4)
+        );
+        assert_eq!(
+            clearing.markets[&MARKET]
+                .position_manager
+                .total_short_interest,
+            dec!(4)
+        );
+    }
+
+    #[test]
+    fn test_fees_debited_and_credited_to_accounts() {
+        let mut clearing = funded(&[(1, dec!(2000)), (2, dec!(2000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        let outcome = clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+
+        let fill = &outcome.fills[0];
+        assert_eq!(fill.maker_fee(), dec!(-1));
+        assert_eq!(fill.taker_fee(), dec!(5));
+        assert_eq!(clearing.accounts.account(1).unwrap().balance, dec!(2001));
+        assert_eq!(clearing.accounts.account(2).unwrap().balance, dec!(1995));
+        assert_eq!(clearing.accounts.account(1).unwrap().order_margin, dec!(0));
+        assert_eq!(
+            clearing.markets[&MARKET].position_manager.positions[&1].margin,
+            dec!(1006)
+        );
+        assert_eq!(
+            clearing.markets[&MARKET].position_manager.positions[&2].margin,
+            dec!(1000)
+        );
+        assert_eq!(clearing.fees_collected, dec!(4));
+        assert!(clearing.reconcile(1));
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_insufficient_margin_rejected_before_matching() {
+        let mut clearing = funded(&[(1, dec!(2000)), (2, dec!(100))]);
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+
+        let result = clearing.submit_order(2, MARKET, Side::Sell, dec!(1000), dec!(10));
+        assert_eq!(
+            result,
+            Err(OrderBookError::InsufficientMargin {
+                required: dec!(1005),
+                provided: dec!(100)
+            })
+        );
+        assert_eq!(
+            clearing.markets[&MARKET].order_book.best_buy(),
+            Some((dec!(1000), dec!(10)))
+        );
+        assert!(clearing.markets[&MARKET]
+            .position_manager
+            .positions
+            .is_empty());
+    }
+
+    #[test]
+    fn test_sell_margin_sized_at_best_bid() {
+        let mut clearing = funded(&[(1, dec!(1000)), (2, dec!(1000))]);
+        clearing.set_leverage(2, dec!(100)).unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(2000), dec!(1))
+            .unwrap();
+
+        assert_eq!(
+            clearing
+                .required_margin(2, MARKET, Side::Sell, dec!(1000), dec!(1))
+                .unwrap(),
+            dec!(21)
+        );
+    }
+
+    #[test]
+    fn test_cancel_releases_order_margin() {
+        let mut clearing = funded(&[(1, dec!(2000))]);
+        let outcome = clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        assert_eq!(clearing.available_balance(1).unwrap(), dec!(995));
+
+        assert!(clearing.cancel_order(2, MARKET, outcome.order_id).is_err());
+        assert_eq!(
+            clearing.cancel_order(1, MARKET, outcome.order_id).unwrap(),
+            dec!(1005)
+        );
+        assert_eq!(clearing.available_balance(1).unwrap(), dec!(2000));
+        assert!(clearing.markets[&MARKET].order_book.is_empty());
+    }
+
+    #[test]
+    fn test_realized_pnl_posted_to_ledger() {
+        let mut clearing = funded(&[(1, dec!(5000)), (2, dec!(5000)), (3, dec!(5000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(2))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(2))
+            .unwrap();
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(1100), dec!(2))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1100), dec!(2))
+            .unwrap();
+
+        let kinds: Vec<LedgerEntryKind> =
+            clearing.accounts.entries_for(2).map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                LedgerEntryKind::Deposit,
+                LedgerEntryKind::TradingFee,
+                LedgerEntryKind::TradingFee,
+                LedgerEntryKind::RealizedPnl,
+            ]
+        );
+        assert!(!clearing.markets[&MARKET]
+            .position_manager
+            .positions
+            .contains_key(&2));
+        assert_eq!(clearing.accounts.account(2).unwrap().balance, dec!(5197.9));
+        assert_eq!(clearing.available_balance(2).unwrap(), dec!(5197.9));
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_adjust_margin_draws_on_available_balance() {
+        let mut clearing = funded(&[(1, dec!(5000)), (2, dec!(5000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+
+        assert!(matches!(
+            clearing.adjust_margin(2, MARKET, dec!(4000)),
+            Err(OrderBookError::InsufficientBalance { .. })
+        ));
+
+        let before = clearing.market(MARKET).unwrap().position(2).unwrap().margin;
+        let position = clearing.adjust_margin(2, MARKET, dec!(1000)).unwrap();
+        assert_eq!(position.margin, before + dec!(1000));
+        assert!(position.leverage < dec!(10));
+        assert_eq!(clearing.available_balance(2).unwrap(), dec!(2995));
+        assert_eq!(clearing.accounts.account(2).unwrap().balance, dec!(4995));
+        assert!(clearing.reconcile(2));
+
+        // Underwater, the margin added above can no longer be taken back out
+        clearing.update_mark_price(MARKET, dec!(900)).unwrap();
+        assert!(matches!(
+            clearing.adjust_margin(2, MARKET, dec!(-1000)),
+            Err(OrderBookError::InsufficientMargin { .. })
+        ));
+        assert_eq!(
+            clearing.market(MARKET).unwrap().position(2).unwrap().margin,
+            position.margin
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_limited_by_initial_margin() {
+        let mut clearing = funded(&[(1, dec!(5000)), (2, dec!(5000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+
+        let free = clearing.available_balance(2).unwrap();
+        assert_eq!(free, dec!(3995));
+        assert_eq!(clearing.max_withdrawable(2).unwrap(), free);
+
+        clearing.update_mark_price(MARKET, dec!(600)).unwrap();
+        assert_eq!(clearing.max_withdrawable(2).unwrap(), dec!(935));
+        assert!(matches!(
+            clearing.withdraw(2, dec!(1000)),
+            Err(OrderBookError::InsufficientBalance { .. })
+        ));
+
+        let entry = clearing.withdraw(2, dec!(900)).unwrap();
+        assert_eq!(entry.kind, LedgerEntryKind::Withdrawal);
+        assert_eq!(entry.balance_after, dec!(4095));
+        assert!(clearing.reconcile(2));
+    }
+
+    fn cross_setup(margin_mode: MarginMode) -> ClearingHouse {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(3000))]);
+        clearing.add_market(Market::new(2)).unwrap();
+        clearing.set_margin_mode(1, MarginMode::Cross).unwrap();
+        clearing.set_margin_mode(2, margin_mode).unwrap();
+
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(1, 2, Side::Buy, dec!(100), dec!(50))
+            .unwrap();
+        clearing
+            .submit_order(2, 2, Side::Sell, dec!(100), dec!(50))
+            .unwrap();
+        clearing
+    }
+
+    #[test]
+    fn test_cross_margin_offsets_losses_across_markets() {
+        let mut clearing = cross_setup(MarginMode::Cross);
+        clearing.update_mark_price(MARKET, dec!(880)).unwrap();
+        clearing.update_mark_price(2, dec!(80)).unwrap();
+
+        let ratio = clearing.account_margin_ratio(2).unwrap();
+        assert_eq!(ratio.round_dp(4), dec!(0.2182));
+        assert!(clearing.liquidation_candidates().is_empty());
+
+        clearing.update_mark_price(2, dec!(100)).unwrap();
+        clearing.update_mark_price(MARKET, dec!(700)).unwrap();
+        assert_eq!(clearing.liquidation_candidates(), vec![(2, MARKET), (2, 2)]);
+    }
+
+    #[test]
+    fn test_isolated_margin_liquidates_positions_independently() {
+        let mut clearing = cross_setup(MarginMode::Isolated);
+        clearing.update_mark_price(MARKET, dec!(880)).unwrap();
+        clearing.update_mark_price(2, dec!(80)).unwrap();
+
+        assert_eq!(clearing.liquidation_candidates(), vec![(2, MARKET)]);
+    }
+
+    #[test]
+    fn test_cross_available_balance_includes_unrealized_pnl() {
+        let mut clearing = cross_setup(MarginMode::Cross);
+        let before = clearing.available_balance(2).unwrap();
+
+        clearing.update_mark_price(2, dec!(90)).unwrap();
+        assert_eq!(clearing.available_balance(2).unwrap(), before + dec!(500));
+
+        let mut isolated = cross_setup(MarginMode::Isolated);
+        isolated.update_mark_price(2, dec!(90)).unwrap();
+        assert_eq!(isolated.available_balance(2).unwrap(), before);
+    }
+
+    #[test]
+    fn test_margin_mode_locked_with_open_positions() {
+        let mut clearing = cross_setup(MarginMode::Cross);
+        assert_eq!(
+            clearing.set_margin_mode(2, MarginMode::Isolated),
+            Err(OrderBookError::MarginModeLocked { trader_id: 2 })
+        );
+        assert!(clearing.set_margin_mode(2, MarginMode::Cross).is_ok());
+    }
+
+    fn leveraged_long() -> ClearingHouse {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(5000)), (3, dec!(100000))]);
+        clearing.set_leverage(2, dec!(50)).unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+    }
+
+    #[test]
+    fn test_partial_liquidation_through_book() {
+        let mut clearing = leveraged_long();
+        assert_eq!(
+            clearing.liquidate(2, MARKET),
+            Err(OrderBookError::NotLiquidatable {
+                trader_id: 2,
+                market_id: MARKET
+            })
+        );
+
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(985), dec!(100))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(986)).unwrap();
+        assert_eq!(clearing.liquidation_candidates(), vec![(2, MARKET)]);
+
+        let outcomes = clearing.run_liquidations().unwrap();
+        assert_eq!(outcomes.len(), 1);
+        let outcome = &outcomes[0];
+        assert_eq!(outcome.quantity, dec!(6));
+        assert_eq!(outcome.remaining_size, dec!(4));
+        assert_eq!(outcome.fills[0].trade.price, dec!(985));
+        assert_eq!(outcome.penalty, dec!(17.73));
+        assert_eq!(outcome.insurance_payout, dec!(0));
+
+        let position = clearing.market(MARKET).unwrap().position(2).unwrap();
+        assert_eq!(position.margin, dec!(92.27));
+        assert!(!clearing.is_liquidatable(2, MARKET));
+        assert_eq!(
+            clearing.market(MARKET).unwrap().position(3).unwrap().size,
+            dec!(6)
+        );
+        assert_eq!(clearing.insurance_fund.balance, dec!(1000017.73));
+        assert_eq!(clearing.accounts.account(2).unwrap().balance, dec!(4887.27));
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_liquidation_past_bankruptcy_draws_insurance_fund() {
+        let mut clearing = leveraged_long();
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(970), dec!(10))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(975)).unwrap();
+
+        let outcome = clearing.liquidate(2, MARKET).unwrap();
+        assert_eq!(outcome.quantity, dec!(10));
+        assert_eq!(outcome.remaining_size, dec!(0));
+        assert_eq!(outcome.penalty, dec!(0));
+        assert_eq!(outcome.insurance_payout, dec!(100));
+        assert!(clearing.market(MARKET).unwrap().position(2).is_none());
+
+        // The trader loses exactly the margin and fee they put up
+        assert_eq!(clearing.accounts.account(2).unwrap().balance, dec!(4795));
+        assert_eq!(clearing.insurance_fund.balance, dec!(999900));
+        assert_eq!(
+            clearing.accounts.entries_for(2).last().unwrap().kind,
+            LedgerEntryKind::InsurancePayout
+        );
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_small_fund_that_covers_the_shortfall_avoids_adl() {
+        let mut clearing = leveraged_long();
+        // A small fraction of the 19.5k open, but enough for the 100 shortfall
+        clearing.insurance_fund = InsuranceFund::new(dec!(1000));
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(970), dec!(10))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(975)).unwrap();
+
+        let outcome = clearing.liquidate(2, MARKET).unwrap();
+        assert!(outcome.deleveraged.is_empty());
+        assert_eq!(outcome.insurance_payout, dec!(100));
+        assert_eq!(clearing.insurance_fund.balance, dec!(900));
+        assert_eq!(
+            clearing.market(MARKET).unwrap().position(1).unwrap().size,
+            dec!(10)
+        );
+    }
+
+    #[test]
+    fn test_liquidation_reports_uncovered_loss() {
+        let mut clearing = leveraged_long();
+        clearing.insurance_fund = InsuranceFund::new(dec!(50));
+        clearing.liquidation_engine.adl_enabled = false;
+        clearing.liquidation_engine.socialized_loss = SocializedLoss::Disabled;
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(970), dec!(10))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(975)).unwrap();
+
+        let outcome = clearing.liquidate(2, MARKET).unwrap();
+        assert_eq!(outcome.insurance_payout, dec!(0));
+        assert_eq!(outcome.uncovered_loss, dec!(100));
+        assert_eq!(clearing.insurance_fund.balance, dec!(50));
+    }
+
+    #[test]
+    fn test_adl_closes_bankrupt_position_at_bankruptcy_price() {
+        let mut clearing = leveraged_long();
+        clearing.insurance_fund = InsuranceFund::new(dec!(0));
+        clearing.update_mark_price(MARKET, dec!(975)).unwrap();
+        assert_eq!(clearing.total_position_value(), dec!(19500));
+        assert_eq!(clearing.adl_quantile(1, MARKET).unwrap(), 5);
+
+        let outcome = clearing.liquidate(2, MARKET).unwrap();
+        assert!(outcome.fills.is_empty());
+        assert_eq!(
+            outcome.deleveraged,
+            vec![AdlFill {
+                trader_id: 1,
+                quantity: dec!(10),
+                price: dec!(980),
+                realized_pnl: dec!(200),
+            }]
+        );
+        assert_eq!(outcome.remaining_size, dec!(0));
+        assert_eq!(outcome.uncovered_loss, dec!(0));
+
+        let market = clearing.market(MARKET).unwrap();
+        assert!(market.position(1).is_none());
+        assert!(market.position(2).is_none());
+        assert_eq!(clearing.accounts.account(2).unwrap().balance, dec!(4795));
+        assert!(clearing.reconcile(1));
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_adl_ranking_by_pnl_and_leverage() {
+        let mut clearing = funded(&[
+            (1, dec!(100000)),
+            (3, dec!(5000)),
+            (4, dec!(5000)),
+            (5, dec!(5000)),
+        ]);
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(1000), dec!(30))
+            .unwrap();
+        for (trader_id, leverage) in [(3, dec!(5)), (4, dec!(20)), (5, dec!(10))] {
+            clearing.set_leverage(trader_id, leverage).unwrap();
+            clearing
+                .submit_order(trader_id, MARKET, Side::Sell, dec!(1000), dec!(10))
+                .unwrap();
+        }
+
+        clearing.update_mark_price(MARKET, dec!(950)).unwrap();
+        let ranking = clearing.adl_ranking(MARKET).unwrap();
+        let shorts: Vec<(u64, u8)> = ranking
+            .iter()
+            .filter(|rank| rank.side == PositionSide::Short)
+            .map(|rank| (rank.trader_id, rank.quantile))
+            .collect();
+        assert_eq!(shorts, vec![(4, 5), (5, 4), (3, 2)]);
+        assert_eq!(clearing.adl_quantile(1, MARKET).unwrap(), 0);
+
+        clearing.update_mark_price(MARKET, dec!(1040)).unwrap();
+        assert_eq!(clearing.adl_quantile(1, MARKET).unwrap(), 5);
+        assert_eq!(clearing.adl_quantile(4, MARKET).unwrap(), 0);
+        assert!(clearing.adl_quantile(9, MARKET).is_err());
+    }
+
+    fn socialized_setup(mode: SocializedLoss) -> ClearingHouse {
+        let mut clearing = leveraged_long();
+        clearing.insurance_fund = InsuranceFund::new(dec!(50));
+        clearing.liquidation_engine.adl_enabled = false;
+        clearing.liquidation_engine.socialized_loss = mode;
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(970), dec!(10))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(975)).unwrap();
+        clearing.advance_time(500);
+        clearing
+    }
+
+    #[test]
+    fn test_shortfall_socialized_across_profitable_positions() {
+        let mut clearing = socialized_setup(SocializedLoss::ProfitablePositions);
+        let outcome = clearing.liquidate(2, MARKET).unwrap();
+        assert_eq!(outcome.socialized_loss, dec!(100));
+        assert_eq!(outcome.uncovered_loss, dec!(0));
+
+        // Trader 1 is up 250 on the short, trader 3 up 50 on the long just bought at 970
+        let charged = |trader_id| -> Decimal {
+            clearing
+                .accounts
+                .entries_for(trader_id)
+                .filter(|e| e.kind == LedgerEntryKind::SocializedLoss)
+                .map(|e| e.amount)
+                .sum()
+        };
+        assert_eq!(charged(1), dec!(-100) * dec!(250) / dec!(300));
+        assert_eq!(charged(1) + charged(3), dec!(-100));
+        assert_eq!(clearing.accounts.account(2).unwrap().balance, dec!(4795));
+
+        let shortfall = clearing.insurance_fund.history().last().unwrap();
+        assert_eq!(shortfall.kind, FundEventKind::Shortfall);
+        assert_eq!(shortfall.timestamp, 500);
+        for trader_id in [1, 2, 3] {
+            assert!(clearing.reconcile(trader_id));
+        }
+    }
+
+    #[test]
+    fn test_shortfall_socialized_by_open_interest() {
+        let mut clearing = socialized_setup(SocializedLoss::OpenInterest);
+        let margin = |clearing: &ClearingHouse, trader_id| {
+            clearing
+                .market(MARKET)
+                .unwrap()
+                .position(trader_id)
+                .unwrap()
+                .margin
+        };
+        let before = margin(&clearing, 1);
+        clearing.liquidate(2, MARKET).unwrap();
+
+        // Both remaining positions are 10 contracts, so the 100 shortfall splits evenly.
+        // Trader 3 bought at 970 with 970 + 4.85 reserved, plus a 0.97 maker rebate.
+        assert_eq!(margin(&clearing, 1), before - dec!(50));
+        assert_eq!(margin(&clearing, 3), dec!(975.82) - dec!(50));
+    }
+
+    #[test]
+    fn test_socialized_share_capped_at_position_margin() {
+        let mut clearing = socialized_setup(SocializedLoss::OpenInterest);
+        let margin = |clearing: &ClearingHouse, trader_id| {
+            clearing
+                .market(MARKET)
+                .unwrap()
+                .position(trader_id)
+                .unwrap()
+                .margin
+        };
+        clearing
+            .market_mut(MARKET)
+            .unwrap()
+            .position_manager
+            .positions
+            .get_mut(&1)
+            .unwrap()
+            .margin = dec!(20);
+
+        // Trader 1 can only absorb 20 of its even 50 share; trader 3 picks up the rest
+        let outcome = clearing.liquidate(2, MARKET).unwrap();
+        assert_eq!(outcome.socialized_loss, dec!(100));
+        assert_eq!(outcome.uncovered_loss, dec!(0));
+        assert_eq!(margin(&clearing, 1), dec!(0));
+        assert_eq!(margin(&clearing, 3), dec!(975.82) - dec!(80));
+    }
+
+    #[test]
+    fn test_fees_beyond_fund_target_go_to_treasury() {
+        let mut clearing = funded(&[(1, dec!(5000)), (2, dec!(5000))]);
+        clearing.insurance_fund = InsuranceFund::new(dec!(0));
+        clearing.insurance_fund.target_ratio = dec!(0.0001);
+        clearing.advance_time(60);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+
+        // Net fees of 4 against a target of 0.01% of 20000 in positions
+        assert_eq!(clearing.insurance_fund.balance, dec!(2));
+        assert_eq!(clearing.treasury, dec!(2));
+        assert_eq!(
+            clearing.insurance_fund.history(),
+            &[FundEvent {
+                timestamp: 60,
+                kind: FundEventKind::Contribution,
+                amount: dec!(2),
+                balance_after: dec!(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_required_margin_tops_up_into_higher_bracket() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(10000))]);
+        clearing.set_leverage(2, dec!(100)).unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(60))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(40))
+            .unwrap();
+        assert_eq!(
+            clearing.market(MARKET).unwrap().position(2).unwrap().margin,
+            dec!(400)
+        );
+
+        // 60k total needs 2% (1200): 400 for the new 20k plus a 400 top-up, plus the taker fee
+        assert_eq!(
+            clearing
+                .required_margin(2, MARKET, Side::Buy, dec!(1000), dec!(20))
+                .unwrap(),
+            dec!(810)
+        );
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(20))
+            .unwrap();
+        let position = clearing.market(MARKET).unwrap().position(2).unwrap();
+        assert_eq!(position.size, dec!(60));
+        assert_eq!(position.leverage, dec!(50));
+    }
+
+    #[test]
+    fn test_resting_orders_settle_across_bracket_boundary() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000))]);
+        clearing.set_leverage(1, dec!(100)).unwrap();
+        // Together the orders cross into the 2% bracket, so the second one reserves the top-up
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(40))
+            .unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(20))
+            .unwrap();
+
+        let outcome = clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(60))
+            .unwrap();
+        assert_eq!(outcome.fills.len(), 2);
+
+        let maker = clearing.market(MARKET).unwrap().position(1).unwrap();
+        assert_eq!(maker.size, dec!(60));
+        // 1200 plus the reserved taker fees and the maker rebate
+        assert_eq!(maker.margin, dec!(1216));
+        assert_eq!(clearing.accounts.account(1).unwrap().order_margin, dec!(0));
+    }
+
+    #[test]
+    fn test_funding_accrues_to_index_and_settles_on_touch() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        let long_balance = clearing.accounts.account(2).unwrap().balance;
+        let short_balance = clearing.accounts.account(1).unwrap().balance;
+        // The predicted rate sees fills before any settlement refreshes it
+        let funding = &clearing.market(MARKET).unwrap().funding;
+        assert_eq!(funding.long_open_interest, dec!(10));
+        assert_eq!(funding.short_open_interest, dec!(10));
+
+        clearing.update_mark_price(MARKET, dec!(1100)).unwrap();
+        clearing
+            .market_mut(MARKET)
+            .unwrap()
+            .funding
+            .add_price_sample(dec!(1001), dec!(1000), dec!(0.001), 0);
+        clearing.advance_time(28800);
+        assert_eq!(clearing.apply_funding(MARKET).unwrap(), dec!(1.1));
+        let funding = &clearing.market(MARKET).unwrap().funding;
+        assert_eq!(funding.history().len(), 1);
+        assert_eq!(funding.history()[0].rate, dec!(0.001));
+        assert_eq!(funding.next_funding_time, 57600);
+
+        // Charged on notional at mark: 10 * 1100 * 0.001
+        assert_eq!(clearing.accounts.account(2).unwrap().balance, long_balance);
+        assert_eq!(clearing.pending_funding(2), dec!(-11));
+
+        assert_eq!(clearing.settle_funding(2, MARKET).unwrap(), dec!(-11));
+        assert_eq!(
+            clearing.accounts.account(2).unwrap().balance,
+            long_balance - dec!(11)
+        );
+        assert_eq!(clearing.settle_funding(2, MARKET).unwrap(), dec!(0));
+
+        let margin = clearing.market(MARKET).unwrap().position(1).unwrap().margin;
+        let position = clearing.adjust_margin(1, MARKET, dec!(5)).unwrap();
+        assert_eq!(position.margin, margin + dec!(11) + dec!(5));
+        assert_eq!(
+            clearing.accounts.account(1).unwrap().balance,
+            short_balance + dec!(11)
+        );
+        assert!(clearing.reconcile(1));
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_stale_oracle_enters_safe_mode() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(100000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(1000)).unwrap();
+
+        let oracle = oracle_at(dec!(1000), 0, dec!(1));
+        assert_eq!(clearing.check_oracle(MARKET, &oracle).unwrap(), None);
+
+        // The feed froze at t=0
+        clearing.advance_time(61);
+        let event = clearing.check_oracle(MARKET, &oracle).unwrap().unwrap();
+        assert_eq!(event.kind, SafeModeEventKind::Entered(OracleStatus::Stale));
+        assert_eq!(clearing.check_oracle(MARKET, &oracle).unwrap(), None);
+
+        clearing
+            .market_mut(MARKET)
+            .unwrap()
+            .funding
+            .add_price_sample(dec!(1010), dec!(1000), dec!(0.01), 0);
+        assert_eq!(clearing.apply_funding(MARKET).unwrap(), dec!(0));
+        assert!(clearing
+            .market(MARKET)
+            .unwrap()
+            .funding
+            .history()
+            .is_empty());
+
+        assert_eq!(
+            clearing.submit_order(3, MARKET, Side::Buy, dec!(1000), dec!(1)),
+            Err(OrderBookError::StaleOracle { market_id: MARKET })
+        );
+        assert!(clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1010), dec!(5))
+            .is_ok());
+        assert_eq!(
+            clearing.liquidate(2, MARKET),
+            Err(OrderBookError::StaleOracle { market_id: MARKET })
+        );
+
+        let oracle = oracle_at(dec!(1000), 61, dec!(1));
+        let event = clearing.check_oracle(MARKET, &oracle).unwrap().unwrap();
+        assert_eq!(event.kind, SafeModeEventKind::Exited);
+        assert_eq!(clearing.safe_mode_events().len(), 2);
+        assert!(clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(1000), dec!(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_low_confidence_liquidates_at_fallback_mark() {
+        let mut clearing = leveraged_long();
+        clearing.liquidation_engine.safe_mode_liquidations = SafeModeLiquidations::FallbackMark;
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(985), dec!(100))
+            .unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(987), dec!(1))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(1000)).unwrap();
+        assert!(clearing.run_liquidations().unwrap().is_empty());
+
+        let oracle = oracle_at(dec!(1000), 0, dec!(0.3));
+        let event = clearing.check_oracle(MARKET, &oracle).unwrap().unwrap();
+        assert_eq!(
+            event.kind,
+            SafeModeEventKind::Entered(OracleStatus::LowConfidence)
+        );
+
+        let outcomes = clearing.run_liquidations().unwrap();
+        assert_eq!(clearing.market(MARKET).unwrap().mark_price, Some(dec!(986)));
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].trader_id, 2);
+        assert_eq!(outcomes[0].quantity, dec!(6));
+    }
+
+    #[test]
+    fn test_reduce_only_sized_to_position_and_resized_as_it_shrinks() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(100000))]);
+        assert_eq!(
+            clearing.submit_reduce_only_order(2, MARKET, Side::Sell, dec!(1100), dec!(1)),
+            Err(OrderBookError::ReduceOnlyRejected {
+                trader_id: 2,
+                market_id: MARKET
+            })
+        );
+
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        assert!(clearing
+            .submit_reduce_only_order(2, MARKET, Side::Buy, dec!(900), dec!(1))
+            .is_err());
+
+        let outcome = clearing
+            .submit_reduce_only_order(2, MARKET, Side::Sell, dec!(1100), dec!(15))
+            .unwrap();
+        assert_eq!(outcome.resting_quantity, dec!(10));
+        // Only the fee is reserved
+        assert_eq!(
+            clearing.accounts.account(2).unwrap().order_margin,
+            dec!(5.5)
+        );
+
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(990), dec!(4))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(990), dec!(4))
+            .unwrap();
+        let market = clearing.market(MARKET).unwrap();
+        assert_eq!(market.order_book.best_sell(), Some((dec!(1100), dec!(6))));
+        assert_eq!(
+            clearing.accounts.account(2).unwrap().order_margin,
+            dec!(3.3)
+        );
+
+        // A second reduce-only order only gets what the first leaves over
+        let outcome = clearing
+            .submit_reduce_only_order(2, MARKET, Side::Sell, dec!(1050), dec!(2))
+            .unwrap();
+        assert_eq!(outcome.resting_quantity, dec!(0));
+        assert!(clearing
+            .market(MARKET)
+            .unwrap()
+            .order_book
+            .order(outcome.order_id)
+            .is_none());
+    }
+
+    #[test]
+    fn test_reduce_only_capped_at_match_time() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(100000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+
+        let reduce_only = clearing
+            .submit_reduce_only_order(2, MARKET, Side::Sell, dec!(1010), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1000), dec!(5))
+            .unwrap();
+
+        // The plain sell fills first, so only half the reduce-only order may still trade
+        let outcome = clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(1010), dec!(15))
+            .unwrap();
+        assert_eq!(outcome.fills.len(), 2);
+        assert_eq!(outcome.fills[1].trade.maker_id, reduce_only.order_id);
+        assert_eq!(outcome.fills[1].trade.quantity, dec!(5));
+        assert_eq!(outcome.resting_quantity, dec!(5));
+
+        let market = clearing.market(MARKET).unwrap();
+        assert!(market.position(2).is_none());
+        assert!(market.order_book.order(reduce_only.order_id).is_none());
+        assert_eq!(clearing.accounts.account(2).unwrap().order_margin, dec!(0));
+    }
+
+    #[test]
+    fn test_open_orders_tracked_per_trader_through_fills_and_cancels() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000))]);
+        clearing.add_market(Market::new(2)).unwrap();
+        let first = clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(5))
+            .unwrap();
+        let second = clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1010), dec!(5))
+            .unwrap();
+        clearing
+            .submit_order(1, 2, Side::Buy, dec!(100), dec!(5))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(900), dec!(1))
+            .unwrap();
+        assert_eq!(clearing.open_order_count(1), 3);
+        assert_eq!(clearing.open_order_count(2), 1);
+
+        // A partial fill leaves the order open; a full one takes it off the index
+        clearing
+            .submit_ioc_order(2, MARKET, Side::Buy, dec!(1000), dec!(3))
+            .unwrap();
+        assert_eq!(clearing.open_order_count(1), 3);
+        clearing
+            .submit_ioc_order(2, MARKET, Side::Buy, dec!(1000), dec!(2))
+            .unwrap();
+        assert_eq!(clearing.open_order_count(1), 2);
+        assert!(clearing.cancel_order(1, MARKET, first.order_id).is_err());
+
+        clearing.cancel_order(1, MARKET, second.order_id).unwrap();
+        assert_eq!(clearing.open_order_count(1), 1);
+        let market = clearing.market(MARKET).unwrap();
+        assert_eq!(market.orders_for(1).count(), 0);
+        assert_eq!(market.orders_for(2).count(), 1);
+    }
+
+    #[test]
+    fn test_ioc_orders_bypass_open_order_cap() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(100000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .set_risk_limits(
+                2,
+                RiskLimits {
+                    max_open_orders: 1,
+                    ..RiskLimits::new()
+                },
+            )
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(900), dec!(1))
+            .unwrap();
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(990), dec!(10))
+            .unwrap();
+
+        // At the cap, another resting order is refused but a reduce-only IOC can still flatten
+        assert!(matches!(
+            clearing.submit_reduce_only_order(2, MARKET, Side::Sell, dec!(990), dec!(10)),
+            Err(OrderBookError::TooManyOpenOrders { .. })
+        ));
+        let outcome = clearing
+            .submit_reduce_only_ioc_order(2, MARKET, Side::Sell, dec!(980), dec!(12))
+            .unwrap();
+        assert_eq!(outcome.fills.len(), 1);
+        assert_eq!(outcome.resting_quantity, dec!(0));
+        assert!(clearing.market(MARKET).unwrap().position(2).is_none());
+        assert_eq!(clearing.open_order_count(2), 1);
+
+        // An IOC that finds nothing to match is simply dropped
+        let outcome = clearing
+            .submit_ioc_order(2, MARKET, Side::Sell, dec!(1200), dec!(1))
+            .unwrap();
+        assert!(outcome.fills.is_empty());
+        assert!(clearing
+            .market(MARKET)
+            .unwrap()
+            .order_book
+            .order(outcome.order_id)
+            .is_none());
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_untracked_maker_is_cancelled_instead_of_failing_settlement() {
+        let mut clearing = funded(&[(1, dec!(10000)), (2, dec!(10000)), (3, dec!(10000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(1))
+            .unwrap();
+        let orphan = clearing
+            .submit_order(3, MARKET, Side::Sell, dec!(1001), dec!(1))
+            .unwrap();
+        let stale = clearing
+            .market_mut(MARKET)
+            .unwrap()
+            .untrack_order(orphan.order_id)
+            .unwrap();
+        clearing.accounts.account_mut(3).unwrap().order_margin -= stale.margin_per_unit;
+
+        // The second maker can't settle, so it leaves the book without a trade
+        let outcome = clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1001), dec!(2))
+            .unwrap();
+        assert_eq!(outcome.fills.len(), 1);
+        assert_eq!(outcome.fills[0].maker_trader_id, 1);
+        assert_eq!(outcome.resting_quantity, dec!(1));
+        assert_eq!(outcome.untracked_makers, vec![orphan.order_id]);
+
+        let market = clearing.market(MARKET).unwrap();
+        assert!(market.order_book.order(orphan.order_id).is_none());
+        assert!(market.position(3).is_none());
+        assert_eq!(market.position(2).unwrap().size, dec!(1));
+        assert_eq!(market.position(1).unwrap().size, dec!(1));
+        assert_eq!(market.order_book.best_buy(), Some((dec!(1001), dec!(1))));
+        for trader_id in 1..=3 {
+            assert!(clearing.reconcile(trader_id));
+        }
+    }
+
+    #[test]
+    fn test_pre_trade_limits_count_open_orders() {
+        let mut clearing = funded(&[(1, dec!(100000))]);
+        clearing
+            .market_mut(MARKET)
+            .unwrap()
+            .position_manager
+            .max_position_size = dec!(50);
+        clearing
+            .set_risk_limits(
+                1,
+                RiskLimits {
+                    max_open_orders: 3,
+                    max_order_notional: dec!(40000),
+                },
+            )
+            .unwrap();
+
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(900), dec!(30))
+            .unwrap();
+        // Resting bids count towards the worst-case long; asks don't
+        assert_eq!(
+            clearing.submit_order(1, MARKET, Side::Buy, dec!(910), dec!(25)),
+            Err(OrderBookError::PositionSizeExceeded {
+                size: dec!(55),
+                limit: dec!(50)
+            })
+        );
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1100), dec!(25))
+            .unwrap();
+        assert_eq!(
+            clearing.submit_order(1, MARKET, Side::Sell, dec!(1100), dec!(40)),
+            Err(OrderBookError::OrderNotionalExceeded {
+                notional: dec!(44000),
+                limit: dec!(40000)
+            })
+        );
+
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(910), dec!(20))
+            .unwrap();
+        assert_eq!(clearing.open_order_count(1), 3);
+        assert_eq!(
+            clearing.submit_order(1, MARKET, Side::Sell, dec!(1200), dec!(1)),
+            Err(OrderBookError::TooManyOpenOrders {
+                open_orders: 3,
+                limit: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_open_interest_cap_checked_before_matching() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(100000))]);
+        clearing
+            .market_mut(MARKET)
+            .unwrap()
+            .position_manager
+            .open_interest_caps
+            .max_total = Some(dec!(20));
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(15))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(15))
+            .unwrap();
+
+        assert_eq!(
+            clearing.submit_order(3, MARKET, Side::Buy, dec!(1000), dec!(10)),
+            Err(OrderBookError::OpenInterestCapExceeded {
+                open_interest: dec!(25),
+                cap: dec!(20)
+            })
+        );
+        // Buying back part of a short only shrinks open interest
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(990), dec!(10))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_volume_tiers_and_referral_split() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(1000))]);
+        clearing.fee_structure = FeeStructure::with_tiers(vec![FeeTier {
+            min_volume: dec!(10000),
+            rates: FeeRates::new(dec!(-0.0002), dec!(0.0003)).unwrap(),
+        }])
+        .unwrap();
+        clearing.set_referrer(2, 3).unwrap();
+        assert!(clearing.set_referrer(2, 2).is_err());
+
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        let outcome = clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        assert_eq!(outcome.fills[0].taker_fee(), dec!(5));
+        // 10% of the referred taker fee goes to the referrer
+        assert_eq!(clearing.accounts.account(3).unwrap().balance, dec!(1000.5));
+        assert_eq!(clearing.fees_collected, dec!(3.5));
+
+        // 10k of trailing volume reaches the first tier for both sides
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(1000), dec!(1))
+            .unwrap();
+        let outcome = clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1000), dec!(1))
+            .unwrap();
+        assert_eq!(outcome.fills[0].maker_fee(), dec!(-0.2));
+        assert_eq!(outcome.fills[0].taker_fee(), dec!(0.3));
+
+        // The volume window rolls off
+        clearing.advance_time(31 * 86400);
+        assert_eq!(clearing.fee_rates(2).taker_fee(), dec!(0.0005));
+        assert!(clearing.reconcile(3));
+    }
+
+    #[test]
+    fn test_order_entry_rejections_are_distinct() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000))]);
+        let market = clearing.market_mut(MARKET).unwrap();
+        assert!(market.set_tick_size(Some(Decimal::ZERO)).is_err());
+        market.set_tick_size(Some(dec!(0.5))).unwrap();
+        assert_eq!(
+            clearing.submit_order(1, MARKET, Side::Buy, dec!(1000.25), dec!(1)),
+            Err(OrderBookError::OffTick {
+                price: dec!(1000.25),
+                tick_size: dec!(0.5)
+            })
+        );
+
+        let ask = clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1001), dec!(5))
+            .unwrap();
+        let result = clearing.submit_order(1, MARKET, Side::Buy, dec!(1001.5), dec!(1));
+        assert_eq!(
+            result,
+            Err(OrderBookError::SelfTrade {
+                trader_id: 1,
+                order_id: ask.order_id
+            })
+        );
+        assert_eq!(result.unwrap_err().code(), "SELF_TRADE");
+        // Bids below the trader's own ask don't reach it
+        assert!(clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(999), dec!(1))
+            .is_ok());
+
+        assert_eq!(
+            clearing.submit_post_only_order(2, MARKET, Side::Buy, dec!(1001), dec!(1)),
+            Err(OrderBookError::PostOnlyWouldCross {
+                price: dec!(1001),
+                best_price: dec!(1001)
+            })
+        );
+        let resting = clearing
+            .submit_post_only_order(2, MARKET, Side::Buy, dec!(1000.5), dec!(1))
+            .unwrap();
+        assert!(resting.fills.is_empty());
+
+        clearing.market_mut(MARKET).unwrap().halted = true;
+        assert_eq!(
+            clearing.submit_order(2, MARKET, Side::Buy, dec!(1001), dec!(1)),
+            Err(OrderBookError::MarketHalted { market_id: MARKET })
+        );
+    }
+}
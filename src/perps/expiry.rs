@@ -0,0 +1,559 @@
+use super::account::{LedgerEntryKind, MarginMode};
+use super::clearing::{ClearingHouse, MarketId, RestingOrder};
+use super::{LiquidationEngine, OraclePrice, Position, PositionSide};
+use crate::error::{OrderBookError, Result};
+use crate::types::Side;
+use rust_decimal::Decimal;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContractKind {
+    Perpetual,
+    // Settles at the mean of the last `settlement_periods` index samples taken by expiry;
+    // never pays funding
+    Dated {
+        expiry: u64,
+        settlement_periods: usize,
+    },
+}
+
+impl ContractKind {
+    pub fn expiry(&self) -> Option<u64> {
+        match self {
+            ContractKind::Perpetual => None,
+            ContractKind::Dated { expiry, .. } => Some(*expiry),
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expiry().is_some_and(|expiry| now >= expiry)
+    }
+
+    pub fn has_funding(&self) -> bool {
+        matches!(self, ContractKind::Perpetual)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettledPosition {
+    pub trader_id: u64,
+    pub side: PositionSide,
+    pub size: Decimal,
+    pub entry_price: Decimal,
+    pub realized_pnl: Decimal,
+    pub insurance_payout: Decimal,
+    pub socialized_loss: Decimal,
+    pub uncovered_loss: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelledOrder {
+    pub order_id: u64,
+    pub trader_id: u64,
+    pub side: Side,
+    pub price: Decimal,
+    pub quantity: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpirySettlement {
+    pub market_id: MarketId,
+    pub timestamp: u64,
+    pub settlement_price: Decimal,
+    pub positions: Vec<SettledPosition>,
+    pub cancelled_orders: Vec<CancelledOrder>,
+}
+
+impl ClearingHouse {
+    // Closes out a dated market once it has expired: resting orders are cancelled and every
+    // position settles at the index TWAP, with losses past the margin drawn from the fund and
+    // then socialized
+    pub fn settle_expiry(
+        &mut self,
+        market_id: MarketId,
+        oracle: &OraclePrice,
+    ) -> Result<ExpirySettlement> {
+        let market = self.market(market_id)?;
+        let ContractKind::Dated {
+            expiry,
+            settlement_periods,
+        } = market.contract
+        else {
+            return Err(OrderBookError::MarketNotExpired { market_id });
+        };
+        if !market.contract.is_expired(self.timestamp) {
+            return Err(OrderBookError::MarketNotExpired { market_id });
+        }
+        if market.settlement_price.is_some() {
+            return Err(OrderBookError::MarketExpired { market_id });
+        }
+
+        // Samples posted after expiry must not move the settlement price
+        let settlement_price = oracle
+            .get_twap_until(settlement_periods, expiry)
+            .ok_or(OrderBookError::StaleOracle { market_id })?;
+        let mut open_orders: Vec<(u64, RestingOrder)> = market
+            .resting_orders
+            .iter()
+            .map(|(&order_id, &resting)| (order_id, resting))
+            .collect();
+        open_orders.sort_unstable_by_key(|&(order_id, _)| order_id);
+
+        let mut cancelled_orders = Vec::with_capacity(open_orders.len());
+        for (order_id, resting) in open_orders {
+            let quantity = self
+                .market(market_id)?
+                .order_book
+                .order(order_id)
+                .map_or(Decimal::ZERO, |order| order.quantity);
+            self.cancel_order(resting.trader_id, market_id, order_id)?;
+            cancelled_orders.push(CancelledOrder {
+                order_id,
+                trader_id: resting.trader_id,
+                side: resting.side,
+                price: resting.price,
+                quantity,
+            });
+        }
+
+        let market = self.market_mut(market_id)?;
+        market.order_book.clear();
+        market.triggers.clear();
+        market.settlement_price = Some(settlement_price);
+        market.mark_price = Some(settlement_price);
+        let mut open_positions: Vec<Position> = market
+            .position_manager
+            .positions
+            .values()
+            .cloned()
+            .collect();
+        open_positions.sort_unstable_by_key(|position| position.trader_id);
+
+        // Losers settle first so what they cannot pay is known before any winner is paid
+        let (losers, winners): (Vec<Position>, Vec<Position>) =
+            open_positions.into_iter().partition(|position| {
+                LiquidationEngine::calculate_pnl(position, settlement_price) < Decimal::ZERO
+            });
+
+        let mut positions = Vec::with_capacity(losers.len() + winners.len());
+        for loser in losers {
+            // Socializing an earlier loser's shortfall may already have charged this one's margin
+            let trader_id = loser.trader_id;
+            let position = self
+                .market(market_id)?
+                .position(trader_id)
+                .cloned()
+                .ok_or(OrderBookError::PositionNotFound { trader_id })?;
+            let realized_pnl = self.close_at_settlement(market_id, &position, settlement_price)?;
+            let shortfall = match self.margin_mode(trader_id) {
+                MarginMode::Isolated => -(position.margin + realized_pnl),
+                MarginMode::Cross => -self.accounts.account(trader_id)?.balance,
+            }
+            .max(Decimal::ZERO);
+
+            let mut insurance_payout = Decimal::ZERO;
+            let mut socialized_loss = Decimal::ZERO;
+            if shortfall > Decimal::ZERO {
+                if self
+                    .insurance_fund
+                    .process_payout(shortfall, self.timestamp)?
+                {
+                    self.accounts
+                        .post(trader_id, LedgerEntryKind::InsurancePayout, shortfall)?;
+                    insurance_payout = shortfall;
+                } else {
+                    self.insurance_fund
+                        .record_shortfall(shortfall, self.timestamp)?;
+                    socialized_loss = self.socialize_loss(trader_id, market_id, shortfall)?;
+                }
+            }
+
+            positions.push(SettledPosition {
+                trader_id,
+                side: position.side,
+                size: position.size,
+                entry_price: position.entry_price,
+                realized_pnl,
+                insurance_payout,
+                socialized_loss,
+                uncovered_loss: shortfall - insurance_payout - socialized_loss,
+            });
+        }
+
+        // Nobody is left holding the market once it settles, so whatever the fund and
+        // socialization could not place is cut from the winners' payouts pro rata
+        let winnings: Decimal = winners
+            .iter()
+            .map(|position| LiquidationEngine::calculate_pnl(position, settlement_price))
+            .sum();
+        let haircut = positions
+            .iter()
+            .map(|settled| settled.uncovered_loss)
+            .sum::<Decimal>()
+            .min(winnings);
+        let mut cut = Decimal::ZERO;
+        let winner_count = winners.len();
+        for (index, position) in winners.into_iter().enumerate() {
+            let trader_id = position.trader_id;
+            let realized_pnl = self.close_at_settlement(market_id, &position, settlement_price)?;
+            // The last share absorbs rounding so the whole haircut is taken
+            let share = if haircut.is_zero() {
+                Decimal::ZERO
+            } else if index + 1 == winner_count {
+                haircut - cut
+            } else {
+                haircut * realized_pnl / winnings
+            };
+            if !share.is_zero() {
+                self.accounts
+                    .post(trader_id, LedgerEntryKind::SocializedLoss, -share)?;
+                cut += share;
+            }
+
+            positions.push(SettledPosition {
+                trader_id,
+                side: position.side,
+                size: position.size,
+                entry_price: position.entry_price,
+                realized_pnl,
+                insurance_payout: Decimal::ZERO,
+                socialized_loss: Decimal::ZERO,
+                uncovered_loss: Decimal::ZERO,
+            });
+        }
+
+        // Losers are credited with everything charged to the others on their behalf
+        let mut placed = haircut;
+        for settled in positions.iter_mut() {
+            let from_payouts = placed.min(settled.uncovered_loss);
+            placed -= from_payouts;
+            settled.socialized_loss += from_payouts;
+            settled.uncovered_loss -= from_payouts;
+            if !settled.socialized_loss.is_zero() {
+                self.accounts.post(
+                    settled.trader_id,
+                    LedgerEntryKind::SocializedLoss,
+                    settled.socialized_loss,
+                )?;
+            }
+        }
+        positions.sort_unstable_by_key(|settled| settled.trader_id);
+
+        Ok(ExpirySettlement {
+            market_id,
+            timestamp: self.timestamp,
+            settlement_price,
+            positions,
+            cancelled_orders,
+        })
+    }
+
+    // Closes a position at the settlement price and posts the PnL it realizes
+    fn close_at_settlement(
+        &mut self,
+        market_id: MarketId,
+        position: &Position,
+        settlement_price: Decimal,
+    ) -> Result<Decimal> {
+        self.market_mut(market_id)?
+            .position_manager
+            .close_position(position.trader_id)?;
+        let realized_pnl = LiquidationEngine::calculate_pnl(position, settlement_price);
+        if !realized_pnl.is_zero() {
+            self.accounts.post(
+                position.trader_id,
+                LedgerEntryKind::RealizedPnl,
+                realized_pnl,
+            )?;
+        }
+        Ok(realized_pnl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perps::clearing::fixtures::{funded, oracle_at, MARKET};
+    use crate::perps::{AggregatedPrice, InsuranceFund, Market, SocializedLoss};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_only_dated_contracts_expire() {
+        let perpetual = ContractKind::Perpetual;
+        assert!(!perpetual.is_expired(u64::MAX));
+        assert!(perpetual.has_funding());
+
+        let quarterly = ContractKind::Dated {
+            expiry: 7_776_000,
+            settlement_periods: 30,
+        };
+        assert_eq!(quarterly.expiry(), Some(7_776_000));
+        assert!(!quarterly.is_expired(7_775_999));
+        assert!(quarterly.is_expired(7_776_000));
+        assert!(!quarterly.has_funding());
+    }
+
+    #[test]
+    fn test_dated_market_settles_at_index_twap() {
+        const QUARTERLY: MarketId = 2;
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(100000))]);
+        clearing
+            .add_market(Market::dated(QUARTERLY, 1000, 3))
+            .unwrap();
+        clearing
+            .submit_order(1, QUARTERLY, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, QUARTERLY, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        let resting = clearing
+            .submit_order(3, QUARTERLY, Side::Buy, dec!(950), dec!(4))
+            .unwrap();
+        clearing.update_mark_price(QUARTERLY, dec!(1000)).unwrap();
+        assert_eq!(clearing.apply_funding(QUARTERLY).unwrap(), dec!(0));
+
+        let mut oracle = oracle_at(dec!(1000), 900, Decimal::ONE);
+        for (price, timestamp) in [(dec!(1040), 950), (dec!(1050), 990), (dec!(1060), 1000)] {
+            oracle
+                .update(&AggregatedPrice {
+                    price,
+                    timestamp,
+                    confidence: Decimal::ONE,
+                    sources: vec!["a".to_string()],
+                    dropped: Vec::new(),
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            clearing.settle_expiry(QUARTERLY, &oracle),
+            Err(OrderBookError::MarketNotExpired {
+                market_id: QUARTERLY
+            })
+        );
+
+        clearing.advance_time(1000);
+        assert_eq!(
+            clearing.submit_order(3, QUARTERLY, Side::Buy, dec!(1000), dec!(1)),
+            Err(OrderBookError::MarketExpired {
+                market_id: QUARTERLY
+            })
+        );
+
+        // A sample posted after expiry is left out of the settlement TWAP
+        oracle
+            .update(&AggregatedPrice {
+                price: dec!(1300),
+                timestamp: 1020,
+                confidence: Decimal::ONE,
+                sources: vec!["a".to_string()],
+                dropped: Vec::new(),
+            })
+            .unwrap();
+        let settlement = clearing.settle_expiry(QUARTERLY, &oracle).unwrap();
+        assert_eq!(settlement.settlement_price, dec!(1050));
+        assert_eq!(settlement.cancelled_orders.len(), 1);
+        assert_eq!(settlement.cancelled_orders[0].order_id, resting.order_id);
+        assert_eq!(settlement.cancelled_orders[0].quantity, dec!(4));
+        let pnl: Vec<(u64, Decimal)> = settlement
+            .positions
+            .iter()
+            .map(|settled| (settled.trader_id, settled.realized_pnl))
+            .collect();
+        assert_eq!(pnl, vec![(1, dec!(-500)), (2, dec!(500))]);
+
+        let market = clearing.market(QUARTERLY).unwrap();
+        assert!(market.order_book.is_empty());
+        assert!(market.position_manager.positions.is_empty());
+        assert_eq!(clearing.accounts.account(3).unwrap().order_margin, dec!(0));
+        assert!(clearing.settle_expiry(QUARTERLY, &oracle).is_err());
+        assert!(clearing.settle_expiry(MARKET, &oracle).is_err());
+        for trader_id in 1..=3 {
+            assert!(clearing.reconcile(trader_id));
+        }
+    }
+
+    #[test]
+    fn test_uncovered_expiry_loss_is_taken_from_winners() {
+        const QUARTERLY: MarketId = 2;
+        for mode in [
+            SocializedLoss::Disabled,
+            SocializedLoss::ProfitablePositions,
+        ] {
+            let mut clearing = funded(&[(1, dec!(1100)), (2, dec!(100000))]);
+            clearing.liquidation_engine.socialized_loss = mode;
+            clearing
+                .add_market(Market::dated(QUARTERLY, 1000, 1))
+                .unwrap();
+            clearing.set_leverage(1, dec!(10)).unwrap();
+            clearing
+                .submit_order(1, QUARTERLY, Side::Sell, dec!(1000), dec!(10))
+                .unwrap();
+            clearing
+                .submit_order(2, QUARTERLY, Side::Buy, dec!(1000), dec!(10))
+                .unwrap();
+
+            clearing.insurance_fund = InsuranceFund::new(dec!(0));
+            let margin = clearing
+                .market(QUARTERLY)
+                .unwrap()
+                .position(1)
+                .unwrap()
+                .margin;
+            let balances = |clearing: &ClearingHouse| {
+                [1, 2].map(|trader_id| clearing.accounts.account(trader_id).unwrap().balance)
+            };
+            let before = balances(&clearing);
+
+            // The short loses 1500 against a margin of about 1000 with nothing in the fund
+            clearing.advance_time(1000);
+            let settlement = clearing
+                .settle_expiry(QUARTERLY, &oracle_at(dec!(1150), 1000, Decimal::ONE))
+                .unwrap();
+            let loser = &settlement.positions[0];
+            assert_eq!(loser.realized_pnl, dec!(-1500));
+            assert_eq!(loser.socialized_loss, dec!(1500) - margin);
+            assert_eq!(loser.uncovered_loss, dec!(0));
+
+            let after = balances(&clearing);
+            assert_eq!(after[0], before[0] - margin);
+            assert_eq!(after[0] - before[0] + after[1] - before[1], dec!(0));
+            assert_eq!(clearing.insurance_fund.balance, dec!(0));
+            assert!(clearing.reconcile(1));
+            assert!(clearing.reconcile(2));
+        }
+    }
+
+    #[test]
+    fn test_losers_charged_by_socialization_settle_on_their_remaining_margin() {
+        const QUARTERLY: MarketId = 2;
+        let mut clearing = funded(&[(1, dec!(10000)), (2, dec!(10000)), (3, dec!(10000))]);
+        clearing.liquidation_engine.socialized_loss = SocializedLoss::OpenInterest;
+        clearing
+            .add_market(Market::dated(QUARTERLY, 1000, 1))
+            .unwrap();
+        clearing.set_leverage(1, dec!(20)).unwrap();
+        clearing.set_leverage(3, dec!(5)).unwrap();
+        for trader_id in [1, 2] {
+            clearing
+                .submit_order(trader_id, QUARTERLY, Side::Sell, dec!(1000), dec!(10))
+                .unwrap();
+        }
+        clearing
+            .submit_order(3, QUARTERLY, Side::Buy, dec!(1000), dec!(20))
+            .unwrap();
+        clearing.insurance_fund = InsuranceFund::new(dec!(0));
+
+        let market = clearing.market(QUARTERLY).unwrap();
+        let margins = [1, 2].map(|trader_id| market.position(trader_id).unwrap().margin);
+        let before =
+            [1, 2, 3].map(|trader_id| clearing.accounts.account(trader_id).unwrap().balance);
+        let ledger_start = clearing.accounts.ledger().len();
+
+        // Both shorts lose 1500; the first one's shortfall is spread over the second short
+        // and the long before the second settles
+        clearing.advance_time(1000);
+        let settlement = clearing
+            .settle_expiry(QUARTERLY, &oracle_at(dec!(1150), 1000, Decimal::ONE))
+            .unwrap();
+        for (settled, margin) in settlement.positions.iter().zip(margins) {
+            assert_eq!(settled.realized_pnl, dec!(-1500));
+            assert_eq!(settled.uncovered_loss, dec!(0));
+            let balance = clearing
+                .accounts
+                .account(settled.trader_id)
+                .unwrap()
+                .balance;
+            assert_eq!(balance, before[settled.trader_id as usize - 1] - margin);
+        }
+        assert!(settlement.positions[1].socialized_loss > dec!(1500) - margins[1]);
+
+        let settled_total: Decimal = clearing.accounts.ledger()[ledger_start..]
+            .iter()
+            .map(|entry| entry.amount)
+            .sum();
+        assert_eq!(settled_total, dec!(0));
+        for trader_id in 1..=3 {
+            assert!(clearing.reconcile(trader_id));
+        }
+    }
+
+    #[test]
+    fn test_insurance_fund_covers_an_isolated_shortfall() {
+        const QUARTERLY: MarketId = 2;
+        let mut clearing = funded(&[(1, dec!(700)), (2, dec!(100000))]);
+        clearing
+            .add_market(Market::dated(QUARTERLY, 1000, 1))
+            .unwrap();
+        clearing
+            .submit_order(1, QUARTERLY, Side::Sell, dec!(1200), dec!(5))
+            .unwrap();
+        clearing
+            .submit_order(2, QUARTERLY, Side::Buy, dec!(1200), dec!(5))
+            .unwrap();
+        clearing.insurance_fund = InsuranceFund::new(dec!(10000));
+        let margin = clearing
+            .market(QUARTERLY)
+            .unwrap()
+            .position(1)
+            .unwrap()
+            .margin;
+        let before =
+            [1, 2].map(|trader_id| clearing.accounts().account(trader_id).unwrap().balance);
+
+        clearing.advance_time(1000);
+        let settlement = clearing
+            .settle_expiry(QUARTERLY, &oracle_at(dec!(1400), 1000, Decimal::ONE))
+            .unwrap();
+        let loser = &settlement.positions[0];
+        assert_eq!(loser.realized_pnl, dec!(-1000));
+        assert_eq!(loser.insurance_payout, dec!(1000) - margin);
+        assert_eq!(loser.socialized_loss, dec!(0));
+        assert_eq!(clearing.insurance_fund.balance, dec!(9000) + margin);
+
+        assert_eq!(
+            clearing.accounts().account(1).unwrap().balance,
+            before[0] - margin
+        );
+        // The winner is paid in full
+        assert_eq!(
+            clearing.accounts().account(2).unwrap().balance,
+            before[1] + dec!(1000)
+        );
+        assert!(clearing.reconcile(1));
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_cross_margin_loser_pays_past_its_position_margin() {
+        const QUARTERLY: MarketId = 2;
+        let mut clearing = funded(&[(1, dec!(3000)), (2, dec!(100000))]);
+        clearing
+            .add_market(Market::dated(QUARTERLY, 1000, 1))
+            .unwrap();
+        clearing.set_margin_mode(1, MarginMode::Cross).unwrap();
+        clearing
+            .submit_order(1, QUARTERLY, Side::Sell, dec!(2000), dec!(5))
+            .unwrap();
+        clearing
+            .submit_order(2, QUARTERLY, Side::Buy, dec!(2000), dec!(5))
+            .unwrap();
+        clearing.insurance_fund = InsuranceFund::new(dec!(0));
+        let before = clearing.accounts().account(1).unwrap().balance;
+
+        // A loss of 1500 against a position margin of 1000 is still within the account
+        clearing.advance_time(1000);
+        let settlement = clearing
+            .settle_expiry(QUARTERLY, &oracle_at(dec!(2300), 1000, Decimal::ONE))
+            .unwrap();
+        let loser = &settlement.positions[0];
+        assert_eq!(loser.realized_pnl, dec!(-1500));
+        assert_eq!(loser.insurance_payout, dec!(0));
+        assert_eq!(loser.socialized_loss, dec!(0));
+        assert_eq!(
+            clearing.accounts().account(1).unwrap().balance,
+            before - dec!(1500)
+        );
+        assert_eq!(settlement.positions[1].realized_pnl, dec!(1500));
+        assert!(clearing.reconcile(1));
+        assert!(clearing.reconcile(2));
+    }
+}
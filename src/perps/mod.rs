@@ -1,6 +1,31 @@
+pub mod account;
+pub mod alert;
+pub mod clearing;
+pub mod expiry;
+pub mod oracle;
+pub mod portfolio;
+pub mod risk;
+pub mod trigger;
+
+pub use account::{Account, AccountManager, LedgerEntry, LedgerEntryKind, MarginMode};
+pub use alert::{MarginAlert, MarginAlertConfig, MarginAlertLevel};
+pub use clearing::{
+    AdlFill, AdlRank, ClearingHouse, Fill, LiquidationOutcome, Market, MarketId, OrderOutcome,
+    SafeModeEvent, SafeModeEventKind,
+};
+pub use expiry::{CancelledOrder, ContractKind, ExpirySettlement, SettledPosition};
+pub use oracle::{
+    AggregatedPrice, AggregationMethod, FileOracle, Oracle, OracleAggregator, OracleQuote,
+    OracleStatus, SimulatedOracle,
+};
+pub use portfolio::{Flows, Portfolio, PositionSummary, Statement};
+pub use risk::{OrderExposure, RiskLimits};
+pub use trigger::{PositionTrigger, TriggerKind, TriggerOutcome, TriggerReference};
+
 use crate::error::{OrderBookError, Result};
 use crate::funding::FundingRate;
-use rust_decimal::prelude::ToPrimitive;
+use crate::orderbook::OrderBook;
+use crate::types::Side;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use std::collections::{HashMap, VecDeque};
@@ -11,7 +36,26 @@ pub enum PositionSide {
     Short,
 }
 
-#[derive(Debug, Clone)]
+impl PositionSide {
+    #[inline]
+    pub fn opposite(self) -> Self {
+        match self {
+            PositionSide::Long => PositionSide::Short,
+            PositionSide::Short => PositionSide::Long,
+        }
+    }
+}
+
+impl From<Side> for PositionSide {
+    fn from(side: Side) -> Self {
+        match side {
+            Side::Buy => PositionSide::Long,
+            Side::Sell => PositionSide::Short,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Position {
     pub trader_id: u64,
     pub side: PositionSide,
@@ -22,6 +66,15 @@ pub struct Position {
     pub unrealized_pnl: Decimal,
     pub liquidation_price: Decimal,
     pub bankruptcy_price: Decimal,
+    pub funding_index: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionUpdate {
+    pub position: Option<Position>,
+    pub realized_pnl: Decimal,
+    pub released_margin: Decimal,
+    pub funding_payment: Decimal,
 }
 
 #[derive(Debug, Clone)]
@@ -30,6 +83,8 @@ pub struct OraclePrice {
     pub timestamp: u64,
     pub confidence: Decimal,
     pub source: String,
+    pub max_staleness: u64,
+    pub min_confidence: Decimal,
     price_history: VecDeque<(u64, Decimal)>,
 }
 
@@ -38,22 +93,25 @@ impl OraclePrice {
         Self {
             price,
             timestamp: 0,
-            confidence: dec!(0.99),
-            source: "Simulated".to_string(),
+            confidence: Decimal::ZERO,
+            source: String::new(),
+            max_staleness: 60,
+            min_confidence: dec!(0.5),
             price_history: VecDeque::new(),
         }
     }
 
-    pub fn update(&mut self, spot_price: Decimal) -> Result<()> {
-        let noise = (rand::random::<f64>() - 0.5) * 0.001;
-        let noise_decimal = Decimal::try_from(noise)
-            .map_err(|e| OrderBookError::OverflowError(format!("Decimal conversion: {e}")))?;
+    pub fn update(&mut self, aggregated: &AggregatedPrice) -> Result<()> {
+        if aggregated.price <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidPrice(
+                "Oracle price must be positive".to_string(),
+            ));
+        }
 
-        self.price = spot_price * (Decimal::ONE + noise_decimal);
-        self.timestamp = self
-            .timestamp
-            .checked_add(1)
-            .ok_or_else(|| OrderBookError::OverflowError("Timestamp overflow".to_string()))?;
+        self.price = aggregated.price;
+        self.timestamp = aggregated.timestamp;
+        self.confidence = aggregated.confidence;
+        self.source = aggregated.sources.join(",");
 
         self.price_history.push_back((self.timestamp, self.price));
         if self.price_history.len() > 1000 {
@@ -63,6 +121,16 @@ impl OraclePrice {
         Ok(())
     }
 
+    pub fn status(&self, now: u64) -> OracleStatus {
+        if now.saturating_sub(self.timestamp) > self.max_staleness {
+            OracleStatus::Stale
+        } else if self.confidence < self.min_confidence {
+            OracleStatus::LowConfidence
+        } else {
+            OracleStatus::Healthy
+        }
+    }
+
     pub fn get_twap(&self, lookback_periods: usize) -> Decimal {
         if self.price_history.is_empty() {
             return self.price;
@@ -82,14 +150,52 @@ impl OraclePrice {
 
         samples.iter().sum::<Decimal>() / Decimal::from(samples.len())
     }
+
+    // TWAP over the last `lookback_periods` samples observed at or before `until`
+    pub fn get_twap_until(&self, lookback_periods: usize, until: u64) -> Option<Decimal> {
+        let samples: Vec<Decimal> = self
+            .price_history
+            .iter()
+            .rev()
+            .filter(|&&(timestamp, _)| timestamp <= until)
+            .take(lookback_periods)
+            .map(|(_, p)| *p)
+            .collect();
+
+        if samples.is_empty() {
+            return None;
+        }
+
+        Some(samples.iter().sum::<Decimal>() / Decimal::from(samples.len()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkPriceMethod {
+    // (impact mid + 2 × index) / 3
+    ImpactWeighted,
+    // index + EMA(fair − index)
+    IndexPlusBasis,
+    // median of (index + basis EMA, book mid, last trade)
+    Median,
+    // fair price smoothed over time; weight halves every `half_life` seconds
+    TimeEma { half_life: u64 },
 }
 
 #[derive(Debug, Clone)]
 pub struct MarkPrice {
+    pub method: MarkPriceMethod,
     pub price: Decimal,
     pub fair_price: Decimal,
     pub index_price: Decimal,
     pub funding_basis: Decimal,
+    pub basis_alpha: Decimal,
+    pub impact_notional: Decimal,
+    pub impact_bid: Option<Decimal>,
+    pub impact_ask: Option<Decimal>,
+    pub premium: Decimal,
+    pub timestamp: u64,
+    initialized: bool,
     price_samples: VecDeque<(u64, Decimal, Decimal)>,
 }
 
@@ -101,46 +207,119 @@ impl Default for MarkPrice {
 
 impl MarkPrice {
     pub fn new() -> Self {
+        Self::with_method(MarkPriceMethod::ImpactWeighted)
+    }
+
+    pub fn with_method(method: MarkPriceMethod) -> Self {
         Self {
-            price: dec!(1000),
-            fair_price: dec!(1000),
-            index_price: dec!(1000),
+            method,
+            price: Decimal::ZERO,
+            fair_price: Decimal::ZERO,
+            index_price: Decimal::ZERO,
             funding_basis: Decimal::ZERO,
+            basis_alpha: dec!(0.1),
+            impact_notional: dec!(10000),
+            impact_bid: None,
+            impact_ask: None,
+            premium: Decimal::ZERO,
+            timestamp: 0,
+            initialized: false,
             price_samples: VecDeque::new(),
         }
     }
 
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    pub fn price_samples(&self) -> &VecDeque<(u64, Decimal, Decimal)> {
+        &self.price_samples
+    }
+
     pub fn calculate(
         &mut self,
-        best_bid: Decimal,
-        best_ask: Decimal,
+        order_book: &OrderBook,
         index_price: Decimal,
+        last_trade: Option<Decimal>,
+        timestamp: u64,
     ) -> Result<()> {
-        if best_bid <= Decimal::ZERO || best_ask <= Decimal::ZERO {
+        if index_price <= Decimal::ZERO {
             return Err(OrderBookError::InvalidPrice(
-                "Invalid bid/ask prices".to_string(),
+                "Index price must be positive".to_string(),
             ));
         }
 
-        if best_bid > best_ask {
-            return Err(OrderBookError::MarketManipulation(
-                "Crossed market detected".to_string(),
-            ));
-        }
+        let best_bid = order_book.best_buy().map(|(price, _)| price);
+        let best_ask = order_book.best_sell().map(|(price, _)| price);
+        let mid = match (best_bid, best_ask) {
+            (Some(bid), Some(ask)) if bid > ask => {
+                return Err(OrderBookError::MarketManipulation(
+                    "Crossed market detected".to_string(),
+                ));
+            }
+            (Some(bid), Some(ask)) => Some((bid + ask) / dec!(2)),
+            _ => None,
+        };
 
-        self.fair_price = (best_bid + best_ask) / dec!(2);
+        self.fair_price = mid.unwrap_or(index_price);
         self.index_price = index_price;
 
+        // The first observation seeds the EMAs instead of decaying from an arbitrary start
         let basis = self.fair_price - self.index_price;
-        self.funding_basis = self.funding_basis * dec!(0.9) + basis * dec!(0.1);
-
-        let impact_bid = best_bid * dec!(0.999);
-        let impact_ask = best_ask * dec!(1.001);
-        let impact_mid = (impact_bid + impact_ask) / dec!(2);
+        self.funding_basis = if self.initialized {
+            self.funding_basis * (Decimal::ONE - self.basis_alpha) + basis * self.basis_alpha
+        } else {
+            basis
+        };
 
-        self.price = (impact_mid + index_price * dec!(2)) / dec!(3);
+        self.impact_bid = Self::impact_price(
+            &order_book.buy_levels(order_book.buy_depth()),
+            self.impact_notional,
+        );
+        self.impact_ask = Self::impact_price(
+            &order_book.sell_levels(order_book.sell_depth()),
+            self.impact_notional,
+        );
+
+        // A side too thin to absorb the impact notional contributes no premium
+        let bid_premium = self
+            .impact_bid
+            .map_or(Decimal::ZERO, |bid| (bid - index_price).max(Decimal::ZERO));
+        let ask_discount = self
+            .impact_ask
+            .map_or(Decimal::ZERO, |ask| (index_price - ask).max(Decimal::ZERO));
+        self.premium = (bid_premium - ask_discount) / index_price;
+
+        self.price = match self.method {
+            MarkPriceMethod::ImpactWeighted => {
+                let impact_mid = (self.impact_bid.unwrap_or(index_price)
+                    + self.impact_ask.unwrap_or(index_price))
+                    / dec!(2);
+                (impact_mid + index_price * dec!(2)) / dec!(3)
+            }
+            MarkPriceMethod::IndexPlusBasis => index_price + self.funding_basis,
+            MarkPriceMethod::Median => {
+                let mut candidates = vec![index_price + self.funding_basis];
+                candidates.extend(mid);
+                candidates.extend(last_trade);
+                Self::median(&mut candidates)
+            }
+            MarkPriceMethod::TimeEma { half_life } => {
+                if self.initialized && half_life > 0 {
+                    let elapsed = timestamp.saturating_sub(self.timestamp) as f64;
+                    let decay = 0.5f64.powf(elapsed / half_life as f64);
+                    let alpha = Decimal::try_from(1.0 - decay).map_err(|e| {
+                        OrderBookError::OverflowError(format!("Decimal conversion: {e}"))
+                    })?;
+                    self.price + (self.fair_price - self.price) * alpha
+                } else {
+                    self.fair_price
+                }
+            }
+        };
 
-        let timestamp = self.price_samples.len() as u64;
+        self.initialized = true;
+        self.timestamp = timestamp;
         self.price_samples
             .push_back((timestamp, self.price, index_price));
         if self.price_samples.len() > 100 {
@@ -149,6 +328,74 @@ impl MarkPrice {
 
         Ok(())
     }
+
+    fn median(values: &mut [Decimal]) -> Decimal {
+        values.sort_unstable();
+        let middle = values.len() / 2;
+        if values.len().is_multiple_of(2) {
+            (values[middle - 1] + values[middle]) / dec!(2)
+        } else {
+            values[middle]
+        }
+    }
+
+    // Average fill price for `notional` walked through `levels` best-first, if there is enough depth
+    fn impact_price(levels: &[(Decimal, Decimal)], notional: Decimal) -> Option<Decimal> {
+        if notional <= Decimal::ZERO {
+            return None;
+        }
+
+        let mut remaining = notional;
+        let mut quantity = Decimal::ZERO;
+        for &(price, level_quantity) in levels {
+            let level_notional = price * level_quantity;
+            if level_notional >= remaining {
+                quantity += remaining / price;
+                return Some(notional / quantity);
+            }
+            quantity += level_quantity;
+            remaining -= level_notional;
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskBracket {
+    pub max_notional: Decimal,
+    pub max_leverage: Decimal,
+    pub initial_margin: Decimal,
+    pub maintenance_margin: Decimal,
+}
+
+impl RiskBracket {
+    pub fn new(
+        max_notional: Decimal,
+        max_leverage: Decimal,
+        initial_margin: Decimal,
+        maintenance_margin: Decimal,
+    ) -> Self {
+        Self {
+            max_notional,
+            max_leverage,
+            initial_margin,
+            maintenance_margin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocializedLoss {
+    Disabled,
+    ProfitablePositions,
+    OpenInterest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafeModeLiquidations {
+    Pause,
+    FallbackMark,
 }
 
 #[derive(Debug)]
@@ -157,7 +404,11 @@ pub struct LiquidationEngine {
     pub initial_margin: Decimal,
     pub liquidation_fee: Decimal,
     pub insurance_fund: Decimal,
-    pub adl_threshold: Decimal,
+    pub brackets: Vec<RiskBracket>,
+    pub adl_enabled: bool,
+    pub socialized_loss: SocializedLoss,
+    pub max_liquidation_slippage: Decimal,
+    pub safe_mode_liquidations: SafeModeLiquidations,
 }
 
 impl Default for LiquidationEngine {
@@ -173,31 +424,72 @@ impl LiquidationEngine {
             initial_margin: dec!(0.01),
             liquidation_fee: dec!(0.003),
             insurance_fund: dec!(1000000),
-            adl_threshold: dec!(0.8),
+            brackets: vec![
+                RiskBracket::new(dec!(50000), dec!(100), dec!(0.01), dec!(0.005)),
+                RiskBracket::new(dec!(250000), dec!(50), dec!(0.02), dec!(0.01)),
+                RiskBracket::new(dec!(1000000), dec!(20), dec!(0.05), dec!(0.025)),
+                RiskBracket::new(dec!(5000000), dec!(10), dec!(0.1), dec!(0.05)),
+                RiskBracket::new(Decimal::MAX, dec!(5), dec!(0.2), dec!(0.1)),
+            ],
+            adl_enabled: true,
+            socialized_loss: SocializedLoss::ProfitablePositions,
+            max_liquidation_slippage: dec!(0.05),
+            safe_mode_liquidations: SafeModeLiquidations::Pause,
         }
     }
 
+    // Brackets are sorted by max_notional; anything beyond the last one stays in it
+    pub fn bracket(&self, notional: Decimal) -> Option<&RiskBracket> {
+        self.brackets
+            .iter()
+            .find(|bracket| notional <= bracket.max_notional)
+            .or_else(|| self.brackets.last())
+    }
+
+    // The flat rates act as a floor under every bracket
+    pub fn initial_margin_rate(&self, notional: Decimal) -> Decimal {
+        self.bracket(notional)
+            .map_or(self.initial_margin, |bracket| {
+                bracket.initial_margin.max(self.initial_margin)
+            })
+    }
+
+    pub fn maintenance_margin_rate(&self, notional: Decimal) -> Decimal {
+        self.bracket(notional)
+            .map_or(self.maintenance_margin, |bracket| {
+                bracket.maintenance_margin.max(self.maintenance_margin)
+            })
+    }
+
+    pub fn max_leverage(&self, notional: Decimal) -> Decimal {
+        self.bracket(notional)
+            .map_or(Decimal::ONE / self.initial_margin, |bracket| {
+                bracket.max_leverage
+            })
+    }
+
     pub fn calculate_liquidation_price(&self, position: &Position) -> Result<Decimal> {
         if position.leverage <= Decimal::ZERO {
-            return Err(OrderBookError::InvalidLeverage(
-                position.leverage.to_f64().unwrap_or(0.0),
-            ));
+            return Err(OrderBookError::InvalidLeverage(position.leverage));
         }
 
-        let margin_ratio = self.maintenance_margin + self.liquidation_fee;
+        let margin_ratio = self.liquidation_margin_ratio(position);
+        let bankruptcy_price = self.calculate_bankruptcy_price(position)?;
 
+        // The price at which equity falls to margin_ratio of notional
         let liq_price = match position.side {
-            PositionSide::Long => {
-                position.entry_price * (Decimal::ONE - margin_ratio / position.leverage)
-            }
-            PositionSide::Short => {
-                position.entry_price * (Decimal::ONE + margin_ratio / position.leverage)
-            }
+            PositionSide::Long => bankruptcy_price / (Decimal::ONE - margin_ratio),
+            PositionSide::Short => bankruptcy_price / (Decimal::ONE + margin_ratio),
         };
 
         Ok(liq_price.max(Decimal::ZERO))
     }
 
+    // Margin ratio at which an isolated position reaches its liquidation price
+    pub fn liquidation_margin_ratio(&self, position: &Position) -> Decimal {
+        self.maintenance_margin_rate(position.entry_price * position.size) + self.liquidation_fee
+    }
+
     pub fn calculate_bankruptcy_price(&self, position: &Position) -> Result<Decimal> {
         if position.size == Decimal::ZERO {
             return Err(OrderBookError::InvalidQuantity(
@@ -244,12 +536,68 @@ impl LiquidationEngine {
         Ok((position.margin + pnl) / position_value)
     }
 
-    pub fn should_trigger_adl(&self) -> bool {
-        let total_positions_value = dec!(10000000);
-        self.insurance_fund / total_positions_value < self.adl_threshold
+    pub fn calculate_account_margin_ratio(
+        &self,
+        collateral: Decimal,
+        positions: &[(&Position, Decimal)],
+    ) -> Result<Decimal> {
+        let mut position_value = Decimal::ZERO;
+        let mut pnl = Decimal::ZERO;
+        for &(position, mark_price) in positions {
+            position_value += mark_price * position.size;
+            pnl += Self::calculate_pnl(position, mark_price);
+        }
+
+        if position_value == Decimal::ZERO {
+            return Err(OrderBookError::InvalidQuantity(
+                "Position value is zero".to_string(),
+            ));
+        }
+
+        Ok((collateral + pnl) / position_value)
+    }
+
+    // Notional-weighted maintenance rate across the positions backing a cross account
+    pub fn account_maintenance_rate(&self, positions: &[(&Position, Decimal)]) -> Decimal {
+        let mut notional = Decimal::ZERO;
+        let mut requirement = Decimal::ZERO;
+        for &(position, mark_price) in positions {
+            let value = mark_price * position.size;
+            notional += value;
+            requirement += value * self.maintenance_margin_rate(value);
+        }
+
+        if notional.is_zero() {
+            return self.maintenance_margin;
+        }
+        requirement / notional
+    }
+
+    pub fn should_liquidate_account(
+        &self,
+        margin_ratio: Decimal,
+        positions: &[(&Position, Decimal)],
+    ) -> bool {
+        margin_ratio < self.account_maintenance_rate(positions)
+    }
+
+    // PnL% x leverage: the most profitable, most leveraged positions are deleveraged first
+    pub fn adl_score(position: &Position, mark_price: Decimal) -> Decimal {
+        if position.margin <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+        let pnl = Self::calculate_pnl(position, mark_price);
+        pnl / position.margin * position.leverage
     }
 }
 
+// Limits on contracts open on one side of a market; None leaves it uncapped
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct OpenInterestCaps {
+    pub max_total: Option<Decimal>,
+    pub max_per_account: Option<Decimal>,
+}
+
 #[derive(Debug)]
 pub struct PositionManager {
     pub positions: HashMap<u64, Position>,
@@ -257,6 +605,8 @@ pub struct PositionManager {
     pub total_short_interest: Decimal,
     pub max_leverage: Decimal,
     pub max_position_size: Decimal,
+    pub open_interest_caps: OpenInterestCaps,
+    pub cumulative_funding: Decimal,
 }
 
 impl Default for PositionManager {
@@ -273,6 +623,8 @@ impl PositionManager {
             total_short_interest: Decimal::ZERO,
             max_leverage: dec!(100),
             max_position_size: dec!(1000000),
+            open_interest_caps: OpenInterestCaps::default(),
+            cumulative_funding: Decimal::ZERO,
         }
     }
 
@@ -285,50 +637,281 @@ impl PositionManager {
         margin: Decimal,
         liquidation_engine: &LiquidationEngine,
     ) -> Result<Position> {
-        if size > self.max_position_size {
-            return Err(OrderBookError::InvalidQuantity(format!(
-                "Position size {} exceeds maximum {}",
-                size, self.max_position_size
-            )));
-        }
+        self.apply_fill(
+            trader_id,
+            side,
+            size,
+            entry_price,
+            margin,
+            liquidation_engine,
+        )?
+        .position
+        .ok_or(OrderBookError::PositionNotFound { trader_id })
+    }
 
-        if margin <= Decimal::ZERO {
-            return Err(OrderBookError::InsufficientMargin {
-                required: 1,
-                provided: 0,
-            });
+    pub fn apply_fill(
+        &mut self,
+        trader_id: u64,
+        side: PositionSide,
+        size: Decimal,
+        price: Decimal,
+        margin: Decimal,
+        liquidation_engine: &LiquidationEngine,
+    ) -> Result<PositionUpdate> {
+        self.fill(
+            trader_id,
+            side,
+            size,
+            price,
+            margin,
+            liquidation_engine,
+            true,
+        )
+    }
+
+    // Settlement of an already matched trade: margin was checked before the
+    // order reached the book, so leverage and bracket limits are not re-applied.
+    fn settle_fill(
+        &mut self,
+        trader_id: u64,
+        side: PositionSide,
+        size: Decimal,
+        price: Decimal,
+        margin: Decimal,
+        liquidation_engine: &LiquidationEngine,
+    ) -> Result<PositionUpdate> {
+        self.fill(
+            trader_id,
+            side,
+            size,
+            price,
+            margin,
+            liquidation_engine,
+            false,
+        )
+    }
+
+    // All or nothing: a flip whose new side fails its limits leaves the old position in place
+    #[allow(clippy::too_many_arguments)]
+    fn fill(
+        &mut self,
+        trader_id: u64,
+        side: PositionSide,
+        size: Decimal,
+        price: Decimal,
+        margin: Decimal,
+        liquidation_engine: &LiquidationEngine,
+        check_limits: bool,
+    ) -> Result<PositionUpdate> {
+        let existing = self.positions.get(&trader_id).cloned();
+        let interest = (self.total_long_interest, self.total_short_interest);
+
+        let result = self.try_fill(
+            trader_id,
+            side,
+            size,
+            price,
+            margin,
+            liquidation_engine,
+            check_limits,
+        );
+        if result.is_err() {
+            match existing {
+                Some(position) => self.positions.insert(trader_id, position),
+                None => self.positions.remove(&trader_id),
+            };
+            (self.total_long_interest, self.total_short_interest) = interest;
         }
+        result
+    }
 
-        let leverage = (entry_price * size) / margin;
-        if leverage > self.max_leverage {
-            return Err(OrderBookError::InvalidLeverage(
-                leverage.to_f64().unwrap_or(0.0),
+    #[allow(clippy::too_many_arguments)]
+    fn try_fill(
+        &mut self,
+        trader_id: u64,
+        side: PositionSide,
+        size: Decimal,
+        price: Decimal,
+        margin: Decimal,
+        liquidation_engine: &LiquidationEngine,
+        check_limits: bool,
+    ) -> Result<PositionUpdate> {
+        if size <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidQuantity(
+                "Fill size must be positive".to_string(),
             ));
         }
 
-        let required_margin = (entry_price * size * liquidation_engine.initial_margin).round_dp(2);
+        let funding_payment = self.settle_funding(trader_id, liquidation_engine)?;
+
+        let existing = match self.positions.get(&trader_id) {
+            Some(position) => position.clone(),
+            None => {
+                let position = self.insert_position(
+                    trader_id,
+                    side,
+                    size,
+                    price,
+                    margin,
+                    liquidation_engine,
+                    check_limits,
+                )?;
+                return Ok(PositionUpdate {
+                    position: Some(position),
+                    realized_pnl: Decimal::ZERO,
+                    released_margin: Decimal::ZERO,
+                    funding_payment,
+                });
+            }
+        };
 
-        if margin < required_margin {
-            return Err(OrderBookError::InsufficientMargin {
-                required: required_margin.to_u64().unwrap_or(0),
-                provided: margin.to_u64().unwrap_or(0),
+        if existing.side == side {
+            let position = self.increase_position(
+                existing,
+                size,
+                price,
+                margin,
+                liquidation_engine,
+                check_limits,
+            )?;
+            return Ok(PositionUpdate {
+                position: Some(position),
+                realized_pnl: Decimal::ZERO,
+                released_margin: Decimal::ZERO,
+                funding_payment,
+            });
+        }
+
+        let closing = size.min(existing.size);
+        let realized_pnl = match existing.side {
+            PositionSide::Long => (price - existing.entry_price) * closing,
+            PositionSide::Short => (existing.entry_price - price) * closing,
+        };
+        let unused_margin = margin * closing / size;
+
+        if closing < existing.size {
+            let released = existing.margin * closing / existing.size;
+            let mut position = existing;
+            position.size -= closing;
+            position.margin = position.margin - released + realized_pnl;
+            Self::refresh_risk(&mut position, liquidation_engine)?;
+            self.release_interest(position.side, closing)?;
+            self.positions.insert(trader_id, position.clone());
+
+            return Ok(PositionUpdate {
+                position: Some(position),
+                realized_pnl,
+                released_margin: released + unused_margin,
+                funding_payment,
             });
         }
 
+        self.close_position(trader_id)?;
+
+        let remaining = size - closing;
+        let position = if remaining > Decimal::ZERO {
+            Some(self.insert_position(
+                trader_id,
+                side,
+                remaining,
+                price,
+                margin - unused_margin,
+                liquidation_engine,
+                check_limits,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(PositionUpdate {
+            position,
+            realized_pnl,
+            released_margin: (existing.margin + realized_pnl).max(Decimal::ZERO) + unused_margin,
+            funding_payment,
+        })
+    }
+
+    // Whether `quantity` more on `side` fits under the caps; only the part that opens or grows a
+    // position counts, so closing is always allowed
+    pub fn check_open_interest(
+        &self,
+        trader_id: u64,
+        side: PositionSide,
+        quantity: Decimal,
+    ) -> Result<()> {
+        let (held, opposing) = match self.positions.get(&trader_id) {
+            Some(position) if position.side == side => (position.size, Decimal::ZERO),
+            Some(position) => (Decimal::ZERO, position.size),
+            None => (Decimal::ZERO, Decimal::ZERO),
+        };
+        let added = (quantity - opposing).max(Decimal::ZERO);
+        self.check_interest_caps(side, added, held + added)
+    }
+
+    fn check_interest_caps(
+        &self,
+        side: PositionSide,
+        added: Decimal,
+        position_size: Decimal,
+    ) -> Result<()> {
+        if added.is_zero() {
+            return Ok(());
+        }
+
+        let interest = match side {
+            PositionSide::Long => self.total_long_interest,
+            PositionSide::Short => self.total_short_interest,
+        };
+        if let Some(max_total) = self.open_interest_caps.max_total {
+            if interest + added > max_total {
+                return Err(OrderBookError::OpenInterestCapExceeded {
+                    open_interest: interest + added,
+                    cap: max_total,
+                });
+            }
+        }
+
+        if let Some(max_per_account) = self.open_interest_caps.max_per_account {
+            if position_size > max_per_account {
+                return Err(OrderBookError::OpenInterestCapExceeded {
+                    open_interest: position_size,
+                    cap: max_per_account,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn insert_position(
+        &mut self,
+        trader_id: u64,
+        side: PositionSide,
+        size: Decimal,
+        entry_price: Decimal,
+        margin: Decimal,
+        liquidation_engine: &LiquidationEngine,
+        check_limits: bool,
+    ) -> Result<Position> {
+        if check_limits {
+            self.validate_margin(size, entry_price, margin, liquidation_engine)?;
+            self.check_interest_caps(side, size, size)?;
+        }
+
         let mut position = Position {
             trader_id,
             side,
             size,
             entry_price,
             margin,
-            leverage,
+            leverage: Decimal::ZERO,
             unrealized_pnl: Decimal::ZERO,
             liquidation_price: Decimal::ZERO,
             bankruptcy_price: Decimal::ZERO,
+            funding_index: self.cumulative_funding,
         };
-
-        position.liquidation_price = liquidation_engine.calculate_liquidation_price(&position)?;
-        position.bankruptcy_price = liquidation_engine.calculate_bankruptcy_price(&position)?;
+        Self::refresh_risk(&mut position, liquidation_engine)?;
 
         match side {
             PositionSide::Long => self.total_long_interest += size,
@@ -339,31 +922,123 @@ impl PositionManager {
         Ok(position)
     }
 
-    pub fn close_position(&mut self, trader_id: u64) -> Result<Position> {
-        let position = self
-            .positions
-            .remove(&trader_id)
-            .ok_or(OrderBookError::PositionNotFound { trader_id })?;
+    fn increase_position(
+        &mut self,
+        mut position: Position,
+        size: Decimal,
+        price: Decimal,
+        margin: Decimal,
+        liquidation_engine: &LiquidationEngine,
+        check_limits: bool,
+    ) -> Result<Position> {
+        let new_size = position.size + size;
+        let entry_price = (position.entry_price * position.size + price * size) / new_size;
+        if check_limits {
+            self.validate_margin(
+                new_size,
+                entry_price,
+                position.margin + margin,
+                liquidation_engine,
+            )?;
+            self.check_interest_caps(position.side, size, new_size)?;
+        }
+
+        position.size = new_size;
+        position.entry_price = entry_price;
+        position.margin += margin;
+        Self::refresh_risk(&mut position, liquidation_engine)?;
 
         match position.side {
+            PositionSide::Long => self.total_long_interest += size,
+            PositionSide::Short => self.total_short_interest += size,
+        }
+
+        self.positions.insert(position.trader_id, position.clone());
+        Ok(position)
+    }
+
+    fn validate_margin(
+        &self,
+        size: Decimal,
+        entry_price: Decimal,
+        margin: Decimal,
+        liquidation_engine: &LiquidationEngine,
+    ) -> Result<()> {
+        if size > self.max_position_size {
+            return Err(OrderBookError::InvalidQuantity(format!(
+                "Position size {} exceeds maximum {}",
+                size, self.max_position_size
+            )));
+        }
+
+        let notional = entry_price * size;
+        let required_margin =
+            (notional * liquidation_engine.initial_margin_rate(notional)).round_dp(2);
+        if margin <= Decimal::ZERO {
+            return Err(OrderBookError::InsufficientMargin {
+                required: required_margin,
+                provided: margin,
+            });
+        }
+
+        let leverage = notional / margin;
+        if leverage
+            > self
+                .max_leverage
+                .min(liquidation_engine.max_leverage(notional))
+        {
+            return Err(OrderBookError::InvalidLeverage(leverage));
+        }
+
+        if margin < required_margin {
+            return Err(OrderBookError::InsufficientMargin {
+                required: required_margin,
+                provided: margin,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn refresh_risk(position: &mut Position, liquidation_engine: &LiquidationEngine) -> Result<()> {
+        position.bankruptcy_price = liquidation_engine.calculate_bankruptcy_price(position)?;
+
+        if position.margin <= Decimal::ZERO {
+            position.liquidation_price = position.bankruptcy_price;
+            return Ok(());
+        }
+
+        position.leverage = (position.entry_price * position.size) / position.margin;
+        position.liquidation_price = liquidation_engine.calculate_liquidation_price(position)?;
+        Ok(())
+    }
+
+    fn release_interest(&mut self, side: PositionSide, size: Decimal) -> Result<()> {
+        match side {
             PositionSide::Long => {
-                self.total_long_interest = self
-                    .total_long_interest
-                    .checked_sub(position.size)
-                    .ok_or_else(|| {
+                self.total_long_interest =
+                    self.total_long_interest.checked_sub(size).ok_or_else(|| {
                         OrderBookError::OverflowError("Long interest underflow".to_string())
                     })?;
             }
             PositionSide::Short => {
-                self.total_short_interest = self
-                    .total_short_interest
-                    .checked_sub(position.size)
-                    .ok_or_else(|| {
+                self.total_short_interest =
+                    self.total_short_interest.checked_sub(size).ok_or_else(|| {
                         OrderBookError::OverflowError("Short interest underflow".to_string())
                     })?;
             }
         }
 
+        Ok(())
+    }
+
+    pub fn close_position(&mut self, trader_id: u64) -> Result<Position> {
+        let position = self
+            .positions
+            .remove(&trader_id)
+            .ok_or(OrderBookError::PositionNotFound { trader_id })?;
+
+        self.release_interest(position.side, position.size)?;
         Ok(position)
     }
 
@@ -372,44 +1047,174 @@ impl PositionManager {
         mark_price: Decimal,
         liquidation_engine: &LiquidationEngine,
     ) -> Result<Vec<u64>> {
-        let mut liquidated = Vec::new();
+        let mut liquidatable = Vec::new();
 
+        // Marks to market only; closing goes through ClearingHouse::liquidate and the book
         for (trader_id, position) in self.positions.iter_mut() {
             position.unrealized_pnl = LiquidationEngine::calculate_pnl(position, mark_price);
 
             if liquidation_engine.should_liquidate(position, mark_price) {
-                liquidated.push(*trader_id);
+                liquidatable.push(*trader_id);
             }
         }
 
-        for trader_id in &liquidated {
-            self.close_position(*trader_id)?;
+        liquidatable.sort_unstable();
+        Ok(liquidatable)
+    }
+
+    pub fn adjust_margin(
+        &mut self,
+        trader_id: u64,
+        delta: Decimal,
+        mark_price: Decimal,
+        liquidation_engine: &LiquidationEngine,
+    ) -> Result<Position> {
+        if delta == Decimal::ZERO {
+            return Err(OrderBookError::InvalidQuantity(
+                "Margin adjustment must be non-zero".to_string(),
+            ));
+        }
+
+        self.settle_funding(trader_id, liquidation_engine)?;
+        let mut position = self
+            .positions
+            .get(&trader_id)
+            .cloned()
+            .ok_or(OrderBookError::PositionNotFound { trader_id })?;
+
+        position.margin = position
+            .margin
+            .checked_add(delta)
+            .ok_or_else(|| OrderBookError::OverflowError("Position margin overflow".to_string()))?;
+
+        if delta < Decimal::ZERO {
+            self.validate_margin(
+                position.size,
+                position.entry_price,
+                position.margin,
+                liquidation_engine,
+            )?;
+
+            // What's left must still cover initial margin once unrealized losses are counted
+            let notional = mark_price * position.size;
+            let required =
+                (notional * liquidation_engine.initial_margin_rate(notional)).round_dp(2);
+            let equity = position.margin + LiquidationEngine::calculate_pnl(&position, mark_price);
+            if equity < required {
+                return Err(OrderBookError::InsufficientMargin {
+                    required,
+                    provided: equity,
+                });
+            }
         }
 
-        Ok(liquidated)
+        Self::refresh_risk(&mut position, liquidation_engine)?;
+        self.positions.insert(trader_id, position.clone());
+        Ok(position)
     }
 
-    pub fn apply_funding(&mut self, funding_rate: &FundingRate) -> HashMap<u64, Decimal> {
-        let mut funding_payments = HashMap::new();
+    // Accrues one funding interval into the market-wide index (quote per unit of size).
+    // Positions pick it up lazily through settle_funding.
+    pub fn apply_funding(
+        &mut self,
+        funding_rate: &FundingRate,
+        mark_price: Decimal,
+    ) -> Result<Decimal> {
+        let accrued = mark_price
+            .checked_mul(funding_rate.current_rate)
+            .ok_or_else(|| OrderBookError::OverflowError("Funding accrual overflow".to_string()))?;
+        self.cumulative_funding = self
+            .cumulative_funding
+            .checked_add(accrued)
+            .ok_or_else(|| OrderBookError::OverflowError("Funding index overflow".to_string()))?;
+        Ok(self.cumulative_funding)
+    }
 
-        for (trader_id, position) in self.positions.iter_mut() {
-            let is_long = matches!(position.side, PositionSide::Long);
-            let payment = funding_rate.calculate_funding_payment(position.size, is_long);
+    pub fn pending_funding(&self, trader_id: u64) -> Decimal {
+        let Some(position) = self.positions.get(&trader_id) else {
+            return Decimal::ZERO;
+        };
+
+        let accrued = (self.cumulative_funding - position.funding_index) * position.size;
+        match position.side {
+            PositionSide::Long => -accrued,
+            PositionSide::Short => accrued,
+        }
+    }
+
+    pub fn settle_funding(
+        &mut self,
+        trader_id: u64,
+        liquidation_engine: &LiquidationEngine,
+    ) -> Result<Decimal> {
+        let payment = self.pending_funding(trader_id);
+        let Some(position) = self.positions.get_mut(&trader_id) else {
+            return Ok(Decimal::ZERO);
+        };
 
+        position.funding_index = self.cumulative_funding;
+        if !payment.is_zero() {
             position.margin += payment;
-            funding_payments.insert(*trader_id, payment);
+            Self::refresh_risk(position, liquidation_engine)?;
         }
+        Ok(payment)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeeRates {
+    maker_fee: Decimal,
+    taker_fee: Decimal,
+}
+
+impl FeeRates {
+    pub fn new(maker_fee: Decimal, taker_fee: Decimal) -> Result<Self> {
+        let rates = Self {
+            maker_fee,
+            taker_fee,
+        };
+        rates.validate()?;
+        Ok(rates)
+    }
+
+    pub fn maker_fee(&self) -> Decimal {
+        self.maker_fee
+    }
 
-        funding_payments
+    pub fn taker_fee(&self) -> Decimal {
+        self.taker_fee
+    }
+
+    // A maker rebate is paid out of the taker fee on the same fill, so takers are never paid
+    fn validate(&self) -> Result<()> {
+        if self.taker_fee < Decimal::ZERO || self.maker_fee < -self.taker_fee {
+            return Err(OrderBookError::InvalidQuantity(format!(
+                "Invalid fee rates: maker {} taker {}",
+                self.maker_fee, self.taker_fee
+            )));
+        }
+        Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeeTier {
+    pub min_volume: Decimal,
+    pub rates: FeeRates,
+}
+
 #[derive(Debug, Clone)]
 pub struct FeeStructure {
-    pub maker_fee: Decimal,
-    pub taker_fee: Decimal,
+    // Rates for accounts below the first tier
+    base: FeeRates,
     pub liquidation_fee: Decimal,
     pub funding_interval: u64,
+    pub volume_window: u64,
+    // Shares of a referred account's fee paid to its referrer and back to the account itself
+    pub referrer_share: Decimal,
+    pub referee_share: Decimal,
+    tiers: Vec<FeeTier>,
+    overrides: HashMap<u64, FeeRates>,
 }
 
 impl Default for FeeStructure {
@@ -421,21 +1226,130 @@ impl Default for FeeStructure {
 impl FeeStructure {
     pub fn new() -> Self {
         Self {
-            maker_fee: dec!(-0.0001),
-            taker_fee: dec!(0.0005),
+            base: FeeRates {
+                maker_fee: dec!(-0.0001),
+                taker_fee: dec!(0.0005),
+            },
             liquidation_fee: dec!(0.003),
             funding_interval: 28800,
+            volume_window: 30 * 86400,
+            referrer_share: dec!(0.1),
+            referee_share: Decimal::ZERO,
+            tiers: Vec::new(),
+            overrides: HashMap::new(),
         }
     }
 
-    pub fn calculate_fee(&self, is_maker: bool, notional_value: Decimal) -> Decimal {
-        let fee_rate = if is_maker {
-            self.maker_fee
-        } else {
-            self.taker_fee
+    pub fn with_tiers(mut tiers: Vec<FeeTier>) -> Result<Self> {
+        tiers.sort_by_key(|tier| tier.min_volume);
+        for tier in &tiers {
+            if tier.min_volume < Decimal::ZERO || tier.rates.validate().is_err() {
+                return Err(OrderBookError::InvalidQuantity(format!(
+                    "Invalid fee tier at volume {}",
+                    tier.min_volume
+                )));
+            }
+        }
+
+        let fee_structure = Self {
+            tiers,
+            ..Self::new()
         };
-        notional_value * fee_rate
+        fee_structure.validate()?;
+        Ok(fee_structure)
+    }
+
+    pub fn tiers(&self) -> &[FeeTier] {
+        &self.tiers
+    }
+
+    pub fn base_rates(&self) -> FeeRates {
+        self.base
     }
+
+    pub fn set_base_rates(&mut self, rates: FeeRates) -> Result<()> {
+        let previous = std::mem::replace(&mut self.base, rates);
+        if let Err(e) = self.validate() {
+            self.base = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn set_override(&mut self, trader_id: u64, rates: FeeRates) -> Result<()> {
+        rates.validate()?;
+        let previous = self.overrides.insert(trader_id, rates);
+        if let Err(e) = self.validate() {
+            match previous {
+                Some(previous) => self.overrides.insert(trader_id, previous),
+                None => self.overrides.remove(&trader_id),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn remove_override(&mut self, trader_id: u64) -> Option<FeeRates> {
+        self.overrides.remove(&trader_id)
+    }
+
+    pub fn overrides(&self) -> &HashMap<u64, FeeRates> {
+        &self.overrides
+    }
+
+    // Override first, then the highest tier the trailing volume reaches, then the base rates
+    pub fn rates_for(&self, trader_id: u64, trailing_volume: Decimal) -> FeeRates {
+        if let Some(rates) = self.overrides.get(&trader_id) {
+            return *rates;
+        }
+
+        self.tiers
+            .iter()
+            .rev()
+            .find(|tier| trailing_volume >= tier.min_volume)
+            .map_or(self.base, |tier| tier.rates)
+    }
+
+    // Any maker can meet any taker, so no rebate in the schedule may exceed the lowest taker fee
+    // in it, or a fill between the two would pay out more than it collects
+    pub fn validate(&self) -> Result<()> {
+        let rates: Vec<FeeRates> = std::iter::once(self.base)
+            .chain(self.tiers.iter().map(|tier| tier.rates))
+            .chain(self.overrides.values().copied())
+            .collect();
+        let lowest_taker_fee = rates
+            .iter()
+            .map(|rates| rates.taker_fee())
+            .min()
+            .unwrap_or(self.base.taker_fee());
+
+        for rates in &rates {
+            rates.validate()?;
+            if rates.maker_fee() < -lowest_taker_fee {
+                return Err(OrderBookError::InvalidQuantity(format!(
+                    "Maker rebate {} exceeds the lowest taker fee {}",
+                    -rates.maker_fee(),
+                    lowest_taker_fee
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FundEventKind {
+    Contribution,
+    Payout,
+    Shortfall,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundEvent {
+    pub timestamp: u64,
+    pub kind: FundEventKind,
+    pub amount: Decimal,
+    pub balance_after: Decimal,
 }
 
 #[derive(Debug)]
@@ -444,6 +1358,8 @@ pub struct InsuranceFund {
     pub target_ratio: Decimal,
     pub contributions: Decimal,
     pub payouts: Decimal,
+    pub shortfalls: Decimal,
+    history: Vec<FundEvent>,
 }
 
 impl InsuranceFund {
@@ -453,10 +1369,12 @@ impl InsuranceFund {
             target_ratio: dec!(0.001),
             contributions: Decimal::ZERO,
             payouts: Decimal::ZERO,
+            shortfalls: Decimal::ZERO,
+            history: Vec::new(),
         }
     }
 
-    pub fn add_contribution(&mut self, amount: Decimal) -> Result<()> {
+    pub fn add_contribution(&mut self, amount: Decimal, timestamp: u64) -> Result<()> {
         self.balance = self
             .balance
             .checked_add(amount)
@@ -465,10 +1383,11 @@ impl InsuranceFund {
             .contributions
             .checked_add(amount)
             .ok_or_else(|| OrderBookError::OverflowError("Contributions overflow".to_string()))?;
+        self.record(FundEventKind::Contribution, amount, timestamp);
         Ok(())
     }
 
-    pub fn process_payout(&mut self, amount: Decimal) -> Result<bool> {
+    pub fn process_payout(&mut self, amount: Decimal, timestamp: u64) -> Result<bool> {
         if self.balance >= amount {
             self.balance = self.balance.checked_sub(amount).ok_or_else(|| {
                 OrderBookError::OverflowError("Insurance fund underflow".to_string())
@@ -477,9 +1396,585 @@ impl InsuranceFund {
                 .payouts
                 .checked_add(amount)
                 .ok_or_else(|| OrderBookError::OverflowError("Payouts overflow".to_string()))?;
+            self.record(FundEventKind::Payout, amount, timestamp);
             Ok(true)
         } else {
             Ok(false)
         }
     }
+
+    pub fn record_shortfall(&mut self, amount: Decimal, timestamp: u64) -> Result<()> {
+        self.shortfalls = self
+            .shortfalls
+            .checked_add(amount)
+            .ok_or_else(|| OrderBookError::OverflowError("Shortfalls overflow".to_string()))?;
+        self.record(FundEventKind::Shortfall, amount, timestamp);
+        Ok(())
+    }
+
+    pub fn target_balance(&self, total_position_value: Decimal) -> Decimal {
+        total_position_value * self.target_ratio
+    }
+
+    // Splits fee income into what tops the fund up to target and the excess for the treasury
+    pub fn fee_split(&self, fees: Decimal, total_position_value: Decimal) -> (Decimal, Decimal) {
+        let room = (self.target_balance(total_position_value) - self.balance).max(Decimal::ZERO);
+        let to_fund = fees.min(room);
+        (to_fund, fees - to_fund)
+    }
+
+    pub fn history(&self) -> &[FundEvent] {
+        &self.history
+    }
+
+    pub fn history_between(&self, from: u64, to: u64) -> impl Iterator<Item = &FundEvent> {
+        self.history
+            .iter()
+            .filter(move |event| event.timestamp >= from && event.timestamp <= to)
+    }
+
+    fn record(&mut self, kind: FundEventKind, amount: Decimal, timestamp: u64) {
+        self.history.push(FundEvent {
+            timestamp,
+            kind,
+            amount,
+            balance_after: self.balance,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(
+        manager: &mut PositionManager,
+        engine: &LiquidationEngine,
+        side: PositionSide,
+        size: Decimal,
+        price: Decimal,
+        margin: Decimal,
+    ) -> PositionUpdate {
+        manager
+            .apply_fill(1, side, size, price, margin, engine)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_same_side_fill_increases_with_weighted_entry() {
+        let engine = LiquidationEngine::new();
+        let mut manager = PositionManager::new();
+        open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(10),
+            dec!(100),
+            dec!(100),
+        );
+        let before = manager.positions[&1].liquidation_price;
+
+        let update = open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(30),
+            dec!(120),
+            dec!(300),
+        );
+        let position = update.position.unwrap();
+
+        assert_eq!(position.size, dec!(40));
+        assert_eq!(position.entry_price, dec!(115));
+        assert_eq!(position.margin, dec!(400));
+        assert_eq!(position.leverage, dec!(11.5));
+        assert_ne!(position.liquidation_price, before);
+        assert_eq!(manager.total_long_interest, dec!(40));
+    }
+
+    #[test]
+    fn test_open_interest_caps_bind_on_growth_only() {
+        let engine = LiquidationEngine::new();
+        let mut manager = PositionManager::new();
+        manager.open_interest_caps = OpenInterestCaps {
+            max_total: Some(dec!(50)),
+            max_per_account: Some(dec!(30)),
+        };
+        open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(30),
+            dec!(100),
+            dec!(300),
+        );
+        manager
+            .apply_fill(
+                2,
+                PositionSide::Long,
+                dec!(15),
+                dec!(100),
+                dec!(150),
+                &engine,
+            )
+            .unwrap();
+
+        assert!(manager
+            .apply_fill(1, PositionSide::Long, dec!(1), dec!(100), dec!(10), &engine)
+            .is_err());
+        assert!(manager
+            .check_open_interest(2, PositionSide::Long, dec!(6))
+            .is_err());
+        manager
+            .check_open_interest(2, PositionSide::Long, dec!(5))
+            .unwrap();
+
+        // Flipping only counts the part beyond the closed position
+        manager
+            .check_open_interest(1, PositionSide::Short, dec!(60))
+            .unwrap();
+        assert!(manager
+            .check_open_interest(1, PositionSide::Short, dec!(61))
+            .is_err());
+    }
+
+    #[test]
+    fn test_partial_reduce_realizes_pnl_into_margin() {
+        let engine = LiquidationEngine::new();
+        let mut manager = PositionManager::new();
+        open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(10),
+            dec!(100),
+            dec!(100),
+        );
+
+        let update = open(
+            &mut manager,
+            &engine,
+            PositionSide::Short,
+            dec!(4),
+            dec!(110),
+            dec!(40),
+        );
+        let position = update.position.unwrap();
+
+        assert_eq!(update.realized_pnl, dec!(40));
+        assert_eq!(update.released_margin, dec!(80));
+        assert_eq!(position.side, PositionSide::Long);
+        assert_eq!(position.size, dec!(6));
+        assert_eq!(position.entry_price, dec!(100));
+        assert_eq!(position.margin, dec!(100));
+        assert_eq!(
+            position.bankruptcy_price,
+            engine.calculate_bankruptcy_price(&position).unwrap()
+        );
+        assert_eq!(manager.total_long_interest, dec!(6));
+        assert_eq!(manager.total_short_interest, dec!(0));
+    }
+
+    #[test]
+    fn test_opposite_fill_larger_than_position_flips_side() {
+        let engine = LiquidationEngine::new();
+        let mut manager = PositionManager::new();
+        open(
+            &mut manager,
+            &engine,
+            PositionSide::Short,
+            dec!(10),
+            dec!(100),
+            dec!(100),
+        );
+
+        let update = open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(15),
+            dec!(90),
+            dec!(150),
+        );
+        let position = update.position.unwrap();
+
+        assert_eq!(update.realized_pnl, dec!(100));
+        assert_eq!(update.released_margin, dec!(300));
+        assert_eq!(position.side, PositionSide::Long);
+        assert_eq!(position.size, dec!(5));
+        assert_eq!(position.entry_price, dec!(90));
+        assert_eq!(position.margin, dec!(50));
+        assert_eq!(manager.total_long_interest, dec!(5));
+        assert_eq!(manager.total_short_interest, dec!(0));
+    }
+
+    #[test]
+    fn test_rejected_flip_leaves_position_untouched() {
+        let engine = LiquidationEngine::new();
+        let mut manager = PositionManager::new();
+        manager.max_position_size = dec!(15);
+        open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(10),
+            dec!(100),
+            dec!(100),
+        );
+        let before = manager.positions[&1].clone();
+
+        // Closing 10 would leave a 20 short, over the size limit
+        assert!(matches!(
+            manager.apply_fill(
+                1,
+                PositionSide::Short,
+                dec!(30),
+                dec!(100),
+                dec!(300),
+                &engine
+            ),
+            Err(OrderBookError::InvalidQuantity(_))
+        ));
+        assert_eq!(manager.positions[&1], before);
+        assert_eq!(manager.total_long_interest, dec!(10));
+        assert_eq!(manager.total_short_interest, dec!(0));
+    }
+
+    #[test]
+    fn test_exact_opposite_fill_closes_position() {
+        let engine = LiquidationEngine::new();
+        let mut manager = PositionManager::new();
+        open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(10),
+            dec!(100),
+            dec!(100),
+        );
+
+        let update = open(
+            &mut manager,
+            &engine,
+            PositionSide::Short,
+            dec!(10),
+            dec!(95),
+            dec!(100),
+        );
+
+        assert!(update.position.is_none());
+        assert_eq!(update.realized_pnl, dec!(-50));
+        assert_eq!(update.released_margin, dec!(150));
+        assert!(manager.positions.is_empty());
+        assert_eq!(manager.total_long_interest, dec!(0));
+        assert_eq!(manager.total_short_interest, dec!(0));
+    }
+
+    #[test]
+    fn test_adjust_margin_moves_risk_prices() {
+        let mut engine = LiquidationEngine::new();
+        engine.initial_margin = dec!(0.05);
+        let mut manager = PositionManager::new();
+        open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(10),
+            dec!(100),
+            dec!(100),
+        );
+        assert_eq!(manager.positions[&1].bankruptcy_price, dec!(90));
+        assert_eq!(
+            manager.positions[&1].liquidation_price,
+            dec!(90) / dec!(0.992)
+        );
+
+        let position = manager
+            .adjust_margin(1, dec!(100), dec!(100), &engine)
+            .unwrap();
+        assert_eq!(position.margin, dec!(200));
+        assert_eq!(position.leverage, dec!(5));
+        assert_eq!(position.bankruptcy_price, dec!(80));
+        assert_eq!(position.liquidation_price, dec!(80) / dec!(0.992));
+
+        assert!(matches!(
+            manager.adjust_margin(1, dec!(-160), dec!(100), &engine),
+            Err(OrderBookError::InsufficientMargin { .. })
+        ));
+        assert_eq!(manager.positions[&1], position);
+
+        // At 97 the 30 loss leaves 20 of equity against 48.5 of initial margin at mark
+        assert_eq!(
+            manager.adjust_margin(1, dec!(-150), dec!(97), &engine),
+            Err(OrderBookError::InsufficientMargin {
+                required: dec!(48.5),
+                provided: dec!(20)
+            })
+        );
+        assert_eq!(manager.positions[&1], position);
+
+        let position = manager
+            .adjust_margin(1, dec!(-150), dec!(100), &engine)
+            .unwrap();
+        assert_eq!(position.leverage, dec!(20));
+        assert_eq!(position.bankruptcy_price, dec!(95));
+        assert!(manager
+            .adjust_margin(2, dec!(10), dec!(100), &engine)
+            .is_err());
+    }
+
+    #[test]
+    fn test_funding_index_settles_lazily_on_notional() {
+        let engine = LiquidationEngine::new();
+        let mut manager = PositionManager::new();
+        open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(10),
+            dec!(100),
+            dec!(100),
+        );
+        let before = manager.positions[&1].liquidation_price;
+
+        let mut funding_rate = FundingRate::new();
+        funding_rate.current_rate = dec!(0.01);
+        assert_eq!(
+            manager.apply_funding(&funding_rate, dec!(120)).unwrap(),
+            dec!(1.2)
+        );
+        assert_eq!(
+            manager.apply_funding(&funding_rate, dec!(80)).unwrap(),
+            dec!(2)
+        );
+
+        // Nothing moves until the position is touched
+        assert_eq!(manager.positions[&1].margin, dec!(100));
+        assert_eq!(manager.pending_funding(1), dec!(-20));
+
+        let update = open(
+            &mut manager,
+            &engine,
+            PositionSide::Long,
+            dec!(10),
+            dec!(100),
+            dec!(100),
+        );
+        assert_eq!(update.funding_payment, dec!(-20));
+
+        let position = &manager.positions[&1];
+        assert_eq!(position.margin, dec!(180));
+        assert_eq!(position.funding_index, dec!(2));
+        assert!(position.liquidation_price > before);
+        assert_eq!(manager.pending_funding(1), dec!(0));
+
+        // A later entrant only pays from its own snapshot
+        manager
+            .apply_fill(
+                2,
+                PositionSide::Short,
+                dec!(5),
+                dec!(100),
+                dec!(50),
+                &engine,
+            )
+            .unwrap();
+        manager.apply_funding(&funding_rate, dec!(100)).unwrap();
+        assert_eq!(manager.pending_funding(1), dec!(-20));
+        assert_eq!(manager.pending_funding(2), dec!(5));
+        assert_eq!(manager.settle_funding(2, &engine).unwrap(), dec!(5));
+        assert_eq!(manager.positions[&2].margin, dec!(55));
+    }
+
+    #[test]
+    fn test_insurance_fund_history() {
+        let mut fund = InsuranceFund::new(dec!(100));
+        fund.add_contribution(dec!(25), 10).unwrap();
+        assert!(fund.process_payout(dec!(50), 20).unwrap());
+        assert!(!fund.process_payout(dec!(500), 30).unwrap());
+        fund.record_shortfall(dec!(500), 30).unwrap();
+
+        let kinds: Vec<FundEventKind> = fund.history().iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                FundEventKind::Contribution,
+                FundEventKind::Payout,
+                FundEventKind::Shortfall
+            ]
+        );
+        assert_eq!(fund.history()[1].balance_after, dec!(75));
+        assert_eq!(fund.history_between(15, 30).count(), 2);
+        assert_eq!(fund.shortfalls, dec!(500));
+
+        // Target is 0.1% of 100000 = 100; only 25 of the fees are needed to reach it
+        assert_eq!(fund.fee_split(dec!(40), dec!(100000)), (dec!(25), dec!(15)));
+    }
+
+    #[test]
+    fn test_fee_tiers_by_volume_with_overrides() {
+        let tier = |min_volume, maker_fee, taker_fee| FeeTier {
+            min_volume,
+            rates: FeeRates::new(maker_fee, taker_fee).unwrap(),
+        };
+        let mut fees = FeeStructure::with_tiers(vec![
+            tier(dec!(5000000), dec!(-0.0002), dec!(0.0003)),
+            tier(dec!(1000000), dec!(-0.00015), dec!(0.0004)),
+        ])
+        .unwrap();
+        assert_eq!(fees.tiers()[0].min_volume, dec!(1000000));
+
+        assert_eq!(fees.rates_for(1, dec!(999999)).taker_fee(), dec!(0.0005));
+        assert_eq!(fees.rates_for(1, dec!(1000000)).taker_fee(), dec!(0.0004));
+        assert_eq!(fees.rates_for(1, dec!(9000000)).maker_fee(), dec!(-0.0002));
+
+        let vip = FeeRates::new(dec!(-0.0002), dec!(0.0002)).unwrap();
+        fees.set_override(2, vip).unwrap();
+        assert_eq!(fees.rates_for(2, Decimal::ZERO), vip);
+
+        // Rebate larger than the taker fee that funds it, or a taker that gets paid
+        assert!(FeeRates::new(dec!(-0.001), dec!(0.0005)).is_err());
+        assert!(FeeRates::new(dec!(0), dec!(-0.0001)).is_err());
+
+        // Overrides and base rates are held to the same rules as tiers and leave the old rates
+        // in place. A taker fee below the deepest tier rebate would let that tier's makers be
+        // paid more than this account pays.
+        let cheap_taker = FeeRates::new(dec!(0), dec!(0.0001)).unwrap();
+        assert!(fees.set_override(2, cheap_taker).is_err());
+        assert_eq!(fees.rates_for(2, Decimal::ZERO), vip);
+        assert!(fees.set_base_rates(cheap_taker).is_err());
+        assert_eq!(fees.base_rates().taker_fee(), dec!(0.0005));
+        let base = FeeRates::new(dec!(0), dec!(0.0004)).unwrap();
+        fees.set_base_rates(base).unwrap();
+        assert_eq!(fees.rates_for(3, Decimal::ZERO), base);
+        assert_eq!(fees.remove_override(2), Some(vip));
+        assert!(fees.overrides().is_empty());
+    }
+
+    #[test]
+    fn test_brackets_scale_margin_with_notional() {
+        let engine = LiquidationEngine::new();
+        assert_eq!(engine.max_leverage(dec!(40000)), dec!(100));
+        assert_eq!(engine.initial_margin_rate(dec!(100000)), dec!(0.02));
+        assert_eq!(engine.maintenance_margin_rate(dec!(2000000)), dec!(0.05));
+        assert_eq!(engine.initial_margin_rate(dec!(10000000)), dec!(0.2));
+
+        let mut manager = PositionManager::new();
+        assert!(matches!(
+            manager.open_position(
+                1,
+                PositionSide::Long,
+                dec!(100),
+                dec!(1000),
+                dec!(1000),
+                &engine
+            ),
+            Err(OrderBookError::InvalidLeverage(_))
+        ));
+        let position = manager
+            .open_position(
+                1,
+                PositionSide::Long,
+                dec!(100),
+                dec!(1000),
+                dec!(2000),
+                &engine,
+            )
+            .unwrap();
+        assert_eq!(position.liquidation_price, dec!(980) / dec!(0.987));
+
+        // Zero margin reports the real requirement rather than a placeholder
+        assert_eq!(
+            manager.open_position(3, PositionSide::Long, dec!(1), dec!(1001), dec!(0), &engine),
+            Err(OrderBookError::InsufficientMargin {
+                required: dec!(10.01),
+                provided: dec!(0)
+            })
+        );
+
+        // 40k at 100x is fine, but adding 20k more lands in the 50x bracket
+        manager
+            .open_position(
+                2,
+                PositionSide::Short,
+                dec!(40),
+                dec!(1000),
+                dec!(400),
+                &engine,
+            )
+            .unwrap();
+        assert!(matches!(
+            manager.apply_fill(
+                2,
+                PositionSide::Short,
+                dec!(20),
+                dec!(1000),
+                dec!(200),
+                &engine
+            ),
+            Err(OrderBookError::InvalidLeverage(_))
+        ));
+    }
+
+    #[test]
+    fn test_mark_price_uses_impact_prices_from_depth() {
+        let mut book = OrderBook::new();
+        book.place_order(Side::Buy, dec!(1010), dec!(5), 1).unwrap();
+        book.place_order(Side::Buy, dec!(990), dec!(5), 2).unwrap();
+        book.place_order(Side::Sell, dec!(1200), dec!(4), 3)
+            .unwrap();
+        book.place_order(Side::Sell, dec!(1300), dec!(4), 4)
+            .unwrap();
+
+        let mut mark = MarkPrice::new();
+        mark.calculate(&book, dec!(800), None, 0).unwrap();
+        assert_eq!(mark.impact_bid, Some(dec!(1000)));
+        assert_eq!(mark.impact_ask, Some(dec!(1250)));
+        assert_eq!(mark.premium, dec!(0.25));
+
+        mark.calculate(&book, dec!(1300), None, 0).unwrap();
+        assert_eq!(mark.premium, dec!(-50) / dec!(1300));
+
+        // Not enough depth on either side: no premium rather than a best-level guess
+        mark.impact_notional = dec!(20000);
+        mark.calculate(&book, dec!(800), None, 0).unwrap();
+        assert_eq!(mark.impact_bid, None);
+        assert_eq!(mark.impact_ask, None);
+        assert_eq!(mark.premium, dec!(0));
+        assert_eq!(mark.fair_price, dec!(1105));
+    }
+
+    #[test]
+    fn test_mark_price_methods_seed_from_first_observation() {
+        let mut book = OrderBook::new();
+        book.place_order(Side::Buy, dec!(990), dec!(100), 1)
+            .unwrap();
+        book.place_order(Side::Sell, dec!(1010), dec!(100), 2)
+            .unwrap();
+
+        let mut median = MarkPrice::with_method(MarkPriceMethod::Median);
+        assert!(!median.is_initialized());
+        median
+            .calculate(&book, dec!(990), Some(dec!(1020)), 100)
+            .unwrap();
+        assert_eq!(median.funding_basis, dec!(10));
+        assert_eq!(median.price, dec!(1000));
+        assert_eq!(median.price_samples()[0], (100, dec!(1000), dec!(990)));
+
+        let mut basis = MarkPrice::with_method(MarkPriceMethod::IndexPlusBasis);
+        basis.calculate(&book, dec!(990), None, 0).unwrap();
+        assert_eq!(basis.price, dec!(1000));
+        basis.calculate(&book, dec!(1000), None, 60).unwrap();
+        assert_eq!(basis.funding_basis, dec!(9));
+        assert_eq!(basis.price, dec!(1009));
+
+        let mut ema = MarkPrice::with_method(MarkPriceMethod::TimeEma { half_life: 60 });
+        ema.calculate(&book, dec!(990), None, 0).unwrap();
+        assert_eq!(ema.price, dec!(1000));
+        book.place_order(Side::Buy, dec!(1005), dec!(100), 3)
+            .unwrap();
+        ema.calculate(&book, dec!(990), None, 60).unwrap();
+        assert_eq!(ema.price, dec!(1003.75));
+        assert_eq!(ema.price_samples()[1].0, 60);
+    }
 }
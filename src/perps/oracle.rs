@@ -0,0 +1,394 @@
+use crate::error::{OrderBookError, Result};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OracleQuote {
+    pub price: Decimal,
+    pub timestamp: u64,
+}
+
+pub trait Oracle {
+    fn name(&self) -> &str;
+
+    // Latest quote from the source, or None if it has nothing to report yet
+    fn quote(&mut self) -> Result<Option<OracleQuote>>;
+}
+
+impl<T: Oracle + ?Sized> Oracle for Box<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn quote(&mut self) -> Result<Option<OracleQuote>> {
+        (**self).quote()
+    }
+}
+
+// Replays `timestamp,price` lines, one per quote; the last line is repeated once exhausted
+#[derive(Debug, Clone)]
+pub struct FileOracle {
+    name: String,
+    quotes: Vec<OracleQuote>,
+    cursor: usize,
+}
+
+impl FileOracle {
+    pub fn from_path(name: &str, path: impl AsRef<Path>) -> Result<Self> {
+        let contents = fs::read_to_string(path.as_ref()).map_err(|e| {
+            OrderBookError::OracleUnavailable(format!("{name}: {}: {e}", path.as_ref().display()))
+        })?;
+        Self::parse(name, &contents)
+    }
+
+    pub fn parse(name: &str, contents: &str) -> Result<Self> {
+        let mut quotes = Vec::new();
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid =
+                || OrderBookError::OracleUnavailable(format!("{name}: bad line {}", line_no + 1));
+            let (timestamp, price) = line.split_once(',').ok_or_else(invalid)?;
+            let timestamp = timestamp.trim().parse::<u64>().map_err(|_| invalid())?;
+            let price = Decimal::from_str(price.trim()).map_err(|_| invalid())?;
+            if price <= Decimal::ZERO {
+                return Err(invalid());
+            }
+            quotes.push(OracleQuote { price, timestamp });
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            quotes,
+            cursor: 0,
+        })
+    }
+}
+
+impl Oracle for FileOracle {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn quote(&mut self) -> Result<Option<OracleQuote>> {
+        let quote = self
+            .quotes
+            .get(self.cursor)
+            .or_else(|| self.quotes.last())
+            .copied();
+        self.cursor = (self.cursor + 1).min(self.quotes.len());
+        Ok(quote)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulatedOracle {
+    name: String,
+    pub price: Decimal,
+    pub timestamp: u64,
+}
+
+impl SimulatedOracle {
+    pub fn new(name: &str, price: Decimal) -> Self {
+        Self {
+            name: name.to_string(),
+            price,
+            timestamp: 0,
+        }
+    }
+
+    pub fn set_price(&mut self, price: Decimal, timestamp: u64) {
+        self.price = price;
+        self.timestamp = timestamp;
+    }
+}
+
+impl Oracle for SimulatedOracle {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn quote(&mut self) -> Result<Option<OracleQuote>> {
+        Ok(Some(OracleQuote {
+            price: self.price,
+            timestamp: self.timestamp,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OracleStatus {
+    Healthy,
+    Stale,
+    LowConfidence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregationMethod {
+    Median,
+    WeightedMedian,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregatedPrice {
+    pub price: Decimal,
+    pub timestamp: u64,
+    pub confidence: Decimal,
+    pub sources: Vec<String>,
+    pub dropped: Vec<String>,
+}
+
+pub struct OracleAggregator<O = Box<dyn Oracle>> {
+    pub method: AggregationMethod,
+    pub max_deviation: Decimal,
+    pub max_staleness: u64,
+    sources: Vec<(O, Decimal)>,
+}
+
+impl<O: Oracle> Default for OracleAggregator<O> {
+    fn default() -> Self {
+        Self::new(AggregationMethod::Median)
+    }
+}
+
+impl<O: Oracle> OracleAggregator<O> {
+    pub fn new(method: AggregationMethod) -> Self {
+        Self {
+            method,
+            max_deviation: dec!(0.02),
+            max_staleness: 60,
+            sources: Vec::new(),
+        }
+    }
+
+    pub fn add_source(&mut self, source: O, weight: Decimal) -> Result<()> {
+        if weight <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidQuantity(format!(
+                "Oracle weight for {} must be positive",
+                source.name()
+            )));
+        }
+
+        self.sources.push((source, weight));
+        Ok(())
+    }
+
+    pub fn source_mut(&mut self, index: usize) -> Option<&mut O> {
+        self.sources.get_mut(index).map(|(source, _)| source)
+    }
+
+    pub fn source_count(&self) -> usize {
+        self.sources.len()
+    }
+
+    // Median of all reporting sources, then again over those within max_deviation of it.
+    // Confidence is the share of configured weight that made it into the final price.
+    // Sources that fail, or quote older than max_staleness at `now` or at a non-positive price,
+    // count as missing; only an aggregation with no source left fails.
+    pub fn aggregate(&mut self, now: u64) -> Result<AggregatedPrice> {
+        let total_weight: Decimal = self.sources.iter().map(|(_, weight)| *weight).sum();
+
+        let mut quotes = Vec::new();
+        let mut dropped = Vec::new();
+        for (source, weight) in self.sources.iter_mut() {
+            match source.quote() {
+                Ok(Some(quote))
+                    if quote.price > Decimal::ZERO
+                        && now.saturating_sub(quote.timestamp) <= self.max_staleness =>
+                {
+                    quotes.push((source.name().to_string(), quote, *weight))
+                }
+                _ => dropped.push(source.name().to_string()),
+            }
+        }
+
+        let reference = self.median(&quotes).ok_or_else(|| {
+            OrderBookError::OracleUnavailable("No oracle source reported a price".to_string())
+        })?;
+
+        let (kept, outliers): (Vec<_>, Vec<_>) = quotes.into_iter().partition(|(_, quote, _)| {
+            ((quote.price - reference) / reference).abs() <= self.max_deviation
+        });
+        dropped.extend(outliers.into_iter().map(|(name, _, _)| name));
+
+        let price = self.median(&kept).ok_or_else(|| {
+            OrderBookError::OracleUnavailable("All oracle prices were outliers".to_string())
+        })?;
+        let kept_weight: Decimal = kept.iter().map(|(_, _, weight)| *weight).sum();
+
+        Ok(AggregatedPrice {
+            price,
+            timestamp: kept
+                .iter()
+                .map(|(_, quote, _)| quote.timestamp)
+                .max()
+                .unwrap_or(0),
+            confidence: kept_weight / total_weight,
+            sources: kept.into_iter().map(|(name, _, _)| name).collect(),
+            dropped,
+        })
+    }
+
+    fn median(&self, quotes: &[(String, OracleQuote, Decimal)]) -> Option<Decimal> {
+        let mut points: Vec<(Decimal, Decimal)> = quotes
+            .iter()
+            .map(|(_, quote, weight)| match self.method {
+                AggregationMethod::Median => (quote.price, Decimal::ONE),
+                AggregationMethod::WeightedMedian => (quote.price, *weight),
+            })
+            .collect();
+        points.sort_by_key(|&(price, _)| price);
+
+        let half = points.iter().map(|(_, weight)| *weight).sum::<Decimal>() / dec!(2);
+        let mut cumulative = Decimal::ZERO;
+        for (i, &(price, weight)) in points.iter().enumerate() {
+            cumulative += weight;
+            if cumulative == half {
+                // Exactly between two points: average them
+                return Some(
+                    points
+                        .get(i + 1)
+                        .map_or(price, |next| (price + next.0) / dec!(2)),
+                );
+            }
+            if cumulative > half {
+                return Some(price);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simulated(prices: &[(&str, Decimal)]) -> OracleAggregator<SimulatedOracle> {
+        let mut aggregator = OracleAggregator::new(AggregationMethod::Median);
+        for &(name, price) in prices {
+            aggregator
+                .add_source(SimulatedOracle::new(name, price), Decimal::ONE)
+                .unwrap();
+        }
+        aggregator
+    }
+
+    #[test]
+    fn test_median_drops_outliers() {
+        let mut aggregator = simulated(&[
+            ("a", dec!(1000)),
+            ("b", dec!(1002)),
+            ("c", dec!(1001)),
+            ("d", dec!(1200)),
+        ]);
+        aggregator.source_mut(0).unwrap().set_price(dec!(1000), 7);
+
+        let aggregated = aggregator.aggregate(7).unwrap();
+        assert_eq!(aggregated.price, dec!(1001));
+        assert_eq!(aggregated.timestamp, 7);
+        assert_eq!(aggregated.confidence, dec!(0.75));
+        assert_eq!(aggregated.sources, vec!["a", "b", "c"]);
+        assert_eq!(aggregated.dropped, vec!["d"]);
+    }
+
+    #[test]
+    fn test_weighted_median_and_file_source() {
+        let file = FileOracle::parse("file", "# ts,price\n1,1010\n2,1012\n").unwrap();
+        let mut aggregator: OracleAggregator =
+            OracleAggregator::new(AggregationMethod::WeightedMedian);
+        aggregator.add_source(Box::new(file), dec!(3)).unwrap();
+        aggregator
+            .add_source(Box::new(SimulatedOracle::new("sim", dec!(1000))), dec!(1))
+            .unwrap();
+
+        assert_eq!(aggregator.aggregate(2).unwrap().price, dec!(1010));
+        assert_eq!(aggregator.aggregate(2).unwrap().price, dec!(1012));
+        // Exhausted feeds keep repeating their last line
+        let aggregated = aggregator.aggregate(2).unwrap();
+        assert_eq!(aggregated.price, dec!(1012));
+        assert_eq!(aggregated.timestamp, 2);
+
+        assert!(FileOracle::parse("bad", "1;1000").is_err());
+        assert!(
+            OracleAggregator::<SimulatedOracle>::new(AggregationMethod::Median)
+                .aggregate(0)
+                .is_err()
+        );
+    }
+
+    struct FailingOracle;
+
+    impl Oracle for FailingOracle {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn quote(&mut self) -> Result<Option<OracleQuote>> {
+            Err(OrderBookError::OracleUnavailable(
+                "failing: read error".to_string(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_failing_source_is_dropped_not_fatal() {
+        let mut aggregator: OracleAggregator = OracleAggregator::new(AggregationMethod::Median);
+        aggregator
+            .add_source(Box::new(FailingOracle), Decimal::ONE)
+            .unwrap();
+        aggregator
+            .add_source(
+                Box::new(SimulatedOracle::new("sim", dec!(1000))),
+                Decimal::ONE,
+            )
+            .unwrap();
+
+        let aggregated = aggregator.aggregate(0).unwrap();
+        assert_eq!(aggregated.price, dec!(1000));
+        assert_eq!(aggregated.sources, vec!["sim"]);
+        assert_eq!(aggregated.dropped, vec!["failing"]);
+        assert_eq!(aggregated.confidence, dec!(0.5));
+
+        let mut failing: OracleAggregator = OracleAggregator::new(AggregationMethod::Median);
+        failing
+            .add_source(Box::new(FailingOracle), Decimal::ONE)
+            .unwrap();
+        assert!(failing.aggregate(0).is_err());
+    }
+
+    #[test]
+    fn test_bad_and_stale_quotes_are_dropped_per_source() {
+        let mut aggregator = simulated(&[
+            ("a", dec!(1000)),
+            ("b", dec!(0)),
+            ("c", dec!(-5)),
+            ("d", dec!(1001)),
+        ]);
+        for index in 0..4 {
+            let source = aggregator.source_mut(index).unwrap();
+            let price = source.price;
+            source.set_price(price, 100);
+        }
+
+        // A zero or negative price never reaches the median
+        let aggregated = aggregator.aggregate(100).unwrap();
+        assert_eq!(aggregated.sources, vec!["a", "d"]);
+        assert_eq!(aggregated.dropped, vec!["b", "c"]);
+        assert_eq!(aggregated.confidence, dec!(0.5));
+
+        // A fresh source no longer hides a stale one behind the newest timestamp
+        aggregator.source_mut(3).unwrap().set_price(dec!(1001), 200);
+        let aggregated = aggregator.aggregate(200).unwrap();
+        assert_eq!(aggregated.sources, vec!["d"]);
+        assert_eq!(aggregated.dropped, vec!["a", "b", "c"]);
+
+        assert!(aggregator.aggregate(300).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use super::account::{LedgerEntry, LedgerEntryKind, MarginMode};
+use super::clearing::{ClearingHouse, MarketId};
+use super::{LiquidationEngine, PositionSide};
+use crate::error::Result;
+use rust_decimal::Decimal;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionSummary {
+    pub market_id: MarketId,
+    pub side: PositionSide,
+    pub size: Decimal,
+    pub entry_price: Decimal,
+    pub mark_price: Decimal,
+    pub unrealized_pnl: Decimal,
+    pub pending_funding: Decimal,
+    pub margin: Decimal,
+    // None under cross margin, where the account is liquidated as a whole
+    pub margin_ratio: Option<Decimal>,
+    pub leverage: Decimal,
+    pub liquidation_price: Option<Decimal>,
+}
+
+// Ledger amounts of one kind, split by direction; both sides are kept positive
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Flows {
+    pub paid: Decimal,
+    pub received: Decimal,
+}
+
+impl Flows {
+    pub fn record(&mut self, amount: Decimal) {
+        if amount < Decimal::ZERO {
+            self.paid -= amount;
+        } else {
+            self.received += amount;
+        }
+    }
+
+    pub fn net(&self) -> Decimal {
+        self.received - self.paid
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Portfolio {
+    pub trader_id: u64,
+    pub timestamp: u64,
+    pub balance: Decimal,
+    pub order_margin: Decimal,
+    pub available_balance: Decimal,
+    pub unrealized_pnl: Decimal,
+    pub pending_funding: Decimal,
+    pub equity: Decimal,
+    // None while the account holds no positions
+    pub margin_ratio: Option<Decimal>,
+    // Account margin ratio a cross account is liquidated below; None for isolated accounts,
+    // whose positions carry their own
+    pub liquidation_margin_ratio: Option<Decimal>,
+    pub realized_pnl: Decimal,
+    pub fees: Flows,
+    pub funding: Flows,
+    pub positions: Vec<PositionSummary>,
+}
+
+impl Portfolio {
+    // Lifetime realized PnL, fee and funding totals from the trader's ledger entries
+    pub fn tally<'a>(&mut self, entries: impl IntoIterator<Item = &'a LedgerEntry>) {
+        for entry in entries {
+            match entry.kind {
+                LedgerEntryKind::RealizedPnl => self.realized_pnl += entry.amount,
+                LedgerEntryKind::TradingFee | LedgerEntryKind::ReferralRebate => {
+                    self.fees.record(entry.amount)
+                }
+                LedgerEntryKind::Funding => self.funding.record(entry.amount),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub trader_id: u64,
+    pub from: u64,
+    pub to: u64,
+    pub opening_balance: Decimal,
+    pub closing_balance: Decimal,
+    pub totals: Vec<(LedgerEntryKind, Decimal)>,
+    pub entries: Vec<LedgerEntry>,
+}
+
+impl Statement {
+    // Covers entries stamped within [from, to]; the opening balance is whatever the ledger
+    // held just before `from`
+    pub fn from_ledger<'a>(
+        trader_id: u64,
+        from: u64,
+        to: u64,
+        ledger: impl IntoIterator<Item = &'a LedgerEntry>,
+    ) -> Self {
+        let mut opening_balance = Decimal::ZERO;
+        let mut entries = Vec::new();
+        for entry in ledger {
+            if entry.trader_id != trader_id || entry.timestamp > to {
+                continue;
+            }
+            if entry.timestamp < from {
+                opening_balance = entry.balance_after;
+            } else {
+                entries.push(entry.clone());
+            }
+        }
+
+        let mut totals: Vec<(LedgerEntryKind, Decimal)> = Vec::new();
+        for entry in &entries {
+            match totals.iter_mut().find(|(kind, _)| *kind == entry.kind) {
+                Some((_, total)) => *total += entry.amount,
+                None => totals.push((entry.kind, entry.amount)),
+            }
+        }
+
+        Self {
+            trader_id,
+            from,
+            to,
+            opening_balance,
+            closing_balance: entries
+                .last()
+                .map_or(opening_balance, |entry| entry.balance_after),
+            totals,
+            entries,
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("sequence,timestamp,kind,amount,balance_after\n");
+        for entry in &self.entries {
+            let _ = writeln!(
+                csv,
+                "{},{},{:?},{},{}",
+                entry.sequence, entry.timestamp, entry.kind, entry.amount, entry.balance_after
+            );
+        }
+        csv
+    }
+}
+
+impl ClearingHouse {
+    pub fn portfolio(&self, trader_id: u64) -> Result<Portfolio> {
+        let account = self.accounts.account(trader_id)?;
+
+        let mut positions = Vec::new();
+        for market in self.markets.values() {
+            let Some(position) = market.position(trader_id) else {
+                continue;
+            };
+            let mark_price = market.valuation_price(position);
+            // A cross position is backed by the whole account, so its own margin says nothing
+            // about when it is liquidated; the account-level figures cover it instead
+            let (margin_ratio, liquidation_price) = match account.margin_mode {
+                MarginMode::Isolated => (
+                    Some(
+                        self.liquidation_engine
+                            .calculate_margin_ratio(position, mark_price)?,
+                    ),
+                    Some(position.liquidation_price),
+                ),
+                MarginMode::Cross => (None, None),
+            };
+            positions.push(PositionSummary {
+                market_id: market.id,
+                side: position.side,
+                size: position.size,
+                entry_price: position.entry_price,
+                mark_price,
+                unrealized_pnl: LiquidationEngine::calculate_pnl(position, mark_price),
+                pending_funding: market.position_manager.pending_funding(trader_id),
+                margin: position.margin,
+                margin_ratio,
+                leverage: position.leverage,
+                liquidation_price,
+            });
+        }
+
+        let unrealized_pnl: Decimal = positions.iter().map(|p| p.unrealized_pnl).sum();
+        let pending_funding = self.pending_funding(trader_id);
+        let mut portfolio = Portfolio {
+            trader_id,
+            timestamp: self.timestamp,
+            balance: account.balance,
+            order_margin: account.order_margin,
+            available_balance: self.available_balance(trader_id)?,
+            unrealized_pnl,
+            pending_funding,
+            equity: account.balance + unrealized_pnl + pending_funding,
+            margin_ratio: self.account_margin_ratio(trader_id).ok(),
+            liquidation_margin_ratio: match account.margin_mode {
+                MarginMode::Cross if !positions.is_empty() => Some(
+                    self.liquidation_engine
+                        .account_maintenance_rate(&self.positions_for(trader_id)),
+                ),
+                _ => None,
+            },
+            realized_pnl: Decimal::ZERO,
+            fees: Flows::default(),
+            funding: Flows::default(),
+            positions,
+        };
+        portfolio.tally(self.accounts.entries_for(trader_id));
+        Ok(portfolio)
+    }
+
+    pub fn statement(&self, trader_id: u64, from: u64, to: u64) -> Result<Statement> {
+        self.accounts.account(trader_id)?;
+        Ok(Statement::from_ledger(
+            trader_id,
+            from,
+            to,
+            self.accounts.entries_for(trader_id),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perps::clearing::fixtures::{funded, MARKET};
+    use crate::perps::clearing::Market;
+    use crate::types::Side;
+    use rust_decimal_macros::dec;
+
+    fn entry(sequence: u64, timestamp: u64, kind: LedgerEntryKind, amount: Decimal) -> LedgerEntry {
+        LedgerEntry {
+            sequence,
+            timestamp,
+            trader_id: 1,
+            kind,
+            amount,
+            balance_after: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_statement_splits_period_and_totals_by_kind() {
+        let mut ledger = vec![
+            entry(0, 0, LedgerEntryKind::Deposit, dec!(1000)),
+            entry(1, 10, LedgerEntryKind::TradingFee, dec!(-5)),
+            entry(2, 20, LedgerEntryKind::Funding, dec!(2)),
+            entry(3, 30, LedgerEntryKind::TradingFee, dec!(-3)),
+            entry(4, 40, LedgerEntryKind::RealizedPnl, dec!(50)),
+        ];
+        let mut balance = Decimal::ZERO;
+        for entry in &mut ledger {
+            balance += entry.amount;
+            entry.balance_after = balance;
+        }
+
+        let statement = Statement::from_ledger(1, 10, 30, &ledger);
+        assert_eq!(statement.opening_balance, dec!(1000));
+        assert_eq!(statement.closing_balance, dec!(994));
+        assert_eq!(
+            statement.totals,
+            vec![
+                (LedgerEntryKind::TradingFee, dec!(-8)),
+                (LedgerEntryKind::Funding, dec!(2))
+            ]
+        );
+        assert_eq!(
+            statement.to_csv().lines().nth(1),
+            Some("1,10,TradingFee,-5,995")
+        );
+
+        // A period with no activity opens and closes on the balance carried into it
+        let quiet = Statement::from_ledger(1, 50, 60, &ledger);
+        assert!(quiet.entries.is_empty());
+        assert_eq!(quiet.opening_balance, dec!(1044));
+        assert_eq!(quiet.closing_balance, dec!(1044));
+
+        let mut fees = Flows::default();
+        fees.record(dec!(-5));
+        fees.record(dec!(1));
+        assert_eq!(
+            (fees.paid, fees.received, fees.net()),
+            (dec!(5), dec!(1), dec!(-4))
+        );
+    }
+
+    #[test]
+    fn test_portfolio_marks_positions_and_statement_covers_period() {
+        let mut clearing = funded(&[(1, dec!(10000)), (2, dec!(10000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(1100)).unwrap();
+
+        let portfolio = clearing.portfolio(1).unwrap();
+        assert_eq!(portfolio.positions.len(), 1);
+        assert_eq!(portfolio.positions[0].unrealized_pnl, dec!(1000));
+        assert_eq!(portfolio.fees.received, dec!(1));
+        assert_eq!(portfolio.equity, portfolio.balance + dec!(1000));
+        assert!(portfolio.margin_ratio.is_some());
+
+        clearing.advance_time(100);
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1100), dec!(5))
+            .unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1100), dec!(5))
+            .unwrap();
+
+        let portfolio = clearing.portfolio(1).unwrap();
+        assert_eq!(portfolio.realized_pnl, dec!(500));
+        assert_eq!(portfolio.fees.paid, dec!(2.75));
+        assert_eq!(portfolio.fees.net(), dec!(-1.75));
+        assert_eq!(portfolio.positions[0].size, dec!(5));
+
+        let statement = clearing.statement(1, 100, 200).unwrap();
+        assert_eq!(statement.opening_balance, dec!(10001));
+        assert!(statement.entries.iter().all(|entry| entry.timestamp == 100));
+        assert!(statement
+            .totals
+            .contains(&(LedgerEntryKind::RealizedPnl, dec!(500))));
+        assert_eq!(
+            statement.closing_balance,
+            clearing.accounts.account(1).unwrap().balance
+        );
+        assert!(clearing.statement(9, 0, 200).is_err());
+    }
+
+    #[test]
+    fn test_cross_portfolio_reports_risk_per_account() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(3000)), (3, dec!(3000))]);
+        clearing.add_market(Market::new(2)).unwrap();
+        clearing.set_margin_mode(2, MarginMode::Cross).unwrap();
+        for trader_id in [2, 3] {
+            clearing
+                .submit_order(1, MARKET, Side::Sell, dec!(900), dec!(5))
+                .unwrap();
+            clearing
+                .submit_order(trader_id, MARKET, Side::Buy, dec!(900), dec!(5))
+                .unwrap();
+            clearing
+                .submit_order(1, 2, Side::Buy, dec!(100), dec!(20))
+                .unwrap();
+            clearing
+                .submit_order(trader_id, 2, Side::Sell, dec!(100), dec!(20))
+                .unwrap();
+        }
+        clearing.update_mark_price(MARKET, dec!(880)).unwrap();
+        clearing.update_mark_price(2, dec!(105)).unwrap();
+
+        // A cross account is liquidated as a whole, so only the account carries risk figures
+        let cross = clearing.portfolio(2).unwrap();
+        assert_eq!(cross.positions.len(), 2);
+        assert!(cross
+            .positions
+            .iter()
+            .all(|summary| summary.margin_ratio.is_none() && summary.liquidation_price.is_none()));
+        assert_eq!(
+            cross.margin_ratio,
+            Some(clearing.account_margin_ratio(2).unwrap())
+        );
+        assert_eq!(cross.liquidation_margin_ratio, Some(dec!(0.005)));
+
+        let isolated = clearing.portfolio(3).unwrap();
+        assert_eq!(isolated.positions.len(), 2);
+        assert!(isolated
+            .positions
+            .iter()
+            .all(|summary| summary.margin_ratio.is_some() && summary.liquidation_price.is_some()));
+        assert_eq!(isolated.liquidation_margin_ratio, None);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::error::{OrderBookError, Result};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RiskLimits {
+    pub max_open_orders: usize,
+    pub max_order_notional: Decimal,
+}
+
+impl Default for RiskLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RiskLimits {
+    pub fn new() -> Self {
+        Self {
+            max_open_orders: 200,
+            max_order_notional: dec!(10000000),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.max_open_orders == 0 {
+            return Err(OrderBookError::InvalidQuantity(
+                "Max open orders must be at least 1".to_string(),
+            ));
+        }
+
+        if self.max_order_notional <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidQuantity(
+                "Max order notional must be positive".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn check(&self, exposure: &OrderExposure, max_position_size: Decimal) -> Result<()> {
+        // Orders that never rest don't add to the open order count
+        if exposure.rests && exposure.open_orders >= self.max_open_orders {
+            return Err(OrderBookError::TooManyOpenOrders {
+                open_orders: exposure.open_orders,
+                limit: self.max_open_orders,
+            });
+        }
+
+        if exposure.notional > self.max_order_notional {
+            return Err(OrderBookError::OrderNotionalExceeded {
+                notional: exposure.notional,
+                limit: self.max_order_notional,
+            });
+        }
+
+        if !exposure.reduce_only && exposure.worst_case_size() > max_position_size {
+            return Err(OrderBookError::PositionSizeExceeded {
+                size: exposure.worst_case_size(),
+                limit: max_position_size,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// What an incoming order adds to the account, assuming every open order on its side fills
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrderExposure {
+    pub open_orders: usize,
+    pub position_size: Decimal,
+    pub resting_quantity: Decimal,
+    pub quantity: Decimal,
+    pub notional: Decimal,
+    pub reduce_only: bool,
+    // False for immediate-or-cancel orders, which never reach the open order count
+    pub rests: bool,
+}
+
+impl OrderExposure {
+    pub fn worst_case_size(&self) -> Decimal {
+        self.position_size + self.resting_quantity + self.quantity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_bound_orders_notional_and_worst_case_size() {
+        let limits = RiskLimits {
+            max_open_orders: 2,
+            max_order_notional: dec!(50000),
+        };
+        let exposure = OrderExposure {
+            open_orders: 1,
+            position_size: dec!(10),
+            resting_quantity: dec!(20),
+            quantity: dec!(10),
+            notional: dec!(10000),
+            reduce_only: false,
+            rests: true,
+        };
+        assert!(limits.check(&exposure, dec!(40)).is_ok());
+        assert_eq!(
+            limits.check(&exposure, dec!(39)),
+            Err(OrderBookError::PositionSizeExceeded {
+                size: dec!(40),
+                limit: dec!(39)
+            })
+        );
+
+        let reduce_only = OrderExposure {
+            reduce_only: true,
+            ..exposure
+        };
+        assert!(limits.check(&reduce_only, dec!(39)).is_ok());
+
+        let crowded = OrderExposure {
+            open_orders: 2,
+            ..exposure
+        };
+        assert_eq!(
+            limits.check(&crowded, dec!(40)),
+            Err(OrderBookError::TooManyOpenOrders {
+                open_orders: 2,
+                limit: 2
+            })
+        );
+
+        let ioc = OrderExposure {
+            rests: false,
+            ..crowded
+        };
+        assert!(limits.check(&ioc, dec!(40)).is_ok());
+
+        let large = OrderExposure {
+            notional: dec!(50001),
+            ..exposure
+        };
+        assert_eq!(
+            limits.check(&large, dec!(40)).unwrap_err().code(),
+            "ORDER_NOTIONAL_EXCEEDED"
+        );
+        assert!(RiskLimits {
+            max_open_orders: 0,
+            ..limits
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,548 @@
+use super::clearing::{ClearingHouse, Fill, Market, MarketId};
+use super::PositionSide;
+use crate::error::{OrderBookError, Result};
+use rust_decimal::Decimal;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerKind {
+    TakeProfit,
+    StopLoss,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerReference {
+    MarkPrice,
+    LastTrade,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionTrigger {
+    pub kind: TriggerKind,
+    pub trigger_price: Decimal,
+    // None closes whatever the position holds when the trigger fires
+    pub quantity: Option<Decimal>,
+    pub reference: TriggerReference,
+}
+
+impl PositionTrigger {
+    pub fn new(kind: TriggerKind, trigger_price: Decimal) -> Self {
+        Self {
+            kind,
+            trigger_price,
+            quantity: None,
+            reference: TriggerReference::MarkPrice,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.trigger_price <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidPrice(
+                "Trigger price must be positive".to_string(),
+            ));
+        }
+
+        if self
+            .quantity
+            .is_some_and(|quantity| quantity <= Decimal::ZERO)
+        {
+            return Err(OrderBookError::InvalidQuantity(
+                "Trigger quantity must be positive".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_hit(&self, side: PositionSide, price: Decimal) -> bool {
+        match (side, self.kind) {
+            (PositionSide::Long, TriggerKind::TakeProfit)
+            | (PositionSide::Short, TriggerKind::StopLoss) => price >= self.trigger_price,
+            (PositionSide::Long, TriggerKind::StopLoss)
+            | (PositionSide::Short, TriggerKind::TakeProfit) => price <= self.trigger_price,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerOutcome {
+    pub trigger_id: u64,
+    pub trader_id: u64,
+    pub market_id: MarketId,
+    pub kind: TriggerKind,
+    pub reference_price: Decimal,
+    pub quantity: Decimal,
+    pub fills: Vec<Fill>,
+    // The trader's own resting orders that would have crossed the close, cancelled before it
+    pub cancelled_orders: Vec<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct ArmedTrigger {
+    pub(super) trader_id: u64,
+    pub(super) side: PositionSide,
+    pub(super) trigger: PositionTrigger,
+}
+
+impl Market {
+    pub fn triggers_for(&self, trader_id: u64) -> Vec<(u64, &PositionTrigger)> {
+        self.triggers
+            .iter()
+            .filter(|(_, armed)| armed.trader_id == trader_id)
+            .map(|(&trigger_id, armed)| (trigger_id, &armed.trigger))
+            .collect()
+    }
+
+    // Triggers belong to the position they were attached to; once it is closed or flipped
+    // they are dropped
+    pub(super) fn prune_triggers(&mut self, trader_id: u64) {
+        let side = self.position(trader_id).map(|position| position.side);
+        self.triggers
+            .retain(|_, armed| armed.trader_id != trader_id || Some(armed.side) == side);
+    }
+
+    pub(super) fn cancel_triggers(&mut self, trader_id: u64) {
+        self.triggers
+            .retain(|_, armed| armed.trader_id != trader_id);
+    }
+}
+
+impl ClearingHouse {
+    pub fn attach_trigger(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        trigger: PositionTrigger,
+    ) -> Result<u64> {
+        trigger.validate()?;
+        let trigger_id = self.next_trigger_id;
+        let market = self.market_mut(market_id)?;
+        let side = market
+            .position(trader_id)
+            .map(|position| position.side)
+            .ok_or(OrderBookError::PositionNotFound { trader_id })?;
+
+        market.triggers.insert(
+            trigger_id,
+            ArmedTrigger {
+                trader_id,
+                side,
+                trigger,
+            },
+        );
+        self.next_trigger_id += 1;
+        Ok(trigger_id)
+    }
+
+    pub fn cancel_trigger(
+        &mut self,
+        trader_id: u64,
+        market_id: MarketId,
+        trigger_id: u64,
+    ) -> Result<PositionTrigger> {
+        let market = self.market_mut(market_id)?;
+        let owned = market
+            .triggers
+            .get(&trigger_id)
+            .is_some_and(|armed| armed.trader_id == trader_id);
+        if !owned {
+            return Err(OrderBookError::TriggerNotFound { id: trigger_id });
+        }
+
+        market
+            .triggers
+            .remove(&trigger_id)
+            .map(|armed| armed.trigger)
+            .ok_or(OrderBookError::TriggerNotFound { id: trigger_id })
+    }
+
+    // Fires every trigger whose reference price has crossed, closing through reduce-only IOCs.
+    // A trigger that finds no liquidity stays armed for the next run.
+    pub fn run_triggers(&mut self) -> Result<Vec<TriggerOutcome>> {
+        let mut hit = Vec::new();
+        for market in self.markets.values() {
+            if market.halted || market.contract.is_expired(self.timestamp) {
+                continue;
+            }
+            for (&trigger_id, armed) in &market.triggers {
+                let reference_price = match armed.trigger.reference {
+                    // A mark built on a stale index is not worth acting on
+                    TriggerReference::MarkPrice if market.in_safe_mode() => None,
+                    TriggerReference::MarkPrice => market.mark_price,
+                    TriggerReference::LastTrade => market.last_trade_price,
+                };
+                if let Some(price) = reference_price {
+                    if armed.trigger.is_hit(armed.side, price) {
+                        hit.push((market.id, trigger_id, price));
+                    }
+                }
+            }
+        }
+
+        let mut outcomes = Vec::new();
+        for (market_id, trigger_id, reference_price) in hit {
+            // An earlier trigger may already have closed the position and pruned this one
+            let market = self.market(market_id)?;
+            let Some(armed) = market.triggers.get(&trigger_id).cloned() else {
+                continue;
+            };
+            let Some(position) = market.position(armed.trader_id).cloned() else {
+                continue;
+            };
+
+            // The close must not trade with the trader's own resting orders, so any it would
+            // reach are cancelled rather than left to hold the trigger back
+            let (side, limit_price) = self.closing_order(market, &position);
+            let cancelled_orders = market.crossing_orders(armed.trader_id, side, limit_price);
+            for &order_id in &cancelled_orders {
+                self.cancel_order(armed.trader_id, market_id, order_id)?;
+            }
+
+            let quantity = armed
+                .trigger
+                .quantity
+                .map_or(position.size, |quantity| quantity.min(position.size));
+            let taker_fee_rate = self.fee_rates(armed.trader_id).taker_fee();
+            let fills = self.place_reduce_only(market_id, &position, quantity, taker_fee_rate)?;
+            if fills.is_empty() && cancelled_orders.is_empty() {
+                continue;
+            }
+
+            // A thin book may only fill part of it; the rest stays armed for the next run.
+            // Closing the position has already pruned the trigger.
+            let filled: Decimal = fills.iter().map(|fill| fill.trade.quantity).sum();
+            let triggers = &mut self.market_mut(market_id)?.triggers;
+            if let Some(armed) = triggers.get_mut(&trigger_id) {
+                match armed.trigger.quantity {
+                    Some(requested) if requested > filled => {
+                        armed.trigger.quantity = Some(requested - filled)
+                    }
+                    Some(_) => {
+                        triggers.remove(&trigger_id);
+                    }
+                    None => {}
+                }
+            }
+            outcomes.push(TriggerOutcome {
+                trigger_id,
+                trader_id: armed.trader_id,
+                market_id,
+                kind: armed.trigger.kind,
+                reference_price,
+                quantity: filled,
+                fills,
+                cancelled_orders,
+            });
+        }
+        Ok(outcomes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perps::clearing::fixtures::{funded, MARKET};
+    use crate::perps::OracleStatus;
+    use crate::types::Side;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_trigger_direction_follows_position_side() {
+        let take_profit = PositionTrigger::new(TriggerKind::TakeProfit, dec!(110));
+        assert!(take_profit.is_hit(PositionSide::Long, dec!(110)));
+        assert!(!take_profit.is_hit(PositionSide::Long, dec!(109)));
+        assert!(take_profit.is_hit(PositionSide::Short, dec!(100)));
+
+        let stop_loss = PositionTrigger::new(TriggerKind::StopLoss, dec!(90));
+        assert!(stop_loss.is_hit(PositionSide::Long, dec!(89)));
+        assert!(!stop_loss.is_hit(PositionSide::Short, dec!(89)));
+        assert!(stop_loss.is_hit(PositionSide::Short, dec!(95)));
+
+        let mut partial = stop_loss.clone();
+        partial.quantity = Some(dec!(0));
+        assert!(partial.validate().is_err());
+        assert!(stop_loss.validate().is_ok());
+    }
+
+    #[test]
+    fn test_stop_loss_closes_position_through_book() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(100000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        let stop_loss = clearing
+            .attach_trigger(
+                2,
+                MARKET,
+                PositionTrigger::new(TriggerKind::StopLoss, dec!(950)),
+            )
+            .unwrap();
+        clearing
+            .attach_trigger(
+                2,
+                MARKET,
+                PositionTrigger::new(TriggerKind::TakeProfit, dec!(1100)),
+            )
+            .unwrap();
+        assert_eq!(
+            clearing.cancel_trigger(1, MARKET, stop_loss),
+            Err(OrderBookError::TriggerNotFound { id: stop_loss })
+        );
+
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(940), dec!(20))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(960)).unwrap();
+        assert!(clearing.run_triggers().unwrap().is_empty());
+
+        clearing.update_mark_price(MARKET, dec!(950)).unwrap();
+        let outcomes = clearing.run_triggers().unwrap();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].trigger_id, stop_loss);
+        assert_eq!(outcomes[0].quantity, dec!(10));
+        assert_eq!(outcomes[0].fills[0].trade.price, dec!(940));
+        assert_eq!(outcomes[0].fills[0].taker_fee(), dec!(4.7));
+
+        let market = clearing.market(MARKET).unwrap();
+        assert!(market.position(2).is_none());
+        // The take-profit went with the position
+        assert!(market.triggers_for(2).is_empty());
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_stop_loss_cancels_own_crossing_bid() {
+        let mut clearing = funded(&[(1, dec!(10000)), (2, dec!(10000)), (3, dec!(10000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(800), dec!(4))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(800), dec!(4))
+            .unwrap();
+        clearing
+            .attach_trigger(
+                2,
+                MARKET,
+                PositionTrigger::new(TriggerKind::StopLoss, dec!(760)),
+            )
+            .unwrap();
+
+        // The trader's own bid sits inside the slippage band, ahead of trader 3's
+        let own_bid = clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(756), dec!(1))
+            .unwrap();
+        let below = clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(700), dec!(1))
+            .unwrap();
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(752), dec!(10))
+            .unwrap();
+
+        clearing.update_mark_price(MARKET, dec!(760)).unwrap();
+        let outcomes = clearing.run_triggers().unwrap();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].cancelled_orders, vec![own_bid.order_id]);
+        assert_eq!(outcomes[0].quantity, dec!(4));
+        assert!(outcomes[0]
+            .fills
+            .iter()
+            .all(|fill| fill.maker_trader_id == 3));
+
+        let market = clearing.market(MARKET).unwrap();
+        assert!(market.position(2).is_none());
+        assert!(market.order_book().order(own_bid.order_id).is_none());
+        // Orders beyond the slippage limit could never trade with the close and stay put
+        assert!(market.order_book().order(below.order_id).is_some());
+        assert_eq!(clearing.open_order_count(2), 1);
+        assert!(clearing.reconcile(2));
+    }
+
+    #[test]
+    fn test_partially_filled_trigger_stays_armed_for_the_rest() {
+        let mut clearing = funded(&[(1, dec!(10000)), (2, dec!(10000)), (3, dec!(10000))]);
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        let stop_loss = clearing
+            .attach_trigger(
+                1,
+                MARKET,
+                PositionTrigger {
+                    quantity: Some(dec!(8)),
+                    ..PositionTrigger::new(TriggerKind::StopLoss, dec!(1050))
+                },
+            )
+            .unwrap();
+
+        // Only 3 on offer within the slippage band; the short buys back what it can
+        clearing
+            .submit_order(3, MARKET, Side::Sell, dec!(1055), dec!(3))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(1050)).unwrap();
+        let outcomes = clearing.run_triggers().unwrap();
+        assert_eq!(outcomes[0].quantity, dec!(3));
+        let market = clearing.market(MARKET).unwrap();
+        assert_eq!(market.position(1).unwrap().size, dec!(7));
+        assert_eq!(
+            market.triggers_for(1),
+            vec![(
+                stop_loss,
+                &PositionTrigger {
+                    quantity: Some(dec!(5)),
+                    ..PositionTrigger::new(TriggerKind::StopLoss, dec!(1050))
+                }
+            )]
+        );
+
+        clearing
+            .submit_order(3, MARKET, Side::Sell, dec!(1060), dec!(10))
+            .unwrap();
+        let outcomes = clearing.run_triggers().unwrap();
+        assert_eq!(outcomes[0].quantity, dec!(5));
+        let market = clearing.market(MARKET).unwrap();
+        assert_eq!(market.position(1).unwrap().size, dec!(2));
+        assert!(market.triggers_for(1).is_empty());
+        assert!(clearing.reconcile(1));
+    }
+
+    #[test]
+    fn test_triggers_follow_position_size() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(100000)), (3, dec!(100000))]);
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1000), dec!(10))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1000), dec!(10))
+            .unwrap();
+        let mut take_profit = PositionTrigger::new(TriggerKind::TakeProfit, dec!(1100));
+        take_profit.quantity = Some(dec!(15));
+        take_profit.reference = TriggerReference::LastTrade;
+        clearing.attach_trigger(2, MARKET, take_profit).unwrap();
+
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(990), dec!(4))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(990), dec!(4))
+            .unwrap();
+        assert_eq!(clearing.market(MARKET).unwrap().triggers_for(2).len(), 1);
+
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(1100), dec!(20))
+            .unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1100), dec!(1))
+            .unwrap();
+        let outcomes = clearing.run_triggers().unwrap();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].reference_price, dec!(1100));
+        assert_eq!(outcomes[0].quantity, dec!(6));
+        assert!(clearing.market(MARKET).unwrap().position(2).is_none());
+    }
+
+    #[test]
+    fn test_flipped_position_drops_its_triggers() {
+        let mut clearing = funded(&[(1, dec!(10000)), (2, dec!(10000)), (3, dec!(10000))]);
+        assert_eq!(
+            clearing.attach_trigger(
+                2,
+                MARKET,
+                PositionTrigger::new(TriggerKind::StopLoss, dec!(1100))
+            ),
+            Err(OrderBookError::PositionNotFound { trader_id: 2 })
+        );
+
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(1200), dec!(3))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(1200), dec!(3))
+            .unwrap();
+        for (kind, price) in [
+            (TriggerKind::StopLoss, dec!(1100)),
+            (TriggerKind::TakeProfit, dec!(1300)),
+        ] {
+            clearing
+                .attach_trigger(2, MARKET, PositionTrigger::new(kind, price))
+                .unwrap();
+        }
+
+        // Selling 5 against a long of 3 leaves a short the triggers were never set for
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(1200), dec!(5))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(1200), dec!(5))
+            .unwrap();
+        let market = clearing.market(MARKET).unwrap();
+        assert_eq!(market.position(2).unwrap().side, PositionSide::Short);
+        assert!(market.triggers_for(2).is_empty());
+    }
+
+    #[test]
+    fn test_mark_triggers_wait_for_a_trading_market_with_a_trusted_mark() {
+        let mut clearing = funded(&[(1, dec!(10000)), (2, dec!(10000)), (3, dec!(10000))]);
+        clearing
+            .submit_order(2, MARKET, Side::Sell, dec!(500), dec!(2))
+            .unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Buy, dec!(500), dec!(2))
+            .unwrap();
+        clearing
+            .attach_trigger(
+                1,
+                MARKET,
+                PositionTrigger::new(TriggerKind::StopLoss, dec!(480)),
+            )
+            .unwrap();
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(470), dec!(5))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(475)).unwrap();
+
+        clearing.market_mut(MARKET).unwrap().halted = true;
+        assert!(clearing.run_triggers().unwrap().is_empty());
+
+        clearing.market_mut(MARKET).unwrap().halted = false;
+        clearing.market_mut(MARKET).unwrap().safe_mode = Some(OracleStatus::Stale);
+        assert!(clearing.run_triggers().unwrap().is_empty());
+        assert_eq!(clearing.market(MARKET).unwrap().triggers_for(1).len(), 1);
+
+        clearing.market_mut(MARKET).unwrap().safe_mode = None;
+        let outcomes = clearing.run_triggers().unwrap();
+        assert_eq!(outcomes[0].quantity, dec!(2));
+        assert!(clearing.market(MARKET).unwrap().position(1).is_none());
+    }
+
+    #[test]
+    fn test_liquidation_cancels_triggers() {
+        let mut clearing = funded(&[(1, dec!(100000)), (2, dec!(1000)), (3, dec!(100000))]);
+        clearing.set_leverage(2, dec!(50)).unwrap();
+        clearing
+            .submit_order(1, MARKET, Side::Sell, dec!(500), dec!(20))
+            .unwrap();
+        clearing
+            .submit_order(2, MARKET, Side::Buy, dec!(500), dec!(20))
+            .unwrap();
+        clearing
+            .attach_trigger(
+                2,
+                MARKET,
+                PositionTrigger::new(TriggerKind::StopLoss, dec!(450)),
+            )
+            .unwrap();
+        clearing
+            .submit_order(3, MARKET, Side::Buy, dec!(490), dec!(100))
+            .unwrap();
+        clearing.update_mark_price(MARKET, dec!(493)).unwrap();
+
+        let outcomes = clearing.run_liquidations().unwrap();
+        assert_eq!(outcomes[0].trader_id, 2);
+        assert!(clearing.market(MARKET).unwrap().triggers_for(2).is_empty());
+    }
+}
@@ -3,3 +3,13 @@ pub enum Side {
     Buy,
     Sell,
 }
+
+impl Side {
+    #[inline]
+    pub fn opposite(self) -> Self {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        }
+    }
+}